@@ -0,0 +1,241 @@
+use std::str::FromStr;
+use anyhow::{Result, bail};
+
+/// Shells for which a completion script can be generated
+#[derive(Clone, Copy, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish
+}
+
+impl FromStr for Shell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            _ => bail!("Unknown shell `{}` (expected bash, zsh or fish)", s)
+        }
+    }
+}
+
+/// What follows a flag on the command line
+#[derive(Clone, Copy)]
+enum Value {
+    None,
+    /// Any value, nothing to complete
+    Any,
+    File,
+    Choices(&'static [&'static str])
+}
+
+/// A command line flag, as completed by the shells
+struct Flag {
+    short: Option<char>,
+    long: &'static str,
+    value: Value,
+    /// Whether it may be given more than once
+    repeat: bool,
+    help: &'static str
+}
+
+impl Flag {
+    const fn new(short: Option<char>, long: &'static str, value: Value,
+                 help: &'static str) -> Self {
+        Self { short, long, value, repeat: false, help }
+    }
+
+    const fn repeat(mut self) -> Self {
+        self.repeat = true;
+        self
+    }
+}
+
+/// JSON cold runs need the `serde` feature
+const COLD_FORMATS: &[&str] = if cfg!(feature = "serde") { &["text", "json"] } else { &["text"] };
+
+/// Every flag of `parse_args`, which must be kept in sync with it
+const FLAGS: &[Flag] = &[
+    Flag::new(Some('t'), "target-ip", Value::Any, "Address or host name to connect to").repeat(),
+    Flag::new(Some('s'), "serve", Value::Any, "Address to listen on, as a server"),
+    Flag::new(Some('r'), "relay", Value::Any, "Address of the server to relay"),
+    Flag::new(None, "probe", Value::Any, "Tell whether a server listens at an address"),
+    Flag::new(Some('m'), "simple-menu", Value::None, "Show a minimal menu"),
+    Flag::new(Some('c'), "cold-run", Value::None, "Print the keys instead of emulating them"),
+    Flag::new(None, "direction", Value::Choices(&["send", "receive"]),
+              "Whether this end sends or receives the keys"),
+    Flag::new(None, "cold-format", Value::Choices(COLD_FORMATS),
+              "How a cold run prints the keys"),
+    Flag::new(Some('q'), "quiet", Value::None, "Only print the token and the keys"),
+    Flag::new(Some('u'), "unsecure", Value::None, "Disable encryption"),
+    Flag::new(Some('l'), "refresh-latency", Value::Any, "Keys between latency checks"),
+    Flag::new(None, "hostname", Value::Any, "Name shown to the peer"),
+    Flag::new(None, "remap", Value::Any, "Send a key as another one").repeat(),
+    Flag::new(None, "transform", Value::Choices(&["uppercase", "lowercase", "ascii"]),
+              "Transform the keys sent").repeat(),
+    Flag::new(None, "max-rate", Value::Any, "Most keys sent per second"),
+    Flag::new(None, "coalesce", Value::Any, "Milliseconds within which repeats are sent as one key"),
+    Flag::new(None, "send-queue", Value::Any, "Keys queued while the network is slow"),
+    Flag::new(None, "drop-policy", Value::Choices(&["block", "drop-oldest"]),
+              "What to do once the send queue is full"),
+    Flag::new(None, "max-packet-size", Value::Any, "Largest packet accepted, in bytes"),
+    Flag::new(None, "nagle", Value::None, "Keep the Nagle algorithm enabled"),
+    Flag::new(None, "compress", Value::None, "Compress the packets"),
+    Flag::new(None, "absolute-mouse", Value::None, "Send absolute pointer positions"),
+    Flag::new(None, "read-timeout", Value::Any, "Seconds without packets before giving up"),
+    Flag::new(None, "connect-timeout", Value::Any, "Seconds before giving up connecting"),
+    Flag::new(None, "max-hold", Value::Any, "Seconds a key may stay held down"),
+    Flag::new(None, "keepalive", Value::Any, "Seconds between pings of an idle session"),
+    Flag::new(None, "reconnect", Value::None, "Reconnect when the session ends"),
+    Flag::new(None, "max-retries", Value::Any, "Reconnections tried in a row"),
+    Flag::new(None, "reconnect-delay", Value::Any, "Seconds before reconnecting"),
+    Flag::new(None, "multi-client", Value::None, "Send the keys to several clients"),
+    Flag::new(None, "qr", Value::None, "Also show the token as a QR code"),
+    Flag::new(None, "token", Value::Any, "Token of the server"),
+    Flag::new(None, "pause-key", Value::Any, "Hotkey pausing the forwarding"),
+    Flag::new(None, "paste-key", Value::Any, "Hotkey sending the clipboard"),
+    Flag::new(None, "clipboard", Value::None, "Share the clipboard with the peer"),
+    Flag::new(None, "clipboard-key", Value::Any, "Hotkey setting the clipboard of the peer"),
+    Flag::new(None, "fetch-clipboard-key", Value::Any, "Hotkey fetching the clipboard of the peer"),
+    Flag::new(None, "max-failures", Value::Any, "Failed handshakes before a lock out"),
+    Flag::new(None, "failure-window", Value::Any, "Seconds over which failures are counted"),
+    Flag::new(None, "token-ttl", Value::Any, "Seconds a token stays valid"),
+    Flag::new(None, "idle-timeout", Value::Any, "Seconds before the session shows as idle"),
+    Flag::new(None, "activation-delay", Value::Any, "Seconds to wait after activation"),
+    Flag::new(None, "transcript", Value::File, "Record the keys to an encrypted file"),
+    Flag::new(None, "plain-transcript", Value::None, "Write the transcript in plaintext"),
+    Flag::new(None, "history", Value::Any, "Keys shown in the menu"),
+    Flag::new(None, "history-log", Value::File, "Append the keys sent to a file"),
+    Flag::new(None, "record", Value::File, "Record the keys sent to a macro"),
+    Flag::new(None, "replay", Value::File, "Send the keys of a macro"),
+    Flag::new(None, "decrypt-transcript", Value::File, "Print an encrypted transcript"),
+    Flag::new(None, "require-security", Value::Choices(&["none", "encrypted", "strict"]),
+              "Refuse to run below a security level"),
+    Flag::new(None, "allow-downgrade", Value::None, "Accept an unsecure peer"),
+    Flag::new(None, "allow", Value::Any, "Only accept connections from a network").repeat(),
+    Flag::new(None, "reject-log", Value::File, "Append refused connections to a file"),
+    Flag::new(None, "on-duplicate", Value::Choices(&["warn", "replace"]),
+              "What to do when the peer connects again"),
+    Flag::new(None, "x-display", Value::Any, "X display to emulate the keys on"),
+    Flag::new(None, "import-config", Value::File, "Load options from a file"),
+    Flag::new(None, "export-config", Value::File, "Write the options to a file"),
+    Flag::new(None, "test-emulate", Value::File, "Emulate the keys of a file locally"),
+    Flag::new(None, "completions", Value::Choices(&["bash", "zsh", "fish"]),
+              "Print a completion script"),
+    Flag::new(Some('V'), "verbose", Value::None, "Log more details").repeat(),
+    Flag::new(Some('h'), "help", Value::None, "Print help information"),
+    Flag::new(Some('v'), "version", Value::None, "Print version information")
+];
+
+/// Completion script of `shell` for every flag
+pub fn generate(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash(),
+        Shell::Zsh => zsh(),
+        Shell::Fish => fish()
+    }
+}
+
+fn bash() -> String {
+    let mut s = String::from("_telekey() {\n    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n    \
+                              local prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n    case \"$prev\" in\n");
+    let mut words = Vec::new();
+    for flag in FLAGS {
+        let mut names = vec![format!("--{}", flag.long)];
+        if let Some(c) = flag.short {
+            names.push(format!("-{}", c));
+        }
+        let reply = match flag.value {
+            Value::None => None,
+            Value::Any => Some(String::new()),
+            Value::File => Some("COMPREPLY=($(compgen -f -- \"$cur\")); ".to_string()),
+            Value::Choices(choices) => Some(format!(
+                "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); ", choices.join(" ")))
+        };
+        if let Some(reply) = reply {
+            s.push_str(&format!("        {}) {}return ;;\n", names.join("|"), reply));
+        }
+        words.extend(names);
+    }
+    s.push_str(&format!("    esac\n    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n}}\n\
+                         complete -F _telekey telekey\n", words.join(" ")));
+    s
+}
+
+fn zsh() -> String {
+    let mut s = String::from("#compdef telekey\n\n_arguments \\\n");
+    for (i, flag) in FLAGS.iter().enumerate() {
+        let value = match flag.value {
+            Value::None => String::new(),
+            Value::Any => ":value: ".to_string(),
+            Value::File => ":file:_files".to_string(),
+            Value::Choices(choices) => format!(":value:({})", choices.join(" "))
+        };
+        let names = match flag.short {
+            Some(c) if flag.repeat => format!("'*'{{-{},--{}}}'", c, flag.long),
+            Some(c) => format!("'(-{0} --{1})'{{-{0},--{1}}}'", c, flag.long),
+            None if flag.repeat => format!("'*--{}", flag.long),
+            None => format!("'--{}", flag.long)
+        };
+        let end = if i + 1 < FLAGS.len() { " \\" } else { "" };
+        s.push_str(&format!("  {}[{}]{}'{}\n", names, flag.help, value, end));
+    }
+    s
+}
+
+fn fish() -> String {
+    let mut s = String::new();
+    for flag in FLAGS {
+        s.push_str("complete -c telekey");
+        if let Some(c) = flag.short {
+            s.push_str(&format!(" -s {}", c));
+        }
+        s.push_str(&format!(" -l {}", flag.long));
+        match flag.value {
+            Value::None => (),
+            Value::Any => s.push_str(" -x"),
+            Value::File => s.push_str(" -r -F"),
+            Value::Choices(choices) => {
+                s.push_str(&format!(" -x -a '{}'", choices.join(" ")));
+            }
+        }
+        s.push_str(&format!(" -d '{}'\n", flag.help));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Long flags mentioned in `text`, e.g. `--serve`
+    fn long_flags(text: &str) -> Vec<&str> {
+        let mut flags: Vec<&str> = text.split("--").skip(1)
+            .map(|s| s.split(|c: char| !(c.is_ascii_lowercase() || c == '-')).next().unwrap())
+            .filter(|s| !s.is_empty())
+            .collect();
+        flags.sort_unstable();
+        flags.dedup();
+        flags
+    }
+
+    #[test]
+    fn every_flag_of_the_help_is_completed() {
+        let mut table: Vec<&str> = FLAGS.iter().map(|f| f.long).collect();
+        table.sort_unstable();
+        assert_eq!(long_flags(crate::HELP), table);
+    }
+
+    #[test]
+    fn every_shell_completes_every_flag() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = generate(shell);
+            for flag in FLAGS {
+                assert!(script.contains(flag.long), "{:?} misses --{}", shell, flag.long);
+            }
+        }
+    }
+}
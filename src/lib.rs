@@ -0,0 +1,16 @@
+//! Secure remote keyboard interface over TCP.
+//!
+//! A server sends the keys typed in its terminal to a client, which emulates
+//! them:
+//!
+//! ```no_run
+//! use telekey::Telekey;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! Telekey::builder().secure(true).serve("0.0.0.0:8384".parse()?)
+//! # }
+//! ```
+mod protocol;
+pub use crate::protocol::*;
+pub use crate::protocol::bindings::api::{KeyEvent, KeyKind, KeyState};
+pub use crate::protocol::bindings::api::{MouseEvent, MouseAction, MouseButton};
@@ -1,8 +1,13 @@
-mod protocol;
-use crate::protocol::*;
-use std::{net::{SocketAddr, IpAddr}, str::FromStr};
+use telekey::*;
+use std::{net::{SocketAddr, IpAddr, ToSocketAddrs}, str::FromStr, path::PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::Write;
 use anyhow::{Result, Context, bail};
 use tui_markup_ansi_macro::ansi;
+use console::style;
+use ipnet::IpNet;
+
+mod completions;
 
 const HELP: &str = ansi!("<brown TeleKey> by Sofiane Meftah
 Secure remote keyboard interface over TCP.
@@ -10,12 +15,66 @@ Secure remote keyboard interface over TCP.
 <u Usage:> telekey.exe <yellow [OPTIONS...]>
 
 <u Options:>
-  -t, --target-ip \\<<arg IP<opt [:PORT]>>\\>  <green [Runs telekey as client]> Defines the target address to connect to. <def defaults to 127.0.0.1:8384>
+  -t, --target-ip \\<<arg IP<opt [:PORT]>>\\>  <green [Runs telekey as client]> Defines the target address or host name to connect to. Can be repeated to try several servers in turn. <def defaults to 127.0.0.1:8384>
   -s, --serve \\<<arg IP<opt [:PORT]>>\\>      <green [Runs telekey as server]> IP address to start a TCP Listener on. <def defaults to 0.0.0.0:8384>
+  -r, --relay \\<<arg IP<opt [:PORT]>>\\>      <green [Runs telekey as relay]> Forwards the keys of the server at <arg IP> to a client accepted on the <arg -s> address.
+      --hostname \\<<arg NAME>\\>      Name shown to the peer, e.g. to tell several instances apart. <def defaults to this machine's hostname>
   -m, --simple-menu            If enabled, server's menu will only show minimal information and only update latency.
   -c, --cold-run               If enabled, the key presses will be printed to the standard output rather than being emulated.
+      --cold-format \\<<arg FORMAT>\\>  How <arg -c> prints the keys: text, or json for one object per line. <def defaults to text>
+  -q, --quiet                  Only prints the token and the keys of <arg -c>, and logs nothing but errors.
   -l, --refresh-latency \\<<arg N>\\>    Triggers a latency check after <arg N> keys. Use 0 to disable latency checks. <def defaults to 20>
   -u, --unsecure               <red Unsecure mode.> <i No encryption: use it at your own risk!>
+      --remap \\<<arg FROM=TO>\\>       <green [Server]> Sends the key spec <arg TO> instead of <arg FROM>, e.g. CAPS_LOCK=ESC. Can be repeated.
+      --transform \\<<arg NAME>\\>      Rewrites keys before sending them: uppercase, lowercase or ascii. Can be repeated.
+      --max-rate \\<<arg N>\\>          Asks the peer to send at most <arg N> keys per second. Use 0 for no limit. <def defaults to 0>
+      --coalesce \\<<arg MS>\\>         <green [Sender]> Sends a click repeated within <arg MS> milliseconds as one key with a count. Use 0 to disable. <def disabled by default>
+      --send-queue \\<<arg N>\\>        <green [Server]> Queues up to <arg N> packets while the link is slow. Use 0 to send synchronously. <def defaults to 0>
+      --drop-policy \\<<arg POLICY>\\>  <green [Server]> When the send queue is full: block or drop-oldest. <def defaults to block>
+      --max-packet-size \\<<arg N>\\>   Refuses packets larger than <arg N> bytes from the peer. <def defaults to 65536>
+      --nagle                  Keeps Nagle's algorithm enabled, batching keys on slow links.
+      --compress               Compresses the larger packets when the peer uses <arg --compress> too.
+      --absolute-mouse         Lets the server move the pointer to absolute positions when the peer uses <arg --absolute-mouse> too.
+      --read-timeout \\<<arg N>\\>      Ends the session when the peer sends nothing for <arg N> seconds while awaited. <def disabled by default>
+      --connect-timeout \\<<arg N>\\>   <green [Client]> Gives up connecting to the server after <arg N> seconds. Use 0 to wait as long as the system does. <def defaults to 5>
+      --max-hold \\<<arg N>\\>          Releases the keys the server has held down for <arg N> seconds. <def disabled by default>
+      --keepalive \\<<arg N>\\>         <green [Server]> Pings the client after <arg N> seconds without keys. Use 0 to disable. <def defaults to 0>
+      --reconnect              Connects again when the session drops, reusing the token. Also needed on the server.
+      --max-retries \\<<arg N>\\>       Gives up after <arg N> failed reconnections in a row. Use 0 for no limit. <def defaults to 0>
+      --reconnect-delay \\<<arg N>\\>   Waits <arg N> seconds before reconnecting, doubled after each failure (up to 60). <def defaults to 1>
+      --direction \\<<arg DIR>\\>       Whether this end sends or receives the keys, whatever its role. Both ends must agree. <def server sends by default>
+      --multi-client           <green [Server]> Sends the keys to every connected client, sharing one token.
+      --token-ttl \\<<arg N>\\>         <green [Server]> Refuses handshakes more than <arg N> seconds after the token is shown. Use 0 to disable. <def defaults to 60>
+      --max-failures \\<<arg N>\\>      <green [Server]> Locks an IP out for 5 minutes after <arg N> failed handshakes. Use 0 to disable. <def defaults to 5>
+      --failure-window \\<<arg N>\\>    <green [Server]> Counts the failed handshakes of the last <arg N> seconds. <def defaults to 60>
+      --qr                     <green [Server]> Also shows the session token as a QR code.
+      --token \\<<arg TOKEN>\\>         <green [Client]> Token of the server, not asked then. <def defaults to $TELEKEY_TOKEN>
+      --pause-key \\<<arg SPEC>\\>     <green [Server]> Hotkey pausing and resuming the forwarding of keys, or none. <def defaults to none>
+      --paste-key \\<<arg SPEC>\\>     <green [Server]> Hotkey sending the local clipboard as text, or none. <def defaults to none>
+      --clipboard              Shares the clipboard with the peer, which must share it as well. <red The clipboard may hold secrets!>
+      --clipboard-key \\<<arg SPEC>\\>  Hotkey setting the clipboard of the peer to the local one, or none. <def defaults to ALT+CHAR c>
+      --fetch-clipboard-key \\<<arg SPEC>\\>  Hotkey setting the local clipboard to the one of the peer, or none. <def defaults to ALT+CHAR y>
+      --idle-timeout \\<<arg N>\\>      Shows the session as idle after <arg N> seconds without key presses. <def disabled by default>
+      --activation-delay \\<<arg N>\\>  Waits <arg N> seconds after activation before sending keys. <def defaults to 0>
+      --transcript \\<<arg FILE>\\>     Records every key of the sessions to <arg FILE>, encrypted with a passphrase.
+      --plain-transcript       Writes the transcript in plaintext. <red Keys such as passwords are exposed!>
+      --decrypt-transcript \\<<arg FILE>\\>  Prints the encrypted transcript <arg FILE> and exits.
+      --history \\<<arg N>\\>           <green [Server]> Shows the last <arg N> keys sent in the menu. Use 0 to hide them. <def defaults to 20>
+      --history-log \\<<arg FILE>\\>    <green [Server]> Appends every key sent to <arg FILE>, one timestamped line each. <red Stored in plaintext!>
+      --record \\<<arg FILE>\\>         <green [Server]> Appends the keys sent, with their timing, to the macro <arg FILE>. <red Stored in plaintext!>
+      --replay \\<<arg FILE>\\>         Sends the keys of the macro <arg FILE> to the first client accepted on the <arg -s> address, then exits.
+      --require-security \\<<arg LEVEL>\\>  Refuses to run unless the options meet <arg LEVEL>: none, encrypted or strict.
+      --allow-downgrade        Accepts an unsecure session when the peer runs with <arg -u>. <red Use it at your own risk!>
+      --allow \\<<arg CIDR>\\>         <green [Server]> Only accepts connections from <arg CIDR> (or a single IP). Can be repeated. <def allows all by default>
+      --reject-log \\<<arg FILE>\\>     <green [Server]> Appends refused connection attempts to <arg FILE>.
+      --on-duplicate \\<<arg POLICY>\\>  <green [Server]> When the peer connects again during its session: warn or replace. <def defaults to warn>
+      --x-display \\<<arg DISPLAY>\\>  <green [Linux]> X display to emulate keys on (e.g. :1). <def defaults to $DISPLAY>
+      --import-config \\<<arg FILE>\\>  Loads options from <arg FILE>. Options given after it take precedence.
+      --export-config \\<<arg FILE>\\>  Writes the resolved options to <arg FILE> and exits.
+      --test-emulate \\<<arg FILE>\\>   Emulates the key events listed in <arg FILE> locally and exits.
+      --probe \\<<arg IP<opt [:PORT]>>\\>  Tells whether a TeleKey server listens at <arg IP> and exits. No token is needed.
+      --completions \\<<arg SHELL>\\>  Prints the completion script of <arg SHELL> (bash, zsh or fish) and exits.
+  -V, --verbose                Logs more details to stderr, repeat for even more. <def RUST_LOG takes precedence>
   -h, --help                   Print help information.
   -v, --version                Print version information.",
   "brown" => "173",
@@ -24,21 +83,70 @@ Secure remote keyboard interface over TCP.
   "def" => "magenta,i"
 );
 
+/// Parses `HOST[:PORT]`, where the host is an IP address or a name to
+/// resolve. An IPv6 address with a port is bracketed like `[::1]:9000`, and
+/// a bare one may be bracketed or not. Names resolving to several addresses
+/// give the first IPv4 one, if any.
 fn parse_ip(s: &str) -> Result<SocketAddr> {
     if let Ok(addr) = SocketAddr::from_str(s) {
         return Ok(addr)
     }
-    let addr = IpAddr::from_str(s)?;
-    Ok(SocketAddr::new(addr, 8384))
+    let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    if let Ok(addr) = IpAddr::from_str(ip) {
+        return Ok(SocketAddr::new(addr, 8384));
+    }
+    if s.matches(':').count() > 1 && !s.starts_with('[') {
+        bail!("Invalid address `{}`, an IPv6 address with a port must be bracketed \
+               like `[::1]:8384`", s);
+    }
+    let (host, port) = match s.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>()
+            .with_context(|| format!("Invalid port `{}`", port))?),
+        None => (s, 8384)
+    };
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()
+        .with_context(|| format!("`{}` is neither an IP address nor a host name \
+                                  that could be resolved", host))?
+        .collect();
+    addrs.iter().find(|addr| addr.is_ipv4()).or_else(|| addrs.first()).copied()
+        .with_context(|| format!("`{}` did not resolve to any address", host))
+}
+
+enum Command {
+    Serve(SocketAddr),
+    Connect(Vec<SocketAddr>),
+    Relay(SocketAddr, SocketAddr),
+    Replay(SocketAddr, PathBuf),
+    TestEmulate(PathBuf),
+    Probe(SocketAddr),
+    ExportConfig(PathBuf),
+    DecryptTranscript(PathBuf)
 }
 
-fn parse_args() -> Result<(SocketAddr, TelekeyMode, TelekeyConfig)> {
+fn parse_args() -> Result<(Command, TelekeyConfig, log::LevelFilter)> {
+    parse_args_from(std::env::args_os().skip(1))
+}
+
+fn parse_args_from<I>(args: I) -> Result<(Command, TelekeyConfig, log::LevelFilter)>
+where
+    I: IntoIterator,
+    I::Item: Into<std::ffi::OsString>
+{
     use lexopt::prelude::*;
 
     let mut config = TelekeyConfig::default();
-    let mut target_ip: Option<SocketAddr> = None;
+    let mut target_ips: Vec<SocketAddr> = Vec::new();
     let mut bind: Option<SocketAddr> = None;
-    let mut parser = lexopt::Parser::from_env();
+    let mut test_emulate: Option<PathBuf> = None;
+    let mut relay: Option<SocketAddr> = None;
+    let mut replay: Option<PathBuf> = None;
+    let mut probe: Option<SocketAddr> = None;
+    let mut export_config: Option<PathBuf> = None;
+    let mut decrypt_transcript: Option<PathBuf> = None;
+    let mut require_security: Option<SecurityLevel> = None;
+    let mut verbosity = 0;
+    // the flags are listed again in `completions`, keep them in sync
+    let mut parser = lexopt::Parser::from_args(args);
     while let Some(arg) = parser.next()? {
         match arg {
             Short('s') | Long("serve") => {
@@ -48,16 +156,198 @@ fn parse_args() -> Result<(SocketAddr, TelekeyMode, TelekeyConfig)> {
             }
             Short('t') | Long("target-ip") => {
                 let ip: String = parser.value()?.parse()?;
-                target_ip = Some(parse_ip(&ip)
+                target_ips.push(parse_ip(&ip)
                      .context("Invalid target IP address")?);
             }
+            Short('r') | Long("relay") => {
+                let ip: String = parser.value()?.parse()?;
+                relay = Some(parse_ip(&ip)
+                     .context("Invalid relay target IP address")?);
+            }
+            Long("probe") => {
+                let ip: String = parser.value()?.parse()?;
+                probe = Some(parse_ip(&ip)
+                     .context("Invalid IP address to probe")?);
+            }
             Short('m') | Long("simple-menu") => config.set_update_screen(false),
             Short('c') | Long("cold-run") => config.set_cold_run(true),
+            Long("direction") => {
+                let direction: String = parser.value()?.parse()?;
+                config.set_direction(Some(direction.parse()?));
+            }
+            Long("cold-format") => {
+                let format: String = parser.value()?.parse()?;
+                config.set_cold_format(format.parse()?);
+            }
+            Short('q') | Long("quiet") => config.set_quiet(true),
             Short('u') | Long("unsecure") => config.set_secure(false),
             Short('l') | Long("refresh-latency") => {
                 let n: usize = parser.value()?.parse()?;
+                if n > MAX_REFRESH_LATENCY {
+                    eprintln!("{}: Latency will only be checked every {} keys",
+                        style("WARNING").yellow().bold(), n);
+                }
                 config.set_refresh_latency(if n == 0 { None } else { Some(n) });
             }
+            Long("hostname") => {
+                let name: String = parser.value()?.parse()?;
+                config.set_hostname(name);
+            }
+            Long("remap") => {
+                let rule: String = parser.value()?.parse()?;
+                let (from, to) = rule.split_once('=')
+                    .with_context(|| format!("Expected FROM=TO, got `{}`", rule))?;
+                config.remap(from.to_string(), to.to_string());
+            }
+            Long("transform") => {
+                let name: String = parser.value()?.parse()?;
+                config.add_transform(name.parse()?);
+            }
+            Long("max-rate") => {
+                let n: u32 = parser.value()?.parse()?;
+                config.set_max_rate(n);
+            }
+            Long("coalesce") => {
+                let ms: u64 = parser.value()?.parse()?;
+                config.set_coalesce(if ms == 0 { None } else { Some(ms) });
+            }
+            Long("send-queue") => {
+                let n: usize = parser.value()?.parse()?;
+                config.set_send_queue(n);
+            }
+            Long("drop-policy") => {
+                let policy: String = parser.value()?.parse()?;
+                config.set_drop_policy(policy.parse()?);
+            }
+            Long("max-packet-size") => {
+                let n: usize = parser.value()?.parse()?;
+                config.set_max_packet_size(n);
+            }
+            Long("nagle") => config.set_nodelay(false),
+            Long("compress") => config.set_compress(true),
+            Long("absolute-mouse") => config.set_absolute_mouse(true),
+            Long("read-timeout") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_read_timeout(if n == 0 { None } else { Some(n) });
+            }
+            Long("connect-timeout") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_connect_timeout(if n == 0 { None } else { Some(n) });
+            }
+            Long("max-hold") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_max_hold(if n == 0 { None } else { Some(n) });
+            }
+            Long("keepalive") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_keepalive(if n == 0 { None } else { Some(n) });
+            }
+            Long("reconnect") => config.set_reconnect(true),
+            Long("max-retries") => {
+                let n: u32 = parser.value()?.parse()?;
+                config.set_max_retries(if n == 0 { None } else { Some(n) });
+            }
+            Long("reconnect-delay") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_reconnect_delay(n);
+            }
+            Long("multi-client") => config.set_multi_client(true),
+            Long("qr") => config.set_qr(true),
+            Long("token") => config.set_token(Some(parser.value()?.parse()?)),
+            Long("pause-key") => {
+                let spec: String = parser.value()?.parse()?;
+                config.set_pause_key(if spec == "none" { None } else { Some(spec) });
+            }
+            Long("paste-key") => {
+                let spec: String = parser.value()?.parse()?;
+                config.set_paste_key(if spec == "none" { None } else { Some(spec) });
+            }
+            Long("clipboard") => config.set_clipboard(true),
+            Long("clipboard-key") => {
+                let spec: String = parser.value()?.parse()?;
+                config.set_clipboard_key(if spec == "none" { None } else { Some(spec) });
+            }
+            Long("fetch-clipboard-key") => {
+                let spec: String = parser.value()?.parse()?;
+                config.set_fetch_clipboard_key(if spec == "none" { None } else { Some(spec) });
+            }
+            Long("max-failures") => {
+                let n: u32 = parser.value()?.parse()?;
+                config.set_max_failures(if n == 0 { None } else { Some(n) });
+            }
+            Long("failure-window") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_failure_window(n);
+            }
+            Long("token-ttl") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_token_ttl(if n == 0 { None } else { Some(n) });
+            }
+            Long("idle-timeout") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_idle_timeout(if n == 0 { None } else { Some(n) });
+            }
+            Long("activation-delay") => {
+                let n: u64 = parser.value()?.parse()?;
+                config.set_activation_delay(n);
+            }
+            Long("transcript") => {
+                config.set_transcript(Some(parser.value()?.into()));
+            }
+            Long("plain-transcript") => config.set_plain_transcript(true),
+            Long("history") => {
+                let n: usize = parser.value()?.parse()?;
+                config.set_history_size(n);
+            }
+            Long("history-log") => config.set_history_log(Some(parser.value()?.into())),
+            Long("record") => config.set_record(Some(parser.value()?.into())),
+            Long("replay") => {
+                replay = Some(parser.value()?.into());
+            }
+            Long("decrypt-transcript") => {
+                decrypt_transcript = Some(parser.value()?.into());
+            }
+            Long("require-security") => {
+                let level: String = parser.value()?.parse()?;
+                require_security = Some(level.parse()?);
+            }
+            Long("allow-downgrade") => config.set_allow_downgrade(true),
+            Long("allow") => {
+                let net: String = parser.value()?.parse()?;
+                let net = match IpNet::from_str(&net) {
+                    Ok(net) => net,
+                    Err(_) => IpAddr::from_str(&net).map(IpNet::from)
+                        .with_context(|| format!("Invalid network `{}`", net))?
+                };
+                config.allow(net);
+            }
+            Long("reject-log") => {
+                config.set_reject_log(Some(parser.value()?.into()));
+            }
+            Long("on-duplicate") => {
+                let policy: String = parser.value()?.parse()?;
+                config.set_on_duplicate(policy.parse()?);
+            }
+            Long("x-display") => {
+                let display: String = parser.value()?.parse()?;
+                config.set_x_display(Some(display));
+            }
+            Long("import-config") => {
+                let path: PathBuf = parser.value()?.into();
+                config = TelekeyConfig::import(&path)?;
+            }
+            Long("export-config") => {
+                export_config = Some(parser.value()?.into());
+            }
+            Long("test-emulate") => {
+                test_emulate = Some(parser.value()?.into());
+            }
+            Long("completions") => {
+                let shell: String = parser.value()?.parse()?;
+                print!("{}", completions::generate(shell.parse()?));
+                std::process::exit(0);
+            }
+            Short('V') | Long("verbose") => verbosity += 1,
             Short('v') | Long("version") => {
                 println!("TeleKey {} by Sofiane Meftah",
                     VERSION.unwrap_or("Unknown"));
@@ -71,20 +361,147 @@ fn parse_args() -> Result<(SocketAddr, TelekeyMode, TelekeyConfig)> {
         }
     }
 
-    if let Some(addr) = bind {
-        Ok((addr, TelekeyMode::Server, config))
-    } else {
-        let addr = target_ip.unwrap_or_else(||
-            SocketAddr::from(([127, 0, 0, 1], 8384)));
-        Ok((addr, TelekeyMode::Client, config))
+    config.validate().context("Invalid configuration")?;
+    if let Some(level) = require_security {
+        config.check_security(level).context("Security requirements not met")?;
     }
+
+    let level = match verbosity {
+        _ if config.is_quiet() => log::LevelFilter::Error,
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace
+    };
+    let command = if let Some(path) = decrypt_transcript {
+        Command::DecryptTranscript(path)
+    } else if let Some(path) = export_config {
+        Command::ExportConfig(path)
+    } else if let Some(path) = test_emulate {
+        Command::TestEmulate(path)
+    } else if let Some(addr) = probe {
+        Command::Probe(addr)
+    } else if let Some(path) = replay {
+        let addr = bind.unwrap_or_else(||
+            SocketAddr::from(([0, 0, 0, 0], 8384)));
+        Command::Replay(addr, path)
+    } else if let Some(target) = relay {
+        let addr = bind.unwrap_or_else(||
+            SocketAddr::from(([0, 0, 0, 0], 8384)));
+        Command::Relay(addr, target)
+    } else if let Some(addr) = bind {
+        Command::Serve(addr)
+    } else {
+        if target_ips.is_empty() {
+            target_ips.push(SocketAddr::from(([127, 0, 0, 1], 8384)));
+        }
+        Command::Connect(target_ips)
+    };
+    Ok((command, config, level))
 }
 
 fn main() -> Result<()> {
-    use TelekeyMode::*;
-    let (addr, mode, config) = parse_args()?;
-    match mode {
-        Client => Telekey::connect_to(addr, config),
-        Server => Telekey::serve(addr, config)
+    let (command, config, level) = parse_args()?;
+    // enigo connects to the display named by `DISPLAY` on Linux, set while
+    // no other thread may read the environment
+    if let Some(display) = config.x_display() {
+        if cfg!(target_os = "linux") {
+            std::env::set_var("DISPLAY", display);
+        }
+    }
+    // diagnostics go to stderr, apart from the menu printed to stdout.
+    // RUST_LOG takes precedence over the verbosity. Lines logged during a
+    // session carry its ID, the same at both ends
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format(|buf, record| {
+            let level = buf.default_styled_level(record.level());
+            match Telekey::session_id() {
+                Some(id) => writeln!(buf, "[{} {} {}] {}", buf.timestamp(), level, id, record.args()),
+                None => writeln!(buf, "[{} {}] {}", buf.timestamp(), level, record.args())
+            }
+        })
+        .parse_default_env()
+        .init();
+    if let Command::Serve(_) = command {
+        // the first Ctrl+C stops the server cleanly, the second one right away
+        ctrlc::set_handler(|| {
+            static REQUESTED: AtomicBool = AtomicBool::new(false);
+            if REQUESTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            Telekey::shutdown();
+        }).context("Could not install the Ctrl+C handler")?;
+    }
+    match command {
+        Command::Connect(addrs) => Telekey::connect_to_any(&addrs, config),
+        Command::Serve(addr) => Telekey::serve(addr, config),
+        Command::Relay(addr, target) => Telekey::relay(addr, target, config),
+        Command::Replay(addr, path) => Telekey::replay(addr, &path, config),
+        Command::TestEmulate(path) => Telekey::test_emulate(&path, config),
+        Command::Probe(addr) => Telekey::probe(addr, config),
+        Command::ExportConfig(path) => config.export(&path),
+        Command::DecryptTranscript(path) => {
+            transcript::decrypt(&path, &transcript::passphrase()?, &mut std::io::stdout())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_without_port_defaults_to_8384() {
+        assert_eq!(parse_ip("127.0.0.1").unwrap(), SocketAddr::from(([127, 0, 0, 1], 8384)));
+        assert_eq!(parse_ip("::1").unwrap(), "[::1]:8384".parse::<SocketAddr>().unwrap());
+        assert_eq!(parse_ip("[::1]").unwrap(), "[::1]:8384".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn ip_with_port() {
+        assert_eq!(parse_ip("127.0.0.1:9000").unwrap(), SocketAddr::from(([127, 0, 0, 1], 9000)));
+        assert_eq!(parse_ip("[::1]:9000").unwrap(), "[::1]:9000".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn unbracketed_ipv6_with_port_is_rejected() {
+        assert!(parse_ip("::1:9000:zz").is_err());
+        assert!(parse_ip("127.0.0.1:port").is_err());
+    }
+
+    #[test]
+    fn quiet_only_logs_errors() {
+        let (_, config, level) = parse_args_from(["-q", "-V"]).unwrap();
+        assert!(config.is_quiet());
+        assert_eq!(level, log::LevelFilter::Error);
+        let (_, config, level) = parse_args_from(["-V"]).unwrap();
+        assert!(!config.is_quiet());
+        assert_eq!(level, log::LevelFilter::Debug);
+    }
+
+    #[test]
+    fn hostname_is_set_and_validated() {
+        let (_, config, _) = parse_args_from(["--hostname", "desk-2"]).unwrap();
+        assert_eq!(config.hostname(), "desk-2");
+        assert!(parse_args_from(["--hostname", &"x".repeat(65)]).is_err());
+        assert!(parse_args_from(["--hostname", "desk\x1b[2J"]).is_err());
+        assert!(parse_args_from(["--hostname", " "]).is_err());
+    }
+
+    #[test]
+    fn target_ips_are_tried_in_order() {
+        let (command, _, _) = parse_args_from(["-t", "10.0.0.1", "-t", "10.0.0.2:9000"]).unwrap();
+        let expected = [SocketAddr::from(([10, 0, 0, 1], 8384)), SocketAddr::from(([10, 0, 0, 2], 9000))];
+        assert!(matches!(command, Command::Connect(addrs) if addrs == expected));
+        let (command, _, _) = parse_args_from(Vec::<String>::new()).unwrap();
+        assert!(matches!(command, Command::Connect(addrs) if addrs == [SocketAddr::from(([127, 0, 0, 1], 8384))]));
+    }
+
+    #[test]
+    fn x_display_is_kept_in_the_config() {
+        let (_, config, _) = parse_args_from(["--x-display", ":1"]).unwrap();
+        assert_eq!(config.x_display(), Some(":1"));
+        let (_, config, _) = parse_args_from(Vec::<String>::new()).unwrap();
+        assert_eq!(config.x_display(), None);
     }
 }
@@ -35,6 +35,21 @@ pub enum KeyKind {
     ESC = 17,
     SHIFT = 18,
     META = 19,
+    MENU = 20,
+    PAUSE = 21,
+    SCROLL = 22,
+    VOLUME_UP = 23,
+    VOLUME_DOWN = 24,
+    MUTE = 25,
+    PLAY_PAUSE = 26,
+    NEXT = 27,
+    PREV = 28,
+    CAPS_LOCK = 29,
+    NUM_LOCK = 30,
+    SCROLL_LOCK = 31,
+    PRINT_SCREEN = 32,
+    NUMPAD = 33,
+    RAW = 34,
 }
 
 impl Default for KeyKind {
@@ -65,6 +80,21 @@ impl From<i32> for KeyKind {
             17 => KeyKind::ESC,
             18 => KeyKind::SHIFT,
             19 => KeyKind::META,
+            20 => KeyKind::MENU,
+            21 => KeyKind::PAUSE,
+            22 => KeyKind::SCROLL,
+            23 => KeyKind::VOLUME_UP,
+            24 => KeyKind::VOLUME_DOWN,
+            25 => KeyKind::MUTE,
+            26 => KeyKind::PLAY_PAUSE,
+            27 => KeyKind::NEXT,
+            28 => KeyKind::PREV,
+            29 => KeyKind::CAPS_LOCK,
+            30 => KeyKind::NUM_LOCK,
+            31 => KeyKind::SCROLL_LOCK,
+            32 => KeyKind::PRINT_SCREEN,
+            33 => KeyKind::NUMPAD,
+            34 => KeyKind::RAW,
             _ => Self::default(),
         }
     }
@@ -92,6 +122,129 @@ impl<'a> From<&'a str> for KeyKind {
             "ESC" => KeyKind::ESC,
             "SHIFT" => KeyKind::SHIFT,
             "META" => KeyKind::META,
+            "MENU" => KeyKind::MENU,
+            "PAUSE" => KeyKind::PAUSE,
+            "SCROLL" => KeyKind::SCROLL,
+            "VOLUME_UP" => KeyKind::VOLUME_UP,
+            "VOLUME_DOWN" => KeyKind::VOLUME_DOWN,
+            "MUTE" => KeyKind::MUTE,
+            "PLAY_PAUSE" => KeyKind::PLAY_PAUSE,
+            "NEXT" => KeyKind::NEXT,
+            "PREV" => KeyKind::PREV,
+            "CAPS_LOCK" => KeyKind::CAPS_LOCK,
+            "NUM_LOCK" => KeyKind::NUM_LOCK,
+            "SCROLL_LOCK" => KeyKind::SCROLL_LOCK,
+            "PRINT_SCREEN" => KeyKind::PRINT_SCREEN,
+            "NUMPAD" => KeyKind::NUMPAD,
+            "RAW" => KeyKind::RAW,
+            _ => Self::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum KeyState {
+    CLICK = 0,
+    DOWN = 1,
+    UP = 2,
+}
+
+impl Default for KeyState {
+    fn default() -> Self {
+        KeyState::CLICK
+    }
+}
+
+impl From<i32> for KeyState {
+    fn from(i: i32) -> Self {
+        match i {
+            0 => KeyState::CLICK,
+            1 => KeyState::DOWN,
+            2 => KeyState::UP,
+            _ => Self::default(),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for KeyState {
+    fn from(s: &'a str) -> Self {
+        match s {
+            "CLICK" => KeyState::CLICK,
+            "DOWN" => KeyState::DOWN,
+            "UP" => KeyState::UP,
+            _ => Self::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MouseAction {
+    MOVE = 0,
+    CLICK = 1,
+    PRESS = 2,
+    RELEASE = 3,
+}
+
+impl Default for MouseAction {
+    fn default() -> Self {
+        MouseAction::MOVE
+    }
+}
+
+impl From<i32> for MouseAction {
+    fn from(i: i32) -> Self {
+        match i {
+            0 => MouseAction::MOVE,
+            1 => MouseAction::CLICK,
+            2 => MouseAction::PRESS,
+            3 => MouseAction::RELEASE,
+            _ => Self::default(),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for MouseAction {
+    fn from(s: &'a str) -> Self {
+        match s {
+            "MOVE" => MouseAction::MOVE,
+            "CLICK" => MouseAction::CLICK,
+            "PRESS" => MouseAction::PRESS,
+            "RELEASE" => MouseAction::RELEASE,
+            _ => Self::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MouseButton {
+    LEFT = 0,
+    RIGHT = 1,
+    MIDDLE = 2,
+}
+
+impl Default for MouseButton {
+    fn default() -> Self {
+        MouseButton::LEFT
+    }
+}
+
+impl From<i32> for MouseButton {
+    fn from(i: i32) -> Self {
+        match i {
+            0 => MouseButton::LEFT,
+            1 => MouseButton::RIGHT,
+            2 => MouseButton::MIDDLE,
+            _ => Self::default(),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for MouseButton {
+    fn from(s: &'a str) -> Self {
+        match s {
+            "LEFT" => MouseButton::LEFT,
+            "RIGHT" => MouseButton::RIGHT,
+            "MIDDLE" => MouseButton::MIDDLE,
             _ => Self::default(),
         }
     }
@@ -103,6 +256,11 @@ pub struct HandshakeRequest<'a> {
     pub version: u32,
     pub token: Cow<'a, [u8]>,
     pub pkey: Cow<'a, [u8]>,
+    pub max_rate: u32,
+    pub compress: bool,
+    pub absolute_mouse: bool,
+    pub reversed: bool,
+    pub nonce: Cow<'a, [u8]>,
 }
 
 impl<'a> MessageRead<'a> for HandshakeRequest<'a> {
@@ -114,6 +272,11 @@ impl<'a> MessageRead<'a> for HandshakeRequest<'a> {
                 Ok(21) => msg.version = r.read_fixed32(bytes)?,
                 Ok(26) => msg.token = r.read_bytes(bytes).map(Cow::Borrowed)?,
                 Ok(34) => msg.pkey = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(40) => msg.max_rate = r.read_uint32(bytes)?,
+                Ok(48) => msg.compress = r.read_bool(bytes)?,
+                Ok(56) => msg.absolute_mouse = r.read_bool(bytes)?,
+                Ok(64) => msg.reversed = r.read_bool(bytes)?,
+                Ok(74) => msg.nonce = r.read_bytes(bytes).map(Cow::Borrowed)?,
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -129,6 +292,11 @@ impl<'a> MessageWrite for HandshakeRequest<'a> {
         + if self.version == 0u32 { 0 } else { 1 + 4 }
         + if self.token == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.token).len()) }
         + if self.pkey == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.pkey).len()) }
+        + if self.max_rate == 0u32 { 0 } else { 1 + sizeof_varint(*(&self.max_rate) as u64) }
+        + if self.compress == false { 0 } else { 1 + 1 }
+        + if self.absolute_mouse == false { 0 } else { 1 + 1 }
+        + if self.reversed == false { 0 } else { 1 + 1 }
+        + if self.nonce == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.nonce).len()) }
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
@@ -136,6 +304,11 @@ impl<'a> MessageWrite for HandshakeRequest<'a> {
         if self.version != 0u32 { w.write_with_tag(21, |w| w.write_fixed32(*&self.version))?; }
         if self.token != Cow::Borrowed(b"") { w.write_with_tag(26, |w| w.write_bytes(&**&self.token))?; }
         if self.pkey != Cow::Borrowed(b"") { w.write_with_tag(34, |w| w.write_bytes(&**&self.pkey))?; }
+        if self.max_rate != 0u32 { w.write_with_tag(40, |w| w.write_uint32(*&self.max_rate))?; }
+        if self.compress != false { w.write_with_tag(48, |w| w.write_bool(*&self.compress))?; }
+        if self.absolute_mouse != false { w.write_with_tag(56, |w| w.write_bool(*&self.absolute_mouse))?; }
+        if self.reversed != false { w.write_with_tag(64, |w| w.write_bool(*&self.reversed))?; }
+        if self.nonce != Cow::Borrowed(b"") { w.write_with_tag(74, |w| w.write_bytes(&**&self.nonce))?; }
         Ok(())
     }
 }
@@ -145,6 +318,12 @@ pub struct HandshakeResponse<'a> {
     pub hostname: Cow<'a, str>,
     pub version: u32,
     pub pkey: Cow<'a, [u8]>,
+    pub max_rate: u32,
+    pub compress: bool,
+    pub absolute_mouse: bool,
+    pub reversed: bool,
+    pub nonce: Cow<'a, [u8]>,
+    pub session: Cow<'a, str>,
 }
 
 impl<'a> MessageRead<'a> for HandshakeResponse<'a> {
@@ -155,6 +334,12 @@ impl<'a> MessageRead<'a> for HandshakeResponse<'a> {
                 Ok(10) => msg.hostname = r.read_string(bytes).map(Cow::Borrowed)?,
                 Ok(21) => msg.version = r.read_fixed32(bytes)?,
                 Ok(26) => msg.pkey = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(32) => msg.max_rate = r.read_uint32(bytes)?,
+                Ok(40) => msg.compress = r.read_bool(bytes)?,
+                Ok(48) => msg.absolute_mouse = r.read_bool(bytes)?,
+                Ok(56) => msg.reversed = r.read_bool(bytes)?,
+                Ok(66) => msg.nonce = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(74) => msg.session = r.read_string(bytes).map(Cow::Borrowed)?,
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -169,12 +354,24 @@ impl<'a> MessageWrite for HandshakeResponse<'a> {
         + if self.hostname == "" { 0 } else { 1 + sizeof_len((&self.hostname).len()) }
         + if self.version == 0u32 { 0 } else { 1 + 4 }
         + if self.pkey == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.pkey).len()) }
+        + if self.max_rate == 0u32 { 0 } else { 1 + sizeof_varint(*(&self.max_rate) as u64) }
+        + if self.compress == false { 0 } else { 1 + 1 }
+        + if self.absolute_mouse == false { 0 } else { 1 + 1 }
+        + if self.reversed == false { 0 } else { 1 + 1 }
+        + if self.nonce == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.nonce).len()) }
+        + if self.session == "" { 0 } else { 1 + sizeof_len((&self.session).len()) }
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
         if self.hostname != "" { w.write_with_tag(10, |w| w.write_string(&**&self.hostname))?; }
         if self.version != 0u32 { w.write_with_tag(21, |w| w.write_fixed32(*&self.version))?; }
         if self.pkey != Cow::Borrowed(b"") { w.write_with_tag(26, |w| w.write_bytes(&**&self.pkey))?; }
+        if self.max_rate != 0u32 { w.write_with_tag(32, |w| w.write_uint32(*&self.max_rate))?; }
+        if self.compress != false { w.write_with_tag(40, |w| w.write_bool(*&self.compress))?; }
+        if self.absolute_mouse != false { w.write_with_tag(48, |w| w.write_bool(*&self.absolute_mouse))?; }
+        if self.reversed != false { w.write_with_tag(56, |w| w.write_bool(*&self.reversed))?; }
+        if self.nonce != Cow::Borrowed(b"") { w.write_with_tag(66, |w| w.write_bytes(&**&self.nonce))?; }
+        if self.session != "" { w.write_with_tag(74, |w| w.write_string(&**&self.session))?; }
         Ok(())
     }
 }
@@ -184,6 +381,8 @@ pub struct KeyEvent {
     pub kind: KeyKind,
     pub key: u32,
     pub modifiers: u32,
+    pub state: KeyState,
+    pub repeat: u32,
 }
 
 impl<'a> MessageRead<'a> for KeyEvent {
@@ -194,6 +393,8 @@ impl<'a> MessageRead<'a> for KeyEvent {
                 Ok(8) => msg.kind = r.read_enum(bytes)?,
                 Ok(16) => msg.key = r.read_uint32(bytes)?,
                 Ok(24) => msg.modifiers = r.read_uint32(bytes)?,
+                Ok(32) => msg.state = r.read_enum(bytes)?,
+                Ok(40) => msg.repeat = r.read_uint32(bytes)?,
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -208,12 +409,63 @@ impl MessageWrite for KeyEvent {
         + if self.kind == api::KeyKind::UNKNOWN { 0 } else { 1 + sizeof_varint(*(&self.kind) as u64) }
         + if self.key == 0u32 { 0 } else { 1 + sizeof_varint(*(&self.key) as u64) }
         + if self.modifiers == 0u32 { 0 } else { 1 + sizeof_varint(*(&self.modifiers) as u64) }
+        + if self.state == api::KeyState::CLICK { 0 } else { 1 + sizeof_varint(*(&self.state) as u64) }
+        + if self.repeat == 0u32 { 0 } else { 1 + sizeof_varint(*(&self.repeat) as u64) }
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
         if self.kind != api::KeyKind::UNKNOWN { w.write_with_tag(8, |w| w.write_enum(*&self.kind as i32))?; }
         if self.key != 0u32 { w.write_with_tag(16, |w| w.write_uint32(*&self.key))?; }
         if self.modifiers != 0u32 { w.write_with_tag(24, |w| w.write_uint32(*&self.modifiers))?; }
+        if self.state != api::KeyState::CLICK { w.write_with_tag(32, |w| w.write_enum(*&self.state as i32))?; }
+        if self.repeat != 0u32 { w.write_with_tag(40, |w| w.write_uint32(*&self.repeat))?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct MouseEvent {
+    pub action: MouseAction,
+    pub button: MouseButton,
+    pub x: i32,
+    pub y: i32,
+    pub absolute: bool,
+}
+
+impl<'a> MessageRead<'a> for MouseEvent {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(8) => msg.action = r.read_enum(bytes)?,
+                Ok(16) => msg.button = r.read_enum(bytes)?,
+                Ok(24) => msg.x = r.read_sint32(bytes)?,
+                Ok(32) => msg.y = r.read_sint32(bytes)?,
+                Ok(40) => msg.absolute = r.read_bool(bytes)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl MessageWrite for MouseEvent {
+    fn get_size(&self) -> usize {
+        0
+        + if self.action == api::MouseAction::MOVE { 0 } else { 1 + sizeof_varint(*(&self.action) as u64) }
+        + if self.button == api::MouseButton::LEFT { 0 } else { 1 + sizeof_varint(*(&self.button) as u64) }
+        + if self.x == 0i32 { 0 } else { 1 + sizeof_sint32(*(&self.x)) }
+        + if self.y == 0i32 { 0 } else { 1 + sizeof_sint32(*(&self.y)) }
+        + if self.absolute == false { 0 } else { 1 + 1 }
+    }
+
+    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
+        if self.action != api::MouseAction::MOVE { w.write_with_tag(8, |w| w.write_enum(*&self.action as i32))?; }
+        if self.button != api::MouseButton::LEFT { w.write_with_tag(16, |w| w.write_enum(*&self.button as i32))?; }
+        if self.x != 0i32 { w.write_with_tag(24, |w| w.write_sint32(*&self.x))?; }
+        if self.y != 0i32 { w.write_with_tag(32, |w| w.write_sint32(*&self.y))?; }
+        if self.absolute != false { w.write_with_tag(40, |w| w.write_bool(*&self.absolute))?; }
         Ok(())
     }
 }
@@ -1,17 +1,42 @@
 pub mod bindings;
 pub mod transport;
+pub mod transcript;
+pub mod theme;
+pub mod queue;
+pub mod recording;
+pub mod sink;
+pub mod source;
 use crate::protocol::bindings::api::*;
 use crate::transport::*;
-use chrono::{Utc, Duration};
-use enigo::{Enigo, KeyboardControllable};
+use crate::transcript::Transcript;
+use crate::theme::Theme;
+use crate::queue::{QueuedTransport, DropPolicy};
+use crate::recording::Recorder;
+use crate::sink::{KeySink, EnigoSink, ColdRunSink, ColdFormat, SharedSink};
+#[cfg(feature = "serde")]
+use crate::sink::JsonSink;
+use crate::source::{KeySource, TermSource, SharedSource};
+use chrono::Utc;
 use console::{Term, style};
-use std::{io::{self, Write}, net::*, borrow::Cow};
+use std::{io::{self, Write, BufWriter, IsTerminal}, net::*, borrow::Cow, str::FromStr};
+use std::{fs::{File, OpenOptions}, path::{Path, PathBuf}};
 use anyhow::{Result, Context, bail, anyhow};
-use std::collections::VecDeque;
+use std::collections::{VecDeque, BTreeMap, HashMap};
+use std::sync::{mpsc, Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}};
 use orion::kex::*;
 use quick_protobuf::deserialize_from_slice;
+use serde::{Serialize, Deserialize};
+use qrcode::{QrCode, render::unicode};
+use ipnet::IpNet;
+use log::{debug, info, warn, error};
+use zeroize::Zeroizing;
 
 pub const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
+/// Above this many keys between latency checks, the latency shown is
+/// likely to be stale for most of the session
+pub const MAX_REFRESH_LATENCY: usize = 10_000;
+/// Longest hostname sent in the handshake, in characters
+const MAX_HOSTNAME: usize = 64;
 
 /*
 #[macro_export]
@@ -24,27 +49,162 @@ macro_rules! prof {
 }
 */
 
+/// Minimum security a configuration must meet, see
+/// [`TelekeyConfig::check_security`]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SecurityLevel {
+    None,
+    /// Sessions are always encrypted
+    Encrypted,
+    /// Sessions are always encrypted and nothing is written in plaintext
+    Strict
+}
+
+impl FromStr for SecurityLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "encrypted" => Ok(Self::Encrypted),
+            "strict" => Ok(Self::Strict),
+            _ => bail!("Unknown security level `{}` (expected none, encrypted or strict)", s)
+        }
+    }
+}
+
+/// What the server does when the address of the current peer connects
+/// again while its session is still running
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Keeps the current session, the new connection waits for it to end
+    Warn,
+    /// Closes the current session to serve the new connection right away
+    Replace
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "replace" => Ok(Self::Replace),
+            _ => bail!("Unknown duplicate policy `{}` (expected warn or replace)", s)
+        }
+    }
+}
+
+/// Which end of a session types the keys, whatever its role
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Reads the keys and sends them to the peer
+    Send,
+    /// Emulates the keys received from the peer
+    Receive
+}
+
+impl FromStr for Direction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "send" => Ok(Self::Send),
+            "receive" => Ok(Self::Receive),
+            _ => bail!("Unknown direction `{}` (expected send or receive)", s)
+        }
+    }
+}
+
 #[derive(Clone, Debug, Copy)]
 pub enum TelekeyMode {
     Client,
     Server
 }
 
-#[derive(Clone, Debug)]
+/// Sensitive or machine-specific fields must be marked `skip_serializing` so
+/// they never end up in an exported config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TelekeyConfig {
+    #[serde(skip_serializing)]
     hostname: String,
     secure: bool,
     update_screen: bool,
     refresh_latency: Option<usize>,
     cold_run: bool,
+    cold_format: ColdFormat,
+    quiet: bool,
+    direction: Option<Direction>,
+    activation_delay: u64,
+    x_display: Option<String>,
+    idle_timeout: Option<u64>,
+    reject_log: Option<PathBuf>,
+    allow_downgrade: bool,
+    transcript: Option<PathBuf>,
+    plain_transcript: bool,
+    record: Option<PathBuf>,
+    history_log: Option<PathBuf>,
+    history_size: usize,
+    max_rate: u32,
+    coalesce: Option<u64>,
+    on_duplicate: DuplicatePolicy,
+    send_queue: usize,
+    drop_policy: DropPolicy,
+    max_packet_size: usize,
+    nodelay: bool,
+    read_timeout: Option<u64>,
+    connect_timeout: Option<u64>,
+    max_hold: Option<u64>,
+    reconnect: bool,
+    max_retries: Option<u32>,
+    reconnect_delay: u64,
+    multi_client: bool,
+    qr: bool,
+    #[serde(skip)]
+    token: Option<String>,
+    token_ttl: Option<u64>,
+    max_failures: Option<u32>,
+    failure_window: u64,
+    allowlist: Vec<IpNet>,
+    compress: bool,
+    absolute_mouse: bool,
+    paste_key: Option<String>,
+    pause_key: Option<String>,
+    clipboard: bool,
+    clipboard_key: Option<String>,
+    fetch_clipboard_key: Option<String>,
+    keepalive: Option<u64>,
+    remap: BTreeMap<String, String>,
+    #[serde(skip)]
+    transforms: Vec<KeyTransform>,
+    #[serde(skip)]
+    sink: Option<SharedSink>,
+    #[serde(skip)]
+    source: Option<SharedSource>,
+    #[serde(skip)]
+    on_event: Option<EventHook>,
+    theme: Theme,
 }
 
 #[allow(dead_code)]
 impl TelekeyConfig {
+    pub fn builder() -> TelekeyBuilder {
+        TelekeyBuilder::default()
+    }
+
     pub fn hostname(&self) -> &str {
         &self.hostname
     }
 
+    /// Name sent to the peer during the handshake, this machine's hostname
+    /// by default
+    pub fn set_hostname(&mut self, hostname: String) {
+        self.hostname = hostname;
+    }
+
     pub fn is_secure(&self) -> bool {
         self.secure
     }
@@ -64,6 +224,391 @@ impl TelekeyConfig {
     pub fn set_cold_run(&mut self, cold_run: bool) {
         self.cold_run = cold_run;
     }
+
+    /// How a cold run prints the keys received
+    pub fn set_cold_format(&mut self, format: ColdFormat) {
+        self.cold_format = format;
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Prints neither the menu, the headers nor the decorations around the
+    /// token, only what other tools may read: the token and the keys of a
+    /// cold run
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Whether this end sends or receives the keys. By default, the server
+    /// sends them and the client receives them. Both ends must agree
+    pub fn set_direction(&mut self, direction: Option<Direction>) {
+        self.direction = direction;
+    }
+
+    pub fn set_activation_delay(&mut self, secs: u64) {
+        self.activation_delay = secs;
+    }
+
+    /// Shows the session as idle in the menu after `secs` seconds without
+    /// any key press
+    pub fn set_idle_timeout(&mut self, secs: Option<u64>) {
+        self.idle_timeout = secs;
+    }
+
+    /// Accepts to fall back to an unsecure session when the peer does not
+    /// run in secure mode
+    pub fn set_allow_downgrade(&mut self, allow_downgrade: bool) {
+        self.allow_downgrade = allow_downgrade;
+    }
+
+    /// File recording every key of the sessions, encrypted with a passphrase
+    /// unless `plain_transcript` is set
+    pub fn set_transcript(&mut self, transcript: Option<PathBuf>) {
+        self.transcript = transcript;
+    }
+
+    /// Number of keys sent last shown in the server menu, 0 to show none
+    pub fn set_history_size(&mut self, size: usize) {
+        self.history_size = size;
+    }
+
+    /// File the server appends every key it sends to, one timestamped line
+    /// each, to audit what was typed. Always in plaintext
+    pub fn set_history_log(&mut self, history_log: Option<PathBuf>) {
+        self.history_log = history_log;
+    }
+
+    /// Macro file the server appends the keys it sends to, with their
+    /// timing, so that they can be replayed. Always in plaintext
+    pub fn set_record(&mut self, record: Option<PathBuf>) {
+        self.record = record;
+    }
+
+    pub fn set_plain_transcript(&mut self, plain_transcript: bool) {
+        self.plain_transcript = plain_transcript;
+    }
+
+    /// Maximum number of keys per second this instance accepts, advertised
+    /// during the handshake so the peer paces its sends. 0 means unlimited
+    pub fn set_max_rate(&mut self, max_rate: u32) {
+        self.max_rate = max_rate;
+    }
+
+    /// Sends the clicks of a key repeated within `ms` milliseconds of each
+    /// other as a single key with their count, once `ms` milliseconds have
+    /// passed since the first of them. The first click is sent right away.
+    /// `None` sends every click on its own
+    pub fn set_coalesce(&mut self, ms: Option<u64>) {
+        self.coalesce = ms;
+    }
+
+    pub fn set_on_duplicate(&mut self, policy: DuplicatePolicy) {
+        self.on_duplicate = policy;
+    }
+
+    /// Number of packets the server queues while a slow link sends the
+    /// previous ones. 0 sends them synchronously from the input loop
+    pub fn set_send_queue(&mut self, depth: usize) {
+        self.send_queue = depth;
+    }
+
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
+    }
+
+    /// Disables Nagle's algorithm so each key is sent right away instead of
+    /// being batched with the next ones
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Ends the session when the peer sends nothing for `secs` seconds while
+    /// a packet is awaited. A client awaits keys, so this also ends its
+    /// sessions after that long without any key
+    pub fn set_read_timeout(&mut self, secs: Option<u64>) {
+        self.read_timeout = secs;
+    }
+
+    /// Gives up connecting to the server after `secs` seconds. `None` waits
+    /// as long as the system does, which can be minutes
+    pub fn set_connect_timeout(&mut self, secs: Option<u64>) {
+        self.connect_timeout = secs;
+    }
+
+    /// Releases the keys and buttons the server has held down on the client
+    /// for `secs` seconds, in case their release got lost. Whatever is held
+    /// is always released when the session ends
+    pub fn set_max_hold(&mut self, secs: Option<u64>) {
+        self.max_hold = secs;
+    }
+
+    /// Makes the client connect again when the session drops, and the
+    /// server accept the token of its last peer once more when it does
+    pub fn set_reconnect(&mut self, reconnect: bool) {
+        self.reconnect = reconnect;
+    }
+
+    /// Reconnection attempts in a row before giving up, `None` for no limit
+    pub fn set_max_retries(&mut self, max_retries: Option<u32>) {
+        self.max_retries = max_retries;
+    }
+
+    /// Seconds before the first reconnection attempt, doubled after each
+    /// failed one
+    pub fn set_reconnect_delay(&mut self, secs: u64) {
+        self.reconnect_delay = secs;
+    }
+
+    /// Lets the server send the same keys to several clients at once, all
+    /// of them using the token shown on start
+    pub fn set_multi_client(&mut self, multi_client: bool) {
+        self.multi_client = multi_client;
+    }
+
+    /// Refuses handshakes arriving more than `secs` seconds after their
+    /// token was shown. The shared token of a multi-client server never
+    /// expires
+    pub fn set_token_ttl(&mut self, secs: Option<u64>) {
+        self.token_ttl = secs;
+    }
+
+    /// Locks an address out for `LOCKOUT` once its handshakes failed `max`
+    /// times within the failure window. `None` never locks anyone out
+    pub fn set_max_failures(&mut self, max: Option<u32>) {
+        self.max_failures = max;
+    }
+
+    /// Seconds during which the failed handshakes of an address are counted
+    pub fn set_failure_window(&mut self, secs: u64) {
+        self.failure_window = secs;
+    }
+
+    /// Asks the peer to compress the larger packets. Only used if both
+    /// ends ask for it
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Lets the server move the pointer to absolute positions on the
+    /// client's screen, rather than only by relative amounts. Only used if
+    /// both ends ask for it
+    pub fn set_absolute_mouse(&mut self, absolute_mouse: bool) {
+        self.absolute_mouse = absolute_mouse;
+    }
+
+    /// Also shows the session tokens as QR codes in the terminal
+    pub fn set_qr(&mut self, qr: bool) {
+        self.qr = qr;
+    }
+
+    /// Token of the server the client connects to, so it is not asked.
+    /// Otherwise, it is taken from the `TELEKEY_TOKEN` environment variable
+    /// when set. Never written to config files
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    /// Lets the server accept connections from `net`. Once any network is
+    /// allowed, connections from other addresses are dropped
+    pub fn allow(&mut self, net: IpNet) {
+        self.allowlist.push(net);
+    }
+
+    /// Tells whether the server accepts connections from `ip`
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allowlist.is_empty() || self.allowlist.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Makes the server ping the peer after `secs` seconds without any key,
+    /// ending the session if it does not answer within `KEEPALIVE_TIMEOUT`
+    pub fn set_keepalive(&mut self, secs: Option<u64>) {
+        self.keepalive = secs;
+    }
+
+    /// Key spec of the server hotkey sending the local clipboard as text,
+    /// e.g. `ALT+CHAR v`. `None` forwards that key like any other
+    pub fn set_paste_key(&mut self, spec: Option<String>) {
+        self.paste_key = spec;
+    }
+
+    /// Key spec of the server hotkey pausing and resuming the forwarding of
+    /// keys. `None` forwards that key like any other
+    pub fn set_pause_key(&mut self, spec: Option<String>) {
+        self.pause_key = spec;
+    }
+
+    /// Shares the clipboard with the peer, which must share it as well. The
+    /// sender of the keys sets the clipboard of the receiver, or fetches it,
+    /// with the clipboard hotkeys
+    pub fn set_clipboard(&mut self, clipboard: bool) {
+        self.clipboard = clipboard;
+    }
+
+    /// Key spec of the hotkey setting the clipboard of the peer to the local
+    /// one. Only a hotkey when the clipboard is shared
+    pub fn set_clipboard_key(&mut self, spec: Option<String>) {
+        self.clipboard_key = spec;
+    }
+
+    /// Key spec of the hotkey setting the local clipboard to the one of the
+    /// peer. Only a hotkey when the clipboard is shared
+    pub fn set_fetch_clipboard_key(&mut self, spec: Option<String>) {
+        self.fetch_clipboard_key = spec;
+    }
+
+    /// Largest packet accepted from the peer, in bytes. Larger ones end the
+    /// session before being buffered
+    pub fn set_max_packet_size(&mut self, max: usize) {
+        self.max_packet_size = max;
+    }
+
+    /// Makes the server send `to` instead of `from`, both being key specs.
+    /// `from` matches the key whatever its modifiers, which are kept along
+    /// with the ones of `to`
+    pub fn remap(&mut self, from: String, to: String) {
+        self.remap.insert(from, to);
+    }
+
+    /// Adds a transform applied, in order, to every key before it is sent
+    pub fn add_transform(&mut self, transform: KeyTransform) {
+        self.transforms.push(transform);
+    }
+
+    /// Where the client delivers the keys instead of emulating them, taking
+    /// precedence over cold-run
+    pub fn set_sink(&mut self, sink: impl KeySink + Send + 'static) {
+        self.sink = Some(SharedSink::new(sink));
+    }
+
+    /// Where the server reads the keys it sends instead of the terminal
+    pub fn set_source(&mut self, source: impl KeySource + Send + 'static) {
+        self.source = Some(SharedSource::new(source));
+    }
+
+    /// Called on the events of the sessions, e.g. to follow them from an
+    /// application embedding telekey
+    pub fn set_on_event(&mut self, f: impl FnMut(TelekeyEvent) + Send + 'static) {
+        self.on_event = Some(EventHook::new(f));
+    }
+
+    /// Badges shown in the server menu
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    /// File where the server appends refused connection attempts
+    pub fn set_reject_log(&mut self, reject_log: Option<PathBuf>) {
+        self.reject_log = reject_log;
+    }
+
+    /// X display the key presses are emulated on (Linux only)
+    pub fn x_display(&self) -> Option<&str> {
+        self.x_display.as_deref()
+    }
+
+    /// X display the key presses are emulated on (Linux only), overriding
+    /// the `DISPLAY` environment variable. enigo only reads `DISPLAY`, which
+    /// the caller sets to it before any thread starts, as `main` does
+    pub fn set_x_display(&mut self, x_display: Option<String>) {
+        self.x_display = x_display;
+    }
+
+    /// Fails with the first requirement of `level` that this configuration
+    /// does not meet
+    pub fn check_security(&self, level: SecurityLevel) -> Result<()> {
+        if level >= SecurityLevel::Encrypted {
+            if !self.secure {
+                bail!("Security level `{:?}` requires encryption (remove --unsecure)", level);
+            }
+            if self.allow_downgrade {
+                bail!("Security level `{:?}` forbids --allow-downgrade", level);
+            }
+        }
+        if level >= SecurityLevel::Strict && self.transcript.is_some() && self.plain_transcript {
+            bail!("Security level `{:?}` forbids --plain-transcript", level);
+        }
+        if level >= SecurityLevel::Strict && self.record.is_some() {
+            bail!("Security level `{:?}` forbids --record", level);
+        }
+        if level >= SecurityLevel::Strict && self.history_log.is_some() {
+            bail!("Security level `{:?}` forbids --history-log", level);
+        }
+        Ok(())
+    }
+
+    /// Fails on the first option which cannot work as set
+    pub fn validate(&self) -> Result<()> {
+        if self.hostname.trim().is_empty() {
+            bail!("The hostname must not be empty");
+        }
+        if self.hostname.chars().count() > MAX_HOSTNAME {
+            bail!("The hostname must not be longer than {} characters", MAX_HOSTNAME);
+        }
+        // shown as is in the peer's menu
+        if self.hostname.chars().any(char::is_control) {
+            bail!("The hostname must not contain control characters");
+        }
+        if self.refresh_latency == Some(0) {
+            bail!("The latency refresh period must be at least 1 key, use None to disable it");
+        }
+        if self.max_packet_size == 0 {
+            bail!("The maximum packet size must not be 0");
+        }
+        if let Some(spec) = &self.paste_key {
+            spec.parse::<KeyEvent>().context("Invalid paste key")?;
+        }
+        if let Some(spec) = &self.pause_key {
+            spec.parse::<KeyEvent>().context("Invalid pause key")?;
+        }
+        if let Some(spec) = &self.clipboard_key {
+            spec.parse::<KeyEvent>().context("Invalid clipboard key")?;
+        }
+        if let Some(spec) = &self.fetch_clipboard_key {
+            spec.parse::<KeyEvent>().context("Invalid fetch clipboard key")?;
+        }
+        for (from, to) in &self.remap {
+            let e: KeyEvent = from.parse()
+                .with_context(|| format!("Invalid remapped key `{}`", from))?;
+            if e.modifiers != 0 {
+                bail!("Remapped key `{}` must not have modifiers", from);
+            }
+            to.parse::<KeyEvent>()
+                .with_context(|| format!("Invalid replacement key `{}`", to))?;
+        }
+        if self.paste_key.is_some() && self.paste_key == self.pause_key {
+            bail!("The paste and pause keys must differ");
+        }
+        if self.clipboard {
+            let hotkeys = [&self.paste_key, &self.pause_key, &self.clipboard_key,
+                           &self.fetch_clipboard_key];
+            for (i, key) in hotkeys.iter().enumerate() {
+                if key.is_some() && hotkeys[i + 1..].contains(key) {
+                    bail!("The hotkeys must differ, `{}` is set twice",
+                          key.as_deref().unwrap_or_default());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the resolved configuration to `path` as TOML so it can be
+    /// imported on another machine.
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let s = toml::to_string(self).context("Failed to serialize config")?;
+        std::fs::write(path, s)
+            .with_context(|| format!("Could not write `{}`", path.display()))
+    }
+
+    /// Loads a configuration previously written by [`TelekeyConfig::export`].
+    /// Missing fields keep their default value.
+    pub fn import(path: &Path) -> Result<Self> {
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read `{}`", path.display()))?;
+        toml::from_str(&s)
+            .with_context(|| format!("Invalid config file `{}`", path.display()))
+    }
 }
 
 impl Default for TelekeyConfig {
@@ -76,16 +621,292 @@ impl Default for TelekeyConfig {
             refresh_latency: Some(20),
             secure: true,
             update_screen: true,
-            cold_run: false
+            cold_run: false,
+            cold_format: ColdFormat::Text,
+            quiet: false,
+            direction: None,
+            activation_delay: 0,
+            x_display: None,
+            idle_timeout: None,
+            reject_log: None,
+            allow_downgrade: false,
+            transcript: None,
+            plain_transcript: false,
+            record: None,
+            history_log: None,
+            history_size: 20,
+            max_rate: 0,
+            coalesce: None,
+            on_duplicate: DuplicatePolicy::Warn,
+            send_queue: 0,
+            drop_policy: DropPolicy::Block,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            nodelay: true,
+            read_timeout: None,
+            connect_timeout: Some(5),
+            max_hold: None,
+            reconnect: false,
+            max_retries: None,
+            reconnect_delay: 1,
+            multi_client: false,
+            qr: false,
+            token: None,
+            token_ttl: Some(60),
+            max_failures: Some(5),
+            failure_window: 60,
+            allowlist: Vec::new(),
+            compress: false,
+            absolute_mouse: false,
+            paste_key: None,
+            pause_key: None,
+            clipboard: false,
+            clipboard_key: Some("ALT+CHAR c".to_string()),
+            fetch_clipboard_key: Some("ALT+CHAR y".to_string()),
+            keepalive: None,
+            remap: BTreeMap::new(),
+            transforms: Vec::new(),
+            sink: None,
+            source: None,
+            on_event: None,
+            theme: Theme::default()
+        }
+    }
+}
+
+/// Builds a configuration step by step, e.g.
+/// `TelekeyConfig::builder().secure(false).cold_run(true).build()`, or runs
+/// telekey with it right away: `Telekey::builder().secure(true).serve(addr)`
+#[derive(Clone, Debug, Default)]
+pub struct TelekeyBuilder {
+    config: TelekeyConfig
+}
+
+impl TelekeyBuilder {
+    /// Starts from `config` instead of the default configuration
+    pub fn config(mut self, config: TelekeyConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.config.set_hostname(hostname.into());
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.config.set_secure(secure);
+        self
+    }
+
+    pub fn allow_downgrade(mut self, allow_downgrade: bool) -> Self {
+        self.config.set_allow_downgrade(allow_downgrade);
+        self
+    }
+
+    pub fn cold_run(mut self, cold_run: bool) -> Self {
+        self.config.set_cold_run(cold_run);
+        self
+    }
+
+    pub fn cold_format(mut self, format: ColdFormat) -> Self {
+        self.config.set_cold_format(format);
+        self
+    }
+
+    pub fn clipboard(mut self, clipboard: bool) -> Self {
+        self.config.set_clipboard(clipboard);
+        self
+    }
+
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.config.set_quiet(quiet);
+        self
+    }
+
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.config.set_direction(Some(direction));
+        self
+    }
+
+    pub fn update_screen(mut self, update_screen: bool) -> Self {
+        self.config.set_update_screen(update_screen);
+        self
+    }
+
+    pub fn refresh_latency(mut self, refresh_latency: Option<usize>) -> Self {
+        self.config.set_refresh_latency(refresh_latency);
+        self
+    }
+
+    pub fn max_rate(mut self, max_rate: u32) -> Self {
+        self.config.set_max_rate(max_rate);
+        self
+    }
+
+    pub fn coalesce(mut self, ms: Option<u64>) -> Self {
+        self.config.set_coalesce(ms);
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.config.set_reconnect(reconnect);
+        self
+    }
+
+    pub fn activation_delay(mut self, secs: u64) -> Self {
+        self.config.set_activation_delay(secs);
+        self
+    }
+
+    pub fn idle_timeout(mut self, secs: Option<u64>) -> Self {
+        self.config.set_idle_timeout(secs);
+        self
+    }
+
+    pub fn read_timeout(mut self, secs: Option<u64>) -> Self {
+        self.config.set_read_timeout(secs);
+        self
+    }
+
+    pub fn connect_timeout(mut self, secs: Option<u64>) -> Self {
+        self.config.set_connect_timeout(secs);
+        self
+    }
+
+    pub fn transform(mut self, transform: KeyTransform) -> Self {
+        self.config.add_transform(transform);
+        self
+    }
+
+    pub fn sink(mut self, sink: impl KeySink + Send + 'static) -> Self {
+        self.config.set_sink(sink);
+        self
+    }
+
+    pub fn source(mut self, source: impl KeySource + Send + 'static) -> Self {
+        self.config.set_source(source);
+        self
+    }
+
+    pub fn on_event(mut self, f: impl FnMut(TelekeyEvent) + Send + 'static) -> Self {
+        self.config.set_on_event(f);
+        self
+    }
+
+    /// Returns the configuration once [validated](TelekeyConfig::validate)
+    pub fn build(self) -> Result<TelekeyConfig> {
+        self.config.validate().context("Invalid configuration")?;
+        Ok(self.config)
+    }
+
+    /// Runs a server on `addr`, see [`Telekey::serve`]
+    pub fn serve(self, addr: SocketAddr) -> Result<()> {
+        Telekey::serve(addr, self.build()?)
+    }
+
+    /// Runs a client of the server at `addr`, see [`Telekey::connect_to`]
+    pub fn connect(self, addr: SocketAddr) -> Result<()> {
+        Telekey::connect_to(addr, self.build()?)
+    }
+
+    /// Runs a client of the first server of `addrs` accepting the
+    /// connection, see [`Telekey::connect_to_any`]
+    pub fn connect_any(self, addrs: &[SocketAddr]) -> Result<()> {
+        Telekey::connect_to_any(addrs, self.build()?)
+    }
+
+    /// Runs a relay on `addr` for the server at `target`, see
+    /// [`Telekey::relay`]
+    pub fn relay(self, addr: SocketAddr, target: SocketAddr) -> Result<()> {
+        Telekey::relay(addr, target, self.build()?)
+    }
+}
+
+/// Rewrites a key event before it is sent, returning `None` drops it
+#[derive(Clone)]
+pub struct KeyTransform(Arc<dyn Fn(KeyEvent) -> Option<KeyEvent> + Send + Sync>);
+
+impl KeyTransform {
+    pub fn new(f: impl Fn(KeyEvent) -> Option<KeyEvent> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn apply(&self, e: KeyEvent) -> Option<KeyEvent> {
+        (self.0)(e)
+    }
+}
+
+impl std::fmt::Debug for KeyTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KeyTransform")
+    }
+}
+
+/// Built-in transforms: `uppercase`, `lowercase` and `ascii` (drops
+/// non-ASCII characters)
+impl FromStr for KeyTransform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        fn map_char(e: KeyEvent, f: impl Fn(char) -> Option<char>) -> Option<KeyEvent> {
+            if e.kind != KeyKind::CHAR {
+                return Some(e);
+            }
+            let c = f(char::from_u32(e.key)?)?;
+            Some(KeyEvent { key: c as u32, ..e })
+        }
+
+        match s {
+            "uppercase" => Ok(Self::new(|e| map_char(e, |c| c.to_uppercase().next()))),
+            "lowercase" => Ok(Self::new(|e| map_char(e, |c| c.to_lowercase().next()))),
+            "ascii" => Ok(Self::new(|e| map_char(e, |c| Some(c).filter(char::is_ascii)))),
+            _ => bail!("Unknown transform `{}` (expected uppercase, lowercase or ascii)", s)
         }
     }
 }
 
+/// Events of the sessions, see [`TelekeyConfig::set_on_event`]
+#[derive(Clone, Debug)]
+pub enum TelekeyEvent {
+    /// The handshake with a peer succeeded
+    PeerConnected { addr: Option<SocketAddr>, hostname: String },
+    /// The handshake with a peer failed, e.g. on a wrong token
+    HandshakeFailed { addr: Option<SocketAddr> },
+    /// The session with a connected peer ended
+    PeerDisconnected { addr: Option<SocketAddr> },
+    /// Round-trip time measured by the server
+    LatencyMeasured(std::time::Duration)
+}
+
+/// Callback of the configuration, shared by its clones so the sessions of
+/// every thread report to the same one
+#[derive(Clone)]
+pub struct EventHook(Arc<Mutex<dyn FnMut(TelekeyEvent) + Send>>);
+
+impl EventHook {
+    pub fn new(f: impl FnMut(TelekeyEvent) + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(f)))
+    }
+
+    pub fn call(&self, e: TelekeyEvent) {
+        (self.0.lock().unwrap())(e)
+    }
+}
+
+impl std::fmt::Debug for EventHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EventHook")
+    }
+}
+
 #[allow(dead_code)]
 struct TelekeyRemote {
     hostname: String,
     version: u32,
-    mode: TelekeyMode
+    mode: TelekeyMode,
+    max_rate: u32,
+    compress: bool,
+    absolute_mouse: bool
 }
 
 impl From<HandshakeRequest<'_>> for TelekeyRemote {
@@ -94,6 +915,22 @@ impl From<HandshakeRequest<'_>> for TelekeyRemote {
             hostname: msg.hostname.to_string(),
             version: msg.version,
             mode: TelekeyMode::Client,
+            max_rate: msg.max_rate,
+            compress: msg.compress,
+            absolute_mouse: msg.absolute_mouse
+        }
+    }
+}
+
+impl From<&HandshakeResponse<'_>> for TelekeyRemote {
+    fn from(msg: &HandshakeResponse) -> Self {
+        Self {
+            hostname: msg.hostname.to_string(),
+            version: msg.version,
+            mode: TelekeyMode::Server,
+            max_rate: msg.max_rate,
+            compress: msg.compress,
+            absolute_mouse: msg.absolute_mouse
         }
     }
 }
@@ -116,16 +953,334 @@ impl From<KeyEvent> for TelekeyPacket {
     }
 }
 
+impl From<MouseEvent> for TelekeyPacket {
+    fn from(p: MouseEvent) -> Self {
+        Self::new(TelekeyPacketKind::Mouse, p)
+    }
+}
+
+/// Reasons for a peer to refuse a connection
 #[derive(Debug, Clone, Copy)]
+pub enum Rejection {
+    InvalidToken,
+    SecurityMismatch,
+    ExpiredToken,
+    /// The protocol version of the peer, outside of the supported range
+    IncompatibleVersion(u32),
+    /// Both ends want to send the keys, or both to receive them
+    DirectionMismatch,
+    /// The secure handshake was not confirmed with the nonce of the server
+    StaleHandshake,
+    /// The peer sent nothing for `read_timeout` during the handshake
+    HandshakeTimeout
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::InvalidToken => write!(f, "invalid token"),
+            Rejection::SecurityMismatch => write!(f, "security mode mismatch"),
+            Rejection::ExpiredToken => write!(f, "expired token"),
+            Rejection::IncompatibleVersion(v) => write!(f,
+                "incompatible protocol version {}, need {} to {}", v, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION),
+            Rejection::DirectionMismatch => write!(f, "direction mismatch"),
+            Rejection::StaleHandshake => write!(f, "stale handshake"),
+            Rejection::HandshakeTimeout => write!(f, "handshake timeout")
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum TelekeyState {
     Idle,
-    Active
+    Activating(u64),
+    Active,
+    /// Active but no key was pressed for `idle_timeout`
+    Inactive,
+    /// Keys are read but not sent, until the pause key is pressed again
+    Paused
+}
+
+/// Modifiers held down and lock keys toggled by the keys sent, as far as
+/// the server can tell. The terminal only reports clicks, so modifiers are
+/// only held by explicit down and up events.
+#[derive(Debug, Default, Clone, Copy)]
+struct KeyboardState {
+    held: u32,
+    /// Modifiers of the key clicked last, only held along with it
+    clicked: u32,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool
+}
+
+impl KeyboardState {
+    fn update(&mut self, e: &KeyEvent) {
+        // Ctrl and Alt have no key of their own, they only come as the
+        // modifiers of another key
+        let bits = e.modifiers | match e.kind {
+            KeyKind::SHIFT => modifiers::SHIFT,
+            KeyKind::META => modifiers::META,
+            _ => 0
+        };
+        match e.state {
+            KeyState::DOWN => self.held |= bits,
+            KeyState::UP => self.held &= !bits,
+            KeyState::CLICK => ()
+        }
+        self.clicked = if e.state == KeyState::CLICK { e.modifiers } else { 0 };
+        // a lock toggles when its key goes down
+        if e.state != KeyState::UP {
+            match e.kind {
+                KeyKind::CAPS_LOCK => self.caps_lock = !self.caps_lock,
+                KeyKind::NUM_LOCK => self.num_lock = !self.num_lock,
+                KeyKind::SCROLL_LOCK => self.scroll_lock = !self.scroll_lock,
+                _ => ()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for KeyboardState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<&str> = modifiers::ALL.iter()
+            .filter(|(bit, _, _)| (self.held | self.clicked) & bit != 0)
+            .map(|&(_, _, name)| name)
+            .collect();
+        if self.caps_lock {
+            names.push("CAPS");
+        }
+        if self.num_lock {
+            names.push("NUM");
+        }
+        if self.scroll_lock {
+            names.push("SCRL");
+        }
+        write!(f, "{}", names.join(" "))
+    }
+}
+
+/// Lines of the full menu last drawn on the terminal, so that drawing it
+/// again only writes the lines which changed
+#[derive(Default)]
+struct Screen {
+    lines: Vec<String>
+}
+
+impl Screen {
+    /// Writes the lines which differ from the ones drawn last, cut to the
+    /// width of the terminal so each one stays on its row. The whole screen
+    /// is cleared on the first draw
+    fn draw(&mut self, term: &Term, lines: Vec<String>) -> io::Result<()> {
+        let width = term.size().1 as usize;
+        let lines: Vec<String> = lines.iter()
+            .map(|l| console::truncate_str(l, width, "").into_owned())
+            .collect();
+        if self.lines.is_empty() {
+            term.clear_screen()?;
+        }
+        for row in 0..lines.len().max(self.lines.len()) {
+            let line = lines.get(row);
+            if line == self.lines.get(row) {
+                continue;
+            }
+            term.move_cursor_to(0, row)?;
+            term.clear_line()?;
+            if let Some(line) = line {
+                term.write_str(line)?;
+            }
+        }
+        term.move_cursor_to(0, lines.len())?;
+        self.lines = lines;
+        Ok(())
+    }
+
+    /// Forgets what was drawn, after something else was written on the
+    /// terminal, so the next draw starts over
+    fn clear(&mut self) {
+        self.lines.clear();
+    }
+}
+
+/// Asked of the network thread of a session, see `Telekey::threaded_loop`
+enum NetCommand {
+    Send(TelekeyPacket),
+    /// Measures the latency, sent back once done
+    Measure,
+    /// Receives the next packet, handed back once read
+    Recv
+}
+
+/// Transport handed to the menu while the network thread owns the actual
+/// one: packets are queued to that thread, which also receives them
+struct NetHandle {
+    commands: mpsc::Sender<NetCommand>,
+    /// Packets received by the network thread, when asked for
+    packets: mpsc::Receiver<io::Result<TelekeyPacket>>,
+    peer: Option<SocketAddr>
+}
+
+impl NetHandle {
+    /// Asks the network thread to measure the latency
+    fn measure(&mut self) -> io::Result<()> {
+        self.command(NetCommand::Measure)
+    }
+
+    fn command(&mut self, command: NetCommand) -> io::Result<()> {
+        self.commands.send(command).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "The network thread stopped")
+        })
+    }
+}
+
+impl TelekeyTransport for NetHandle {
+    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
+        self.command(NetCommand::Recv)?;
+        self.packets.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "The network thread stopped"))
+        })
+    }
+
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        self.recv_packet().map(Some)
+    }
+
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        self.command(NetCommand::Send(p))
+    }
+
+    /// The actual transport is shut down once the network thread is done
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer.ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected,
+            "Unknown peer address"))
+    }
+}
+
+/// Failed handshakes of each address within the window, and the addresses
+/// locked out along with until when
+#[derive(Default)]
+struct Failures {
+    attempts: HashMap<IpAddr, Vec<std::time::Instant>>,
+    locked: HashMap<IpAddr, std::time::Instant>
+}
+
+impl Failures {
+    /// Tells whether `ip` is locked out, forgetting the lock-outs over
+    fn is_locked(&mut self, ip: IpAddr) -> bool {
+        let now = std::time::Instant::now();
+        self.locked.retain(|_, until| *until > now);
+        self.locked.contains_key(&ip)
+    }
+
+    /// Records a failed handshake of `ip`, locking it out once it failed
+    /// `max` times within `window`. Tells whether it just got locked out
+    fn fail(&mut self, ip: IpAddr, max: u32, window: std::time::Duration) -> bool {
+        let now = std::time::Instant::now();
+        for attempts in self.attempts.values_mut() {
+            attempts.retain(|at| now.duration_since(*at) < window);
+        }
+        self.attempts.retain(|_, attempts| !attempts.is_empty());
+        let attempts = self.attempts.entry(ip).or_default();
+        attempts.push(now);
+        if attempts.len() < max as usize {
+            return false;
+        }
+        self.attempts.remove(&ip);
+        self.locked.insert(ip, now + LOCKOUT);
+        true
+    }
+}
+
+/// Keys and buttons held down on the receiver, along with when they were
+/// pressed. The receiver releases them once the session ends, so that none
+/// stays stuck, and the sender presses the keys again on the next one
+#[derive(Default)]
+struct HeldKeys {
+    keys: Vec<(KeyEvent, std::time::Instant)>,
+    buttons: Vec<(MouseButton, std::time::Instant)>
+}
+
+impl HeldKeys {
+    fn key(&mut self, e: &KeyEvent) {
+        let same = |k: &KeyEvent| k.kind == e.kind && k.key == e.key;
+        match e.state {
+            KeyState::DOWN if !self.keys.iter().any(|(k, _)| same(k)) => {
+                self.keys.push((e.clone(), std::time::Instant::now()));
+            },
+            KeyState::UP => self.keys.retain(|(k, _)| !same(k)),
+            _ => ()
+        }
+    }
+
+    fn button(&mut self, e: &MouseEvent) {
+        match e.action {
+            MouseAction::PRESS if !self.buttons.iter().any(|(b, _)| *b == e.button) => {
+                self.buttons.push((e.button, std::time::Instant::now()));
+            },
+            MouseAction::RELEASE => self.buttons.retain(|(b, _)| *b != e.button),
+            _ => ()
+        }
+    }
+
+    /// Keys held down, the oldest first
+    fn snapshot(&self) -> Vec<KeyEvent> {
+        self.keys.iter().map(|(k, _)| k.clone()).collect()
+    }
+
+    /// When the oldest key or button will have been held for `max`
+    fn deadline(&self, max: std::time::Duration) -> Option<std::time::Instant> {
+        let keys = self.keys.iter().map(|(_, at)| *at);
+        keys.chain(self.buttons.iter().map(|(_, at)| *at)).min().map(|at| at + max)
+    }
+
+    /// Takes the keys and buttons held for at least `min`, or all of them
+    fn take(&mut self, min: Option<std::time::Duration>)
+        -> (Vec<KeyEvent>, Vec<MouseButton>) {
+        let old = |at: &std::time::Instant| min.is_none_or(|min| at.elapsed() >= min);
+        let (keys, kept): (Vec<_>, Vec<_>) = self.keys.drain(..).partition(|(_, at)| old(at));
+        self.keys = kept;
+        let (buttons, kept): (Vec<_>, Vec<_>) = self.buttons.drain(..)
+            .partition(|(_, at)| old(at));
+        self.buttons = kept;
+        (keys.into_iter().map(|(k, _)| k).collect(),
+         buttons.into_iter().map(|(b, _)| b).collect())
+    }
+}
+
+/// Bits of `KeyEvent::modifiers`, each holding a modifier down while the
+/// key is clicked
+pub mod modifiers {
+    pub const SHIFT: u32 = 1;
+    pub const CTRL: u32 = 1 << 1;
+    pub const ALT: u32 = 1 << 2;
+    pub const META: u32 = 1 << 3;
+
+    /// Every modifier along with its key and its name in key specs
+    pub(super) const ALL: [(u32, enigo::Key, &str); 4] = [
+        (SHIFT, enigo::Key::Shift, "SHIFT"),
+        (CTRL, enigo::Key::Control, "CTRL"),
+        (ALT, enigo::Key::Alt, "ALT"),
+        (META, enigo::Key::Meta, "META"),
+    ];
 }
 
 impl From<console::Key> for KeyEvent {
     fn from(key: console::Key) -> Self {
         use console::Key::*;
         match key {
+            BackTab => Self { kind: KeyKind::TAB, modifiers: modifiers::SHIFT, ..Default::default() },
+            // Ctrl+A..Ctrl+Z, except the ones the terminal reports as
+            // backspace, tab and enter
+            Char(x @ '\x01'..='\x1a') if !matches!(x, '\x08' | '\t' | '\n' | '\r') => Self {
+                kind: KeyKind::CHAR, key: x as u32 - 1 + 'a' as u32,
+                modifiers: modifiers::CTRL, ..Default::default()
+            },
             Enter => Self { kind: KeyKind::ENTER, ..Default::default() },
             ArrowUp => Self { kind: KeyKind::UP, ..Default::default() },
             ArrowDown => Self { kind: KeyKind::DOWN, ..Default::default() },
@@ -142,11 +1297,194 @@ impl From<console::Key> for KeyEvent {
             PageDown => Self { kind: KeyKind::PAGEDOWN, ..Default::default() },
             Shift => Self { kind: KeyKind::SHIFT, ..Default::default() },
             Char(x) => Self { kind: KeyKind::CHAR, key: x as u32, ..Default::default() },
+            // Sent by xterm-like terminals for the context menu key
+            UnknownEscSeq(seq) if seq == ['[', '2', '9', '~'] => {
+                Self { kind: KeyKind::MENU, ..Default::default() }
+            },
+            UnknownEscSeq(seq) if function_key(&seq).is_some() => Self {
+                kind: KeyKind::FUNCTION, key: function_key(&seq).unwrap(),
+                ..Default::default()
+            },
+            // Terminals send Alt+key as escape followed by the key
+            UnknownEscSeq(seq) if seq.len() == 1 => Self {
+                kind: KeyKind::CHAR, key: seq[0] as u32, modifiers: modifiers::ALT,
+                ..Default::default()
+            },
             _ => Self { kind: KeyKind::UNKNOWN, ..Default::default() },
         }
     }
 }
 
+/// Same keys as `console::Key` for the ones both report, with the
+/// modifiers held on top. Shift is left out of characters, which are
+/// already shifted
+impl From<crossterm::event::KeyEvent> for KeyEvent {
+    fn from(key: crossterm::event::KeyEvent) -> Self {
+        use crossterm::event::{KeyCode::*, KeyEventState, KeyModifiers, MediaKeyCode,
+                               ModifierKeyCode};
+        let mut mods = 0;
+        for (bit, m) in [(modifiers::SHIFT, KeyModifiers::SHIFT),
+                         (modifiers::CTRL, KeyModifiers::CONTROL),
+                         (modifiers::ALT, KeyModifiers::ALT),
+                         (modifiers::META, KeyModifiers::SUPER | KeyModifiers::META)] {
+            if key.modifiers.intersects(m) {
+                mods |= bit;
+            }
+        }
+        let keypad = key.state.contains(KeyEventState::KEYPAD);
+        let (kind, key) = match key.code {
+            // only reported by terminals telling the keypad apart
+            Char(x) if keypad && is_numpad(x) => (KeyKind::NUMPAD, x as u32),
+            Char(x) => {
+                mods &= !modifiers::SHIFT;
+                (KeyKind::CHAR, x as u32)
+            },
+            BackTab => {
+                mods |= modifiers::SHIFT;
+                (KeyKind::TAB, 0)
+            },
+            Enter => (KeyKind::ENTER, 0),
+            Up => (KeyKind::UP, 0),
+            Down => (KeyKind::DOWN, 0),
+            Left => (KeyKind::LEFT, 0),
+            Right => (KeyKind::RIGHT, 0),
+            Esc => (KeyKind::ESC, 0),
+            Backspace => (KeyKind::BACKSPACE, 0),
+            Home => (KeyKind::HOME, 0),
+            End => (KeyKind::END, 0),
+            Tab => (KeyKind::TAB, 0),
+            Delete => (KeyKind::DELETE, 0),
+            Insert => (KeyKind::INSERT, 0),
+            PageUp => (KeyKind::PAGEUP, 0),
+            PageDown => (KeyKind::PAGEDOWN, 0),
+            F(n) => (KeyKind::FUNCTION, n as u32),
+            Menu => (KeyKind::MENU, 0),
+            Pause => (KeyKind::PAUSE, 0),
+            CapsLock => (KeyKind::CAPS_LOCK, 0),
+            NumLock => (KeyKind::NUM_LOCK, 0),
+            ScrollLock => (KeyKind::SCROLL_LOCK, 0),
+            PrintScreen => (KeyKind::PRINT_SCREEN, 0),
+            Media(MediaKeyCode::PlayPause) => (KeyKind::PLAY_PAUSE, 0),
+            Media(MediaKeyCode::TrackNext) => (KeyKind::NEXT, 0),
+            Media(MediaKeyCode::TrackPrevious) => (KeyKind::PREV, 0),
+            Media(MediaKeyCode::RaiseVolume) => (KeyKind::VOLUME_UP, 0),
+            Media(MediaKeyCode::LowerVolume) => (KeyKind::VOLUME_DOWN, 0),
+            Media(MediaKeyCode::MuteVolume) => (KeyKind::MUTE, 0),
+            Modifier(ModifierKeyCode::LeftShift | ModifierKeyCode::RightShift) => {
+                (KeyKind::SHIFT, 0)
+            },
+            Modifier(ModifierKeyCode::LeftSuper | ModifierKeyCode::RightSuper
+                     | ModifierKeyCode::LeftMeta | ModifierKeyCode::RightMeta) => {
+                (KeyKind::META, 0)
+            },
+            _ => (KeyKind::UNKNOWN, 0)
+        };
+        Self { kind, key, modifiers: mods, ..Default::default() }
+    }
+}
+
+/// Whether `c` is one of the keys of the numeric keypad sent as `NUMPAD`
+fn is_numpad(c: char) -> bool {
+    c.is_ascii_digit() || matches!(c, '+' | '-' | '*' | '/' | '.')
+}
+
+/// Number of the function key sent as the escape sequence `seq` by
+/// xterm-like terminals, the escape itself excluded
+fn function_key(seq: &[char]) -> Option<u32> {
+    match seq {
+        ['O', c @ 'P'..='S'] => Some(*c as u32 - 'P' as u32 + 1),
+        ['[', '1', c @ '1'..='4', '~'] => Some(*c as u32 - '0' as u32),
+        ['[', '1', '5', '~'] => Some(5),
+        ['[', '1', c @ '7'..='9', '~'] => Some(*c as u32 - '7' as u32 + 6),
+        ['[', '2', c @ '0'..='1', '~'] => Some(*c as u32 - '0' as u32 + 9),
+        ['[', '2', c @ '3'..='4', '~'] => Some(*c as u32 - '3' as u32 + 11),
+        _ => None
+    }
+}
+
+/// Platform key codes of the keys enigo has no variant for
+#[cfg(target_os = "windows")]
+mod raw_keys {
+    pub const MENU: Option<u16> = Some(0x5D); // VK_APPS
+    pub const PAUSE: Option<u16> = Some(0x13); // VK_PAUSE
+    pub const VOLUME_UP: Option<u16> = Some(0xAF); // VK_VOLUME_UP
+    pub const VOLUME_DOWN: Option<u16> = Some(0xAE); // VK_VOLUME_DOWN
+    pub const MUTE: Option<u16> = Some(0xAD); // VK_VOLUME_MUTE
+    pub const PLAY_PAUSE: Option<u16> = Some(0xB3); // VK_MEDIA_PLAY_PAUSE
+    pub const NEXT: Option<u16> = Some(0xB0); // VK_MEDIA_NEXT_TRACK
+    pub const PREV: Option<u16> = Some(0xB1); // VK_MEDIA_PREV_TRACK
+    pub const NUM_LOCK: Option<u16> = Some(0x90); // VK_NUMLOCK
+    pub const SCROLL_LOCK: Option<u16> = Some(0x91); // VK_SCROLL
+    pub const PRINT_SCREEN: Option<u16> = Some(0x2C); // VK_SNAPSHOT
+
+    pub fn numpad(c: char) -> Option<u16> {
+        match c {
+            '0'..='9' => Some(0x60 + (c as u16 - '0' as u16)), // VK_NUMPAD0..9
+            '*' => Some(0x6A), // VK_MULTIPLY
+            '+' => Some(0x6B), // VK_ADD
+            '-' => Some(0x6D), // VK_SUBTRACT
+            '.' => Some(0x6E), // VK_DECIMAL
+            '/' => Some(0x6F), // VK_DIVIDE
+            _ => None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod raw_keys {
+    pub const MENU: Option<u16> = Some(135);
+    pub const PAUSE: Option<u16> = Some(127);
+    pub const VOLUME_UP: Option<u16> = Some(123);
+    pub const VOLUME_DOWN: Option<u16> = Some(122);
+    pub const MUTE: Option<u16> = Some(121);
+    pub const PLAY_PAUSE: Option<u16> = Some(172);
+    pub const NEXT: Option<u16> = Some(171);
+    pub const PREV: Option<u16> = Some(173);
+    pub const NUM_LOCK: Option<u16> = Some(77);
+    pub const SCROLL_LOCK: Option<u16> = Some(78);
+    pub const PRINT_SCREEN: Option<u16> = Some(107);
+
+    pub fn numpad(c: char) -> Option<u16> {
+        match c {
+            '7' => Some(79),
+            '8' => Some(80),
+            '9' => Some(81),
+            '-' => Some(82),
+            '4' => Some(83),
+            '5' => Some(84),
+            '6' => Some(85),
+            '+' => Some(86),
+            '1' => Some(87),
+            '2' => Some(88),
+            '3' => Some(89),
+            '0' => Some(90),
+            '.' => Some(91),
+            '*' => Some(63),
+            '/' => Some(106),
+            _ => None
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+mod raw_keys {
+    pub const MENU: Option<u16> = None;
+    pub const PAUSE: Option<u16> = None;
+    pub const VOLUME_UP: Option<u16> = None;
+    pub const VOLUME_DOWN: Option<u16> = None;
+    pub const MUTE: Option<u16> = None;
+    pub const PLAY_PAUSE: Option<u16> = None;
+    pub const NEXT: Option<u16> = None;
+    pub const PREV: Option<u16> = None;
+    pub const NUM_LOCK: Option<u16> = None;
+    pub const SCROLL_LOCK: Option<u16> = None;
+    pub const PRINT_SCREEN: Option<u16> = None;
+
+    pub fn numpad(_: char) -> Option<u16> {
+        None
+    }
+}
+
 impl From<&KeyEvent> for Result<enigo::Key, String> {
     fn from(e: &KeyEvent) -> Self {
         use KeyKind::*;
@@ -162,18 +1500,91 @@ impl From<&KeyEvent> for Result<enigo::Key, String> {
             END => Ok(enigo::Key::End),
             TAB => Ok(enigo::Key::Tab),
             DELETE => Ok(enigo::Key::Delete),
-            CHAR => Ok(enigo::Key::Layout(char::from_u32(e.key).unwrap())),
+            CHAR => char::from_u32(e.key).map(enigo::Key::Layout)
+                .ok_or_else(|| format!("Invalid character U+{:04X}", e.key)),
             PAGEUP => Ok(enigo::Key::PageUp),
             PAGEDOWN => Ok(enigo::Key::PageDown),
             SHIFT => Ok(enigo::Key::Shift),
             META => Ok(enigo::Key::Meta),
-            _ => Err(format!("From<KeyEvent> => enigo::Key for {:?}", e))
+            MENU => raw_keys::MENU.map(enigo::Key::Raw)
+                .ok_or_else(|| "No context menu key on this platform".to_string()),
+            PAUSE => raw_keys::PAUSE.map(enigo::Key::Raw)
+                .ok_or_else(|| "No pause key on this platform".to_string()),
+            VOLUME_UP => raw_keys::VOLUME_UP.map(enigo::Key::Raw)
+                .ok_or_else(|| "No volume up key on this platform".to_string()),
+            VOLUME_DOWN => raw_keys::VOLUME_DOWN.map(enigo::Key::Raw)
+                .ok_or_else(|| "No volume down key on this platform".to_string()),
+            MUTE => raw_keys::MUTE.map(enigo::Key::Raw)
+                .ok_or_else(|| "No mute key on this platform".to_string()),
+            PLAY_PAUSE => raw_keys::PLAY_PAUSE.map(enigo::Key::Raw)
+                .ok_or_else(|| "No play/pause key on this platform".to_string()),
+            NEXT => raw_keys::NEXT.map(enigo::Key::Raw)
+                .ok_or_else(|| "No next track key on this platform".to_string()),
+            PREV => raw_keys::PREV.map(enigo::Key::Raw)
+                .ok_or_else(|| "No previous track key on this platform".to_string()),
+            CAPS_LOCK => Ok(enigo::Key::CapsLock),
+            NUM_LOCK => raw_keys::NUM_LOCK.map(enigo::Key::Raw)
+                .ok_or_else(|| "No num lock key on this platform".to_string()),
+            SCROLL_LOCK => raw_keys::SCROLL_LOCK.map(enigo::Key::Raw)
+                .ok_or_else(|| "No scroll lock key on this platform".to_string()),
+            PRINT_SCREEN => raw_keys::PRINT_SCREEN.map(enigo::Key::Raw)
+                .ok_or_else(|| "No print screen key on this platform".to_string()),
+            // the key code is the one of the receiving platform, whatever
+            // its keyboard layout
+            RAW => u16::try_from(e.key).map(enigo::Key::Raw)
+                .map_err(|_| format!("Invalid raw key code {}", e.key)),
+            NUMPAD => match char::from_u32(e.key).filter(|&c| is_numpad(c)) {
+                Some(c) => raw_keys::numpad(c).map(enigo::Key::Raw)
+                    .ok_or_else(|| "No numeric keypad on this platform".to_string()),
+                None => Err(format!("No keypad key U+{:04X}", e.key))
+            },
+            FUNCTION => match e.key {
+                1 => Ok(enigo::Key::F1),
+                2 => Ok(enigo::Key::F2),
+                3 => Ok(enigo::Key::F3),
+                4 => Ok(enigo::Key::F4),
+                5 => Ok(enigo::Key::F5),
+                6 => Ok(enigo::Key::F6),
+                7 => Ok(enigo::Key::F7),
+                8 => Ok(enigo::Key::F8),
+                9 => Ok(enigo::Key::F9),
+                10 => Ok(enigo::Key::F10),
+                11 => Ok(enigo::Key::F11),
+                12 => Ok(enigo::Key::F12),
+                n => Err(format!("No function key F{}, expected F1 to F12", n))
+            },
+            SCROLL => Err("Scrolling is not a key".to_string()),
+            // No wildcard here: a new `KeyKind` must be explicitly handled in
+            // this mapping, like it already has to be in `Display`
+            INSERT | UNKNOWN => {
+                Err(format!("From<KeyEvent> => enigo::Key for {:?}", e))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for MouseEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.action {
+            MouseAction::MOVE if self.absolute => write!(f, "[MOUSE @{},{}]", self.x, self.y),
+            MouseAction::MOVE => write!(f, "[MOUSE {:+},{:+}]", self.x, self.y),
+            action => write!(f, "[MOUSE {:?} {:?}]", self.button, action)
         }
     }
 }
 
 impl std::fmt::Display for KeyEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.state {
+            KeyState::DOWN => write!(f, "[DOWN]")?,
+            KeyState::UP => write!(f, "[UP]")?,
+            KeyState::CLICK => ()
+        }
+        for (bit, _, name) in modifiers::ALL {
+            if self.modifiers & bit != 0 {
+                write!(f, "[{}]+", name)?;
+            }
+        }
         match self.kind {
             KeyKind::ENTER => write!(f, "\\n"),
             KeyKind::UP => write!(f, "[A^]"),
@@ -182,7 +1593,8 @@ impl std::fmt::Display for KeyEvent {
             KeyKind::RIGHT => write!(f, "[A>]"),
             KeyKind::BACKSPACE => write!(f, "[BACKSPACE]"),
             KeyKind::INSERT => write!(f, "[INSERT]"),
-            KeyKind::CHAR => write!(f, "{}", char::from_u32(self.key).unwrap()),
+            KeyKind::CHAR => write!(f, "{}", char::from_u32(self.key)
+                .unwrap_or(char::REPLACEMENT_CHARACTER)),
             KeyKind::TAB => write!(f, "\\t"),
             KeyKind::HOME => write!(f, "[HOM]"),
             KeyKind::ESC => write!(f, "[ESC]"),
@@ -193,224 +1605,1733 @@ impl std::fmt::Display for KeyEvent {
             KeyKind::FUNCTION => write!(f, "[F{}]", self.key),
             KeyKind::SHIFT => write!(f, "[SHIFT]"),
             KeyKind::META => write!(f, "[WIN|CMD]"),
+            KeyKind::MENU => write!(f, "[MENU]"),
+            KeyKind::PAUSE => write!(f, "[PAUSE]"),
+            KeyKind::SCROLL => write!(f, "[SCROLL {:+}]", self.key as i32),
+            KeyKind::VOLUME_UP => write!(f, "[VOL+]"),
+            KeyKind::VOLUME_DOWN => write!(f, "[VOL-]"),
+            KeyKind::MUTE => write!(f, "[MUTE]"),
+            KeyKind::PLAY_PAUSE => write!(f, "[PLAY]"),
+            KeyKind::NEXT => write!(f, "[NEXT]"),
+            KeyKind::PREV => write!(f, "[PREV]"),
+            KeyKind::CAPS_LOCK => write!(f, "[CAPS]"),
+            KeyKind::NUM_LOCK => write!(f, "[NUM]"),
+            KeyKind::SCROLL_LOCK => write!(f, "[SCRL]"),
+            KeyKind::PRINT_SCREEN => write!(f, "[PRTSC]"),
+            KeyKind::NUMPAD => match char::from_u32(self.key) {
+                Some(c) => write!(f, "[KP{}]", c),
+                None => write!(f, "[KP?]")
+            },
+            KeyKind::RAW => write!(f, "[RAW {}]", self.key),
             KeyKind::UNKNOWN => write!(f, "[?]")
         }
     }
 }
 
-pub struct Telekey {
-    config: TelekeyConfig,
-    version: u32,
-    mode: TelekeyMode,
+/// Written as `{"kind":"CHAR","key":97,"modifiers":0}`, along with the
+/// state when not a click
+#[cfg(feature = "serde")]
+impl Serialize for KeyEvent {
+    fn serialize<S: serde::Serializer>(&self, serializer: S)
+        -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let click = self.state == KeyState::CLICK;
+        let mut s = serializer.serialize_struct("KeyEvent", if click { 3 } else { 4 })?;
+        s.serialize_field("kind", &self.kind)?;
+        s.serialize_field("key", &self.key)?;
+        s.serialize_field("modifiers", &self.modifiers)?;
+        if !click {
+            s.serialize_field("state", &format!("{:?}", self.state))?;
+        }
+        s.end()
+    }
+}
 
-    remote: Option<TelekeyRemote>,
-    state: TelekeyState,
-    enigo: Enigo
+/// Read back from the form written by `Serialize`. Only `kind` is
+/// required, the state is a click unless told otherwise
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for KeyEvent {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D)
+        -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            kind: KeyKind,
+            #[serde(default)]
+            key: u32,
+            #[serde(default)]
+            modifiers: u32,
+            state: Option<String>
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        let state = match f.state.as_deref() {
+            None | Some("CLICK") => KeyState::CLICK,
+            Some("DOWN") => KeyState::DOWN,
+            Some("UP") => KeyState::UP,
+            Some(s) => return Err(serde::de::Error::custom(
+                format!("Unknown key state `{}`", s)))
+        };
+        Ok(Self { kind: f.kind, key: f.key, modifiers: f.modifiers, state, ..Default::default() })
+    }
 }
 
-impl Telekey {
-    pub fn is_server(&self) -> bool {
-        matches!(self.mode, TelekeyMode::Server)
+/// Written as its name, e.g. `CHAR`
+#[cfg(feature = "serde")]
+impl Serialize for KeyKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S)
+        -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:?}", self))
     }
+}
 
-    pub fn serve(addr: SocketAddr, config: TelekeyConfig) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        println!("Server listenning on {} as `{}`", addr, config.hostname);
-
-        let mut telekey = Telekey {
-            config, mode: TelekeyMode::Server,
-            version: 1, remote: None,
-            state: TelekeyState::Idle, enigo: Enigo::new()
-        };
-        // accept connections and process them serially
-        for stream in listener.incoming().flatten() {
-            let skey = SecretKey::generate(32)
-                .context("Failed to generate session secret")?;
-            println!("Enter this token to confirm: {}",
-                 base64::encode(skey.unprotected_as_bytes()));
-
-            let stream: TcpTransport = stream.into();
-            let r = if telekey.config.secure {
-                let mut stream = telekey.sec_handshake(stream, skey)?;
-                telekey.wait_for_input(&mut stream)
-            } else {
-                let mut stream = telekey.handshake(stream, skey)?;
-                telekey.wait_for_input(&mut stream)
-            };
-            if let Err(e) = r {
-                eprintln!("{}: Session closed", style("ERROR").red().bold());
-                eprintln!("{:?}", e);
-            }
-            telekey.remote = None;
-            telekey.state = TelekeyState::Idle;
+/// Read from its name, which must be one of the `KeyKind` names
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for KeyKind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D)
+        -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        let kind = KeyKind::from(name.as_str());
+        if kind == KeyKind::UNKNOWN && name != "UNKNOWN" {
+            return Err(serde::de::Error::custom(format!("Unknown key kind `{}`", name)));
         }
-        Ok(())
+        Ok(kind)
     }
+}
 
-    pub fn connect_to(addr: SocketAddr, config: TelekeyConfig) -> Result<()> {
-        println!("Connecting to remote...");
-        match TcpStream::connect(addr) {
-            Ok(stream) => {
-                let mut telekey = Telekey {
-                    config, mode: TelekeyMode::Client, version: 1,
-                    remote: None, state: TelekeyState::Idle, enigo: Enigo::new()
-                };
-                println!("{} connected to the server!",
-                    style("Successfully").green().bold());
-                let stream: TcpTransport = stream.into();
-
-                let mut inp = String::new();
-                print!("Please enter token to continue: ");
-                io::stdout().flush()?;
-                io::stdin().read_line(&mut inp)?;
-
-                let inp = inp.trim();
-                if inp.len() >= 46 {
-                    bail!("Invalid token");
-                }
-                let bytes = base64::decode(inp).context("Failed to parse token")?;
-                let bytes: [u8; 32] = bytes.try_into()
-                    .map_err(|_| anyhow!("Received an incorrectly sized key"))?;
-                let skey = SecretKey::from_slice(&bytes)
-                    .context("Could not create secret key")?;
-
-                if telekey.config.secure {
-                    let stream = telekey.sec_handshake(stream, skey)
-                        .context("Secure handshake failed")?;
-
-                    println!("{}{}", telekey.print_header(stream.peer_addr().ok()),
-                        style(" ACTIVE ").on_green().black());
-
-                    if let Err(e) = telekey.listen_loop(stream) {
-                        println!("{}: {}", style("ERROR").red().bold(), e);
-                    }
-                } else {
-                    let stream = telekey.handshake(stream, skey)
-                        .context("Handshake failed")?;
-
-                    println!("{}{}", telekey.print_header(stream.peer_addr().ok()),
-                        style(" ACTIVE ").on_green().black());
+impl FromStr for KeyEvent {
+    type Err = anyhow::Error;
 
-                    if let Err(e) = telekey.listen_loop(stream) {
-                        println!("{}: {}", style("ERROR").red().bold(), e);
+    /// Parses a key spec of the form `[MODIFIER+...]KIND [VALUE]`, where
+    /// `KIND` is one of the `KeyKind` names and `MODIFIER` one of SHIFT, CTRL,
+    /// ALT or META. `CHAR` expects a single character (or `U+XXXX`),
+    /// `FUNCTION` the function key number and `SCROLL` the signed delta.
+    fn from_str(s: &str) -> Result<Self> {
+        let (mut name, arg) = match s.split_once(' ') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None)
+        };
+        let mut mods = 0;
+        while let Some((m, rest)) = name.split_once('+') {
+            mods |= modifiers::ALL.iter().find(|(_, _, n)| *n == m)
+                .ok_or_else(|| anyhow!("Unknown modifier `{}`", m))?.0;
+            name = rest;
+        }
+        let kind = KeyKind::from(name);
+        if kind == KeyKind::UNKNOWN && name != "UNKNOWN" {
+            bail!("Unknown key kind `{}`", name);
+        }
+        let key = match (kind, arg) {
+            (KeyKind::CHAR, Some(c)) => {
+                if let Some(hex) = c.strip_prefix("U+") {
+                    let code = u32::from_str_radix(hex, 16)
+                        .context("Invalid code point")?;
+                    char::from_u32(code)
+                        .ok_or_else(|| anyhow!("Invalid code point U+{}", hex))? as u32
+                } else {
+                    let mut chars = c.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => c as u32,
+                        _ => bail!("Expected a single character, got `{}`", c)
                     }
                 }
-
-                Ok(())
             },
-            Err(e) => {
-                bail!("{}: Couldn't connect to server: {}",
-                         style("ERROR").red().bold(), e)
+            (KeyKind::FUNCTION, Some(n)) => n.parse()
+                .context("Invalid function key number")?,
+            (KeyKind::SCROLL, Some(n)) => n.parse::<i32>()
+                .context("Invalid scroll delta")? as u32,
+            (KeyKind::RAW, Some(n)) => n.parse::<u16>()
+                .context("Invalid raw key code")? as u32,
+            (KeyKind::NUMPAD, Some(c)) => match c.chars().next() {
+                Some(k) if c.len() == 1 && is_numpad(k) => k as u32,
+                _ => bail!("Expected a keypad digit or operator, got `{}`", c)
+            },
+            (KeyKind::CHAR | KeyKind::FUNCTION | KeyKind::SCROLL | KeyKind::NUMPAD
+             | KeyKind::RAW, None) => {
+                bail!("Missing value for `{}`", name)
+            },
+            (_, Some(_)) => bail!("Unexpected value for `{}`", name),
+            (_, None) => 0
+        };
+        Ok(Self { kind, key, modifiers: mods, ..Default::default() })
+    }
+}
+
+impl KeyEvent {
+    /// Key spec parsed back by `from_str`, e.g. `CTRL+CHAR c`. The state is
+    /// left out, as key specs only describe clicks
+    pub fn to_spec(&self) -> String {
+        let mut spec = String::new();
+        for (bit, _, name) in modifiers::ALL {
+            if self.modifiers & bit != 0 {
+                spec.push_str(name);
+                spec.push('+');
             }
         }
+        spec.push_str(&format!("{:?}", self.kind));
+        match self.kind {
+            KeyKind::CHAR => match char::from_u32(self.key) {
+                Some(c) if !c.is_whitespace() && !c.is_control() => {
+                    spec.push(' ');
+                    spec.push(c);
+                },
+                _ => spec.push_str(&format!(" U+{:04X}", self.key))
+            },
+            KeyKind::FUNCTION | KeyKind::RAW => spec.push_str(&format!(" {}", self.key)),
+            KeyKind::NUMPAD => match char::from_u32(self.key) {
+                Some(c) => {
+                    spec.push(' ');
+                    spec.push(c);
+                },
+                None => spec.push_str(&format!(" U+{:04X}", self.key))
+            },
+            KeyKind::SCROLL => spec.push_str(&format!(" {}", self.key as i32)),
+            _ => ()
+        }
+        spec
     }
+}
 
-    fn sec_handshake(&mut self, mut tr: TcpTransport, skey: SecretKey) -> Result<SecureTransport> {
-        if matches!(self.mode, TelekeyMode::Server) {
-            let session = EphemeralServerSession::new()
-                .context("Failed to generate ephemeral key pair securely")?;
+/// Version of the protocol spoken by this build, sent during the handshake.
+/// Version 2 authenticates each encrypted packet with its sequence number.
+/// Version 3 has the client echo a nonce of the server over the secure
+/// session before it starts.
+const PROTOCOL_VERSION: u32 = 3;
 
-            let p = tr.recv_packet().context("Failed to receive handshake")?;
-            let msg: HandshakeRequest = deserialize_from_slice(p.data())
-                .context("Failed to decode HandshakeRequest message")?;
-            let key = orion::aead::open(&skey, &msg.pkey)
-                .context("Could not open client public key with session secret")?;
-            let key: [u8; 32] = key.try_into()
-                .map_err(|_| anyhow!("Received an incorrectly sized key"))?;
+/// Oldest protocol version of a peer that this build can still talk to
+const MIN_SUPPORTED_VERSION: u32 = 3;
 
-            let pkey = orion::aead::seal(&skey, &session.public_key().to_bytes())
-                .context("Failed to seal public key using session secret")?;
-            tr.send_packet(HandshakeResponse {
-                hostname: Cow::Borrowed(&self.config.hostname),
-                version: self.version,
-                pkey: Cow::Owned(pkey)
-            }.into())?;
-            self.remote = Some(msg.into());
+/// Size of the nonce a secure handshake is confirmed with
+const NONCE_SIZE: usize = 16;
 
-            let server_keys: SessionKeys = session
-                .establish_with_client(&key.into())
-                .context("Key exchange failed")?;
-            Ok(SecureTransport::new(tr.into(), server_keys))
-        } else {
-            let session = EphemeralClientSession::new()
-                .context("Failed to generate ephemeral key pair securely")?;
-            let pkey = orion::aead::seal(&skey, &session.public_key().to_bytes())
-                .context("Failed to seal public key using session secret")?;
-            tr.send_packet(HandshakeRequest {
-                hostname: Cow::Borrowed(&self.config.hostname),
-                version: self.version,
-                token: Cow::Borrowed(&[]),
-                pkey: Cow::Owned(pkey)
-            }.into())?;
+/// Sink used when the config sets none. A cold run never emulates, so it
+/// must not even start the emulation
+#[derive(Debug, PartialEq, Eq)]
+enum LocalSink {
+    Print(ColdFormat),
+    Emulate
+}
 
-            let p = tr.recv_packet()?;
-            let msg: HandshakeResponse = deserialize_from_slice(p.data())
-                .context("Failed to decode HandshakeResponse message")?;
-            self.remote = Some(TelekeyRemote {
-                hostname: msg.hostname.to_string(),
-                version: msg.version,
-                mode: TelekeyMode::Server,
-            });
+impl LocalSink {
+    fn of(config: &TelekeyConfig) -> Self {
+        if config.cold_run {
+            Self::Print(config.cold_format)
+        } else {
+            Self::Emulate
+        }
+    }
 
-            let key = orion::aead::open(&skey, &msg.pkey)
-                .context("Could not open server public key with session secret")?;
-            let key: [u8; 32] = key.try_into()
-                .map_err(|_| anyhow!("Received an incorrectly sized key"))?;
-            let client_keys: SessionKeys = session
-                .establish_with_server(&key.into())
-                .context("Key exchange failed")?;
-            Ok(SecureTransport::new(tr.into(), client_keys))
+    fn open(self) -> Box<dyn KeySink> {
+        match self {
+            Self::Print(ColdFormat::Text) => Box::new(ColdRunSink::new()),
+            #[cfg(feature = "serde")]
+            Self::Print(ColdFormat::Json) => Box::new(JsonSink),
+            Self::Emulate => Box::new(EnigoSink::new())
         }
     }
+}
 
-    fn handshake(&mut self, mut tr: TcpTransport, secret: SecretKey) -> Result<TcpTransport> {
-        if matches!(self.mode, TelekeyMode::Server) {
-            let p = tr.recv_packet()?;
-            let msg: HandshakeRequest = deserialize_from_slice(p.data())
-                .context("Failed to decode HandshakeRequest message")?;
-            let token: &[u8] = &msg.token;
-            if secret != token {
-                tr.shutdown().context("Failed to close socket (Invalid secret)")?;
-                bail!("Invalid secret");
-            }
-            tr.send_packet(HandshakeResponse {
-                hostname: Cow::Borrowed(&self.config.hostname),
-                version: self.version,
-                pkey: Cow::Borrowed(&[])
-            }.into())?;
-            self.remote = Some(msg.into());
+/// Most clicks a coalesced key stands for, in either direction
+const MAX_REPEAT: u32 = 100;
 
-            Ok(tr)
-        } else {
-            let p = HandshakeRequest {
-                hostname: Cow::Borrowed(&self.config.hostname),
-                version: self.version,
-                token: Cow::Borrowed(secret.unprotected_as_bytes()),
-                pkey: Cow::Borrowed(&[])
-            };
-            tr.send_packet(p.into())?;
+/// Clicks of the key sent last, repeated since, see
+/// `TelekeyConfig::set_coalesce`
+struct Repeats {
+    event: KeyEvent,
+    /// Repeats not sent yet
+    count: u32,
+    /// When the first of them was typed
+    since: std::time::Instant,
+    /// When the key was last typed
+    last: std::time::Instant
+}
 
-            let p = tr.recv_packet()?;
-            let msg: HandshakeResponse = deserialize_from_slice(p.data())
-                .context("Failed to decode HandshakeResponse message")?;
-            self.remote = Some(TelekeyRemote {
-                hostname: msg.hostname.to_string(),
-                version: msg.version,
-                mode: TelekeyMode::Server,
-            });
-            Ok(tr)
-        }
-    }
+/// Sink of the handshake threads of a multi-client server, which never
+/// receive any key
+struct NoSink;
 
-    fn listen_loop<T: TelekeyTransport>(&mut self, mut tr: T) -> Result<()> {
-        loop {
-            let p = tr.recv_packet()?;
-            self.handle_packet(&mut tr, p)?;
-        }
+impl KeySink for NoSink {
+    fn deliver(&mut self, event: &KeyEvent) -> Result<()> {
+        bail!("No sink to deliver `{}` to", event)
     }
+}
 
-    fn handle_packet<T: TelekeyTransport>(&mut self, tr: &mut T, p: TelekeyPacket)
-        -> Result<()> {
+/// Where the keys sent are read
+enum Keys {
+    Term(TermSource),
+    /// Keys of the source set in the config, read on their own thread
+    Source(mpsc::Receiver<KeyEvent>)
+}
+
+/// Hotkeys of the config, parsed once. Specs were validated along with it
+#[derive(Default)]
+struct Hotkeys {
+    paste: Option<KeyEvent>,
+    pause: Option<KeyEvent>,
+    clipboard: Option<KeyEvent>,
+    fetch_clipboard: Option<KeyEvent>
+}
+
+impl Hotkeys {
+    fn of(config: &TelekeyConfig) -> Self {
+        let parse = |spec: &Option<String>| spec.as_deref().and_then(|s| s.parse().ok());
+        Self {
+            paste: parse(&config.paste_key),
+            pause: parse(&config.pause_key),
+            clipboard: parse(&config.clipboard_key),
+            fetch_clipboard: parse(&config.fetch_clipboard_key)
+        }
+    }
+}
+
+/// Largest text packet sent, in bytes, well below the default packet size
+/// limit of the peer
+const MAX_TEXT_CHUNK: usize = 4096;
+
+/// Splits `text` into pieces of at most `max` bytes, never inside a
+/// character. `max` must be at least 4, the length of the longest one
+fn text_chunks(text: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = (start + max).min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&text[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+/// Size of a decoded token, the secret sealing the handshake
+const TOKEN_SIZE: usize = 32;
+
+/// Environment variable holding the token of the server to connect to
+const TOKEN_ENV: &str = "TELEKEY_TOKEN";
+
+/// How long an address stays locked out after too many failed handshakes
+const LOCKOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// How often the full menu checks for a new latency while waiting for keys
+const REDRAW_POLL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Set once a shutdown is requested, see [`Telekey::shutdown`]
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Size of a session ID before it is written in hexadecimal
+const SESSION_ID_SIZE: usize = 4;
+
+thread_local! {
+    /// ID of the session handled on this thread, see [`Telekey::session_id`]
+    static SESSION_ID: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// How often the server checks for new connections, and for a shutdown
+/// request, while waiting for keys
+const ACCEPT_POLL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Tells whether `e` comes from the peer closing the connection
+fn is_disconnect(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| matches!(e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe))
+}
+
+/// Tells whether `e` comes from the read timeout elapsing
+fn is_timed_out(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+}
+
+/// Tells whether `e` comes from the peer closing the connection between two
+/// packets, which is how sessions normally end
+fn is_closed(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// The keyboard input ended, which is how the user quits a server
+#[derive(Debug)]
+struct InputClosed;
+
+impl std::fmt::Display for InputClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "keyboard input closed")
+    }
+}
+
+impl std::error::Error for InputClosed {}
+
+/// Longest wait between two reconnection attempts
+const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long a probe waits for the connection and for the answer
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// How long a keepalive ping may wait for its answer
+const KEEPALIVE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How often the answer to a keepalive ping is checked for
+const KEEPALIVE_POLL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Size of the token carried by a ping, which the peer echoes
+const PING_TOKEN_SIZE: usize = 8;
+
+/// Token of the next ping sent, so an answer can be matched to its ping
+static PING_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+fn ping_packet(token: u64) -> TelekeyPacket {
+    TelekeyPacket::raw(TelekeyPacketKind::Ping, token.to_be_bytes().to_vec())
+}
+
+/// Parses the public key opened from a handshake message, wiping the opened
+/// bytes once copied into the key
+fn peer_public_key(key: Vec<u8>) -> Result<PublicKey> {
+    let key = Zeroizing::new(key);
+    PublicKey::from_slice(&key).map_err(|_| anyhow!("Received an incorrectly sized key"))
+}
+
+/// Number of ping exchanges per latency measurement
+const LATENCY_SAMPLES: usize = 4;
+
+/// Round-trip time and estimated one-way delay, in nanoseconds
+#[derive(Clone, Copy)]
+struct Latency {
+    rtt: i64,
+    one_way: i64
+}
+
+impl Latency {
+    /// A round-trip time below the resolution of the clock reads as zero,
+    /// which is meaningless
+    fn is_valid(&self) -> bool {
+        self.rtt > 0
+    }
+
+    fn render(&self, stats: Option<LatencyStats>, sparkline: &str, theme: &Theme) -> String {
+        let fmt = |ns: i64| format!("{:?}", std::time::Duration::from_nanos(ns as u64));
+        match stats {
+            Some(stats) if self.is_valid() => theme.latency.render(&[
+                ("rtt", &fmt(self.rtt)), ("one_way", &fmt(self.one_way)),
+                ("sparkline", &sparkline),
+                ("min", &fmt(stats.min)), ("max", &fmt(stats.max)),
+                ("avg", &fmt(stats.avg)), ("jitter", &fmt(stats.jitter))
+            ]),
+            _ => theme.unknown_latency.render(&[])
+        }
+    }
+}
+
+/// Number of latency measurements the statistics are computed over
+const LATENCY_WINDOW: usize = 10;
+
+/// Round-trip time statistics over the last valid measurements, in
+/// nanoseconds. The jitter is the mean absolute deviation.
+#[derive(Clone, Copy)]
+struct LatencyStats {
+    min: i64,
+    max: i64,
+    avg: i64,
+    jitter: i64
+}
+
+impl LatencyStats {
+    fn of(window: &VecDeque<Latency>) -> Option<Self> {
+        let rtts = window.iter().map(|l| l.rtt);
+        let n = window.len() as i64;
+        let avg = rtts.clone().sum::<i64>().checked_div(n)?;
+        Some(Self {
+            min: rtts.clone().min()?,
+            max: rtts.clone().max()?,
+            avg,
+            jitter: rtts.map(|rtt| (rtt - avg).abs()).sum::<i64>() / n
+        })
+    }
+}
+
+/// Number of round-trip times drawn by the sparkline
+const SPARKLINE_SAMPLES: usize = 40;
+
+/// Draws the round-trip times, oldest first, one block each scaled between
+/// the lowest and highest of them
+fn sparkline(rtts: &VecDeque<i64>) -> String {
+    const RAMP: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let (min, max) = match (rtts.iter().min(), rtts.iter().max()) {
+        (Some(&min), Some(&max)) => (min, max),
+        _ => return String::new()
+    };
+    let top = RAMP.len() as i64 - 1;
+    rtts.iter()
+        .map(|&rtt| {
+            // all the same when flat, drawn at the bottom
+            let level = (rtt - min).saturating_mul(top).checked_div(max - min).unwrap_or(0);
+            RAMP[level as usize]
+        })
+        .collect()
+}
+
+/// Seconds over which the rate of the keys sent is averaged
+const RATE_WINDOW: usize = 5;
+
+/// Keys sent during each of the last `RATE_WINDOW` seconds, counted in a
+/// fixed ring of slots so that counting a key never allocates
+#[derive(Default)]
+struct KeyRate {
+    /// Second since `start` and the keys sent during it
+    slots: [(u64, u32); RATE_WINDOW],
+    /// When the first key was counted
+    start: Option<std::time::Instant>
+}
+
+impl KeyRate {
+    fn count(&mut self) {
+        let start = *self.start.get_or_insert_with(std::time::Instant::now);
+        let second = start.elapsed().as_secs();
+        let slot = &mut self.slots[(second % RATE_WINDOW as u64) as usize];
+        // the slot of a second gone by is reused
+        if slot.0 != second {
+            *slot = (second, 0);
+        }
+        slot.1 += 1;
+    }
+
+    /// Keys sent per second over the window, or since the first key when
+    /// it was sent more recently
+    fn per_second(&self) -> f64 {
+        let now = match self.start {
+            Some(start) => start.elapsed().as_secs(),
+            None => return 0.0
+        };
+        let keys: u32 = self.slots.iter()
+            .filter(|(second, _)| now - second < RATE_WINDOW as u64)
+            .map(|(_, n)| n)
+            .sum();
+        keys as f64 / (now + 1).min(RATE_WINDOW as u64) as f64
+    }
+}
+
+pub struct Telekey {
+    config: TelekeyConfig,
+    version: u32,
+    mode: TelekeyMode,
+
+    remote: Option<TelekeyRemote>,
+    state: TelekeyState,
+    /// Where the received keys are delivered
+    sink: Box<dyn KeySink>,
+    keys: Option<Keys>,
+    /// Connections accepted by the server while a session is running
+    incoming: Option<mpsc::Receiver<TcpStream>>,
+    pending: VecDeque<TcpStream>,
+    /// Token of the running session, which a connection replacing it must
+    /// give as well
+    token: Option<SecretKey>,
+    /// Session of the connection which replaced the running one, along with
+    /// its peer
+    replacement: Option<(Box<dyn TelekeyTransport + Send>, Option<TelekeyRemote>)>,
+    /// Address of the peer, for a client the server it last connected to
+    peer: Option<SocketAddr>,
+    /// When the token of the awaited session was shown
+    token_shown: Option<std::time::Instant>,
+    /// Last valid latency measurements of the session
+    latencies: VecDeque<Latency>,
+    /// Last valid round-trip times, for the sparkline
+    rtts: VecDeque<i64>,
+    /// Modifiers and locks of the session, per the keys sent
+    keyboard: KeyboardState,
+    /// Keys sent recently, for the rate shown in the full menu
+    rate: KeyRate,
+    /// Repeats of the last key not sent yet, when coalescing
+    repeats: Option<Repeats>,
+    /// Keys and buttons held down on the receiver
+    held: HeldKeys,
+    /// The session resumes the previous one with the same peer, which had
+    /// the keys still held down released
+    reconnected: bool,
+    /// Parsed remapping table of the config
+    remaps: Vec<(KeyEvent, KeyEvent)>,
+    hotkeys: Hotkeys,
+    /// Clients of a multi-client server
+    clients: Option<Broadcast>,
+    /// Clipboard of this machine, kept open as some platforms clear what
+    /// was set once it closes
+    clipboard: Option<arboard::Clipboard>,
+    /// Shared by the handshake threads of a multi-client server
+    reject_log: Option<Arc<Mutex<BufWriter<File>>>>,
+    /// Failed handshakes per address, shared by the handshake threads
+    failures: Arc<Mutex<Failures>>,
+    transcript: Option<Transcript>,
+    /// Plaintext log of the keys sent by the server
+    history_log: Option<Transcript>,
+    recorder: Option<Recorder>,
+    last_sent: Option<std::time::Instant>,
+    throttled: bool
+}
+
+impl Telekey {
+    pub fn builder() -> TelekeyBuilder {
+        TelekeyBuilder::default()
+    }
+
+    /// Asks the running server to stop, e.g. from a signal handler. The
+    /// current session ends as when the input closes, telling the peer, and
+    /// `serve` returns `Ok`. Handshakes in progress are not interrupted
+    pub fn shutdown() {
+        SHUTDOWN.store(true, Ordering::SeqCst);
+    }
+
+    /// Short random ID of the session handled on the current thread, if
+    /// any, to tell the sessions apart in the logs. The server picks it
+    /// when it accepts a connection, and the client uses the same one once
+    /// the handshake is done
+    pub fn session_id() -> Option<String> {
+        SESSION_ID.with(|id| id.borrow().clone())
+    }
+
+    fn set_session_id(id: Option<String>) {
+        SESSION_ID.with(|cur| *cur.borrow_mut() = id);
+    }
+
+    /// Starts a new session on the current thread, with a new ID
+    fn start_session() -> Result<()> {
+        let mut bytes = [0u8; SESSION_ID_SIZE];
+        orion::util::secure_rand_bytes(&mut bytes)
+            .context("Failed to generate the session ID")?;
+        let id = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        Self::set_session_id(Some(id));
+        Ok(())
+    }
+
+    /// Takes the ID the server picked for the session, unless it is too
+    /// old to send one
+    fn join_session(msg: &HandshakeResponse) {
+        if !msg.session.is_empty() {
+            Self::set_session_id(Some(msg.session.to_string()));
+        }
+    }
+
+    pub fn is_server(&self) -> bool {
+        matches!(self.mode, TelekeyMode::Server)
+    }
+
+    /// Measures the round-trip time to the peer with a single ping
+    /// exchange, e.g. to poll the latency on another schedule than the
+    /// menu. The peer answers pings during its session, but any other
+    /// packet received meanwhile fails the measurement
+    pub fn ping<T: TelekeyTransport>(tr: &mut T) -> Result<std::time::Duration> {
+        Self::ping_once(tr)
+    }
+
+    fn new(mode: TelekeyMode, config: TelekeyConfig) -> Self {
+        let sink: Box<dyn KeySink> = match &config.sink {
+            Some(sink) => Box::new(sink.clone()),
+            None => LocalSink::of(&config).open()
+        };
+        let mut telekey = Self::bare(mode, config, sink);
+        // validated along with the config
+        telekey.remaps = telekey.config.remap.iter()
+            .filter_map(|(from, to)| Some((from.parse().ok()?, to.parse().ok()?)))
+            .collect();
+        telekey.hotkeys = Hotkeys::of(&telekey.config);
+        telekey.latencies.reserve(LATENCY_WINDOW);
+        telekey.rtts.reserve(SPARKLINE_SAMPLES);
+        telekey
+    }
+
+    /// Just enough of a server to run a handshake on its own thread, for a
+    /// multi-client server: no sink is opened and no hotkey nor remapping
+    /// is parsed
+    fn handshaker(config: TelekeyConfig, failures: Arc<Mutex<Failures>>,
+                  reject_log: Option<Arc<Mutex<BufWriter<File>>>>) -> Self {
+        let mut telekey = Self::bare(TelekeyMode::Server, config, Box::new(NoSink));
+        telekey.failures = failures;
+        telekey.reject_log = reject_log;
+        telekey
+    }
+
+    fn bare(mode: TelekeyMode, config: TelekeyConfig, sink: Box<dyn KeySink>) -> Self {
+        Telekey {
+            config, mode, version: PROTOCOL_VERSION, remote: None, remaps: Vec::new(),
+            hotkeys: Hotkeys::default(), state: TelekeyState::Idle, sink, keys: None,
+            incoming: None, pending: VecDeque::new(), token: None, replacement: None,
+            peer: None, clients: None, latencies: VecDeque::new(), token_shown: None, rtts: VecDeque::new(),
+            keyboard: KeyboardState::default(), rate: KeyRate::default(), repeats: None,
+            held: HeldKeys::default(), reconnected: false, clipboard: None,
+            reject_log: None, failures: Arc::default(), transcript: None, history_log: None, recorder: None,
+            last_sent: None, throttled: false
+        }
+    }
+
+    pub fn serve(addr: SocketAddr, config: TelekeyConfig) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Server listenning on {} as `{}`", addr, config.hostname);
+
+        let mut telekey = Telekey::new(TelekeyMode::Server, config);
+        if let Some(path) = &telekey.config.reject_log {
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("Could not open `{}`", path.display()))?;
+            telekey.reject_log = Some(Arc::new(Mutex::new(BufWriter::new(file))));
+        }
+        telekey.open_transcript()?;
+        if let Some(path) = &telekey.config.record {
+            telekey.recorder = Some(Recorder::create(path)?);
+        }
+        if let Some(path) = &telekey.config.history_log {
+            telekey.history_log = Some(Transcript::plain(path)?);
+        }
+        if telekey.config.multi_client {
+            if !telekey.sends() {
+                bail!("A multi-client server can only send the keys");
+            }
+            return telekey.serve_many(listener);
+        }
+        if !telekey.sends() {
+            telekey.sink.ready()?;
+        }
+        // accept connections on a dedicated thread, so that the ones arriving
+        // during a session are noticed, and process them serially
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if tx.send(stream).is_err() {
+                    break;
+                }
+            }
+        });
+        telekey.incoming = Some(rx);
+        // secret of the last session, which its peer may use again to
+        // reconnect
+        let mut last: Option<(IpAddr, SecretKey)> = None;
+        loop {
+            // a replacing connection already went through the handshake,
+            // with the token of the session it replaced
+            let (peer_addr, opened) = match telekey.replacement.take() {
+                Some((stream, remote)) => {
+                    telekey.remote = remote;
+                    telekey.reconnected = true;
+                    (stream.peer_addr().ok(), Ok(stream))
+                },
+                None => {
+                    let stream: TcpTransport = match telekey.next_connection() {
+                        Some(stream) => stream.into(),
+                        None => break
+                    };
+                    let peer_addr = stream.peer_addr().ok();
+                    if !telekey.check_allowed(peer_addr) || telekey.is_locked_out(peer_addr) {
+                        continue;
+                    }
+                    Self::start_session()?;
+                    telekey.reconnected = false;
+                    let skey = match last.take() {
+                        Some((ip, skey)) if Some(ip) == peer_addr.map(|a| a.ip()) => {
+                            info!("{} reconnected, its token is still valid", ip);
+                            telekey.token_shown = Some(std::time::Instant::now());
+                            telekey.reconnected = true;
+                            skey
+                        },
+                        _ => {
+                            let skey = telekey.session_secret()?;
+                            telekey.token_shown = Some(std::time::Instant::now());
+                            skey
+                        }
+                    };
+                    telekey.token = Some(SecretKey::from_slice(skey.unprotected_as_bytes())?);
+                    telekey.peer = peer_addr;
+                    (peer_addr, telekey.open_session(stream, skey))
+                }
+            };
+            telekey.peer = peer_addr;
+            let r = match opened {
+                Ok(stream) if !telekey.sends() => telekey.receive(stream),
+                Ok(stream) if telekey.config.send_queue > 0 => {
+                    let mut stream = QueuedTransport::new(stream,
+                        telekey.config.send_queue, telekey.config.drop_policy);
+                    telekey.wait_for_input(&mut stream)
+                },
+                Ok(mut stream) => telekey.wait_for_input(&mut stream),
+                Err(e) => Err(telekey.reject(peer_addr, e))
+            };
+            if telekey.remote.is_some() {
+                telekey.emit(TelekeyEvent::PeerDisconnected { addr: peer_addr });
+            }
+            if matches!(&r, Err(e) if e.is::<InputClosed>()) {
+                return Ok(());
+            }
+            match r {
+                Err(e) if is_closed(&e) => info!("Session closed: the peer disconnected"),
+                Err(e) => error!("Session closed: {:?}", e),
+                Ok(()) => ()
+            }
+            let replaced = telekey.replacement.is_some();
+            // the token stays valid for the session replacing this one
+            let token = if replaced { None } else { telekey.token.take() };
+            if telekey.config.reconnect && telekey.remote.is_some() {
+                last = peer_addr.zip(token).map(|(a, token)| (a.ip(), token));
+            }
+            telekey.remote = None;
+            telekey.peer = None;
+            telekey.throttled = false;
+            telekey.state = TelekeyState::Idle;
+            if !replaced {
+                Self::set_session_id(None);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves every client at once, each one receiving the same keys. The
+    /// handshakes run on their own threads, with the single token shown on
+    /// start. The latency shown is the one of the oldest client.
+    fn serve_many(&mut self, listener: TcpListener) -> Result<()> {
+        let timeout = self.config.read_timeout.map(std::time::Duration::from_secs);
+        let clients = Broadcast::new(timeout);
+        let secret = self.session_secret()?.unprotected_as_bytes().to_vec();
+        let (accepted, config) = (clients.clone(), self.config.clone());
+        let (failures, reject_log) = (self.failures.clone(), self.reject_log.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (clients, secret) = (accepted.clone(), secret.clone());
+                let (config, failures) = (config.clone(), failures.clone());
+                let reject_log = reject_log.clone();
+                std::thread::spawn(move || {
+                    let peer_addr = stream.peer_addr().ok();
+                    let mut telekey = Telekey::handshaker(config, failures, reject_log);
+                    if !telekey.check_allowed(peer_addr) || telekey.is_locked_out(peer_addr) {
+                        return;
+                    }
+                    let r = Self::start_session()
+                        .and_then(|()| SecretKey::from_slice(&secret).map_err(anyhow::Error::from))
+                        .and_then(|skey| telekey.open_session(TcpTransport::from(stream), skey));
+                    match r {
+                        Ok(tr) => clients.add(tr),
+                        Err(e) => error!("Refused {:?}: {:#}", peer_addr, telekey.reject(peer_addr, e))
+                    }
+                });
+            }
+        });
+
+        self.clients = Some(clients.clone());
+        let mut clients = clients;
+        loop {
+            while clients.is_empty() {
+                std::thread::sleep(ACCEPT_POLL);
+            }
+            let r = if self.config.send_queue > 0 {
+                let mut tr = QueuedTransport::new(clients.clone(),
+                    self.config.send_queue, self.config.drop_policy);
+                self.wait_for_input(&mut tr)
+            } else {
+                self.wait_for_input(&mut clients)
+            };
+            if matches!(&r, Err(e) if e.is::<InputClosed>()) {
+                return Ok(());
+            }
+            if let Err(e) = r {
+                error!("{:?}", e);
+            }
+            self.throttled = false;
+            self.state = TelekeyState::Idle;
+        }
+    }
+
+    /// Connects to the server at `addr`. With `reconnect`, a dropped session
+    /// is opened again after an exponential backoff, reusing the token.
+    pub fn connect_to(addr: SocketAddr, config: TelekeyConfig) -> Result<()> {
+        Self::connect_to_any(&[addr], config)
+    }
+
+    /// Connects to the first server of `addrs` accepting the connection,
+    /// trying them in turn, as `connect_to` does. A reconnection tries them
+    /// all again from the first one.
+    pub fn connect_to_any(addrs: &[SocketAddr], config: TelekeyConfig) -> Result<()> {
+        if addrs.is_empty() {
+            bail!("No server to connect to");
+        }
+        let mut telekey = Telekey::new(TelekeyMode::Client, config);
+        if !telekey.sends() {
+            telekey.sink.ready()?;
+        }
+        telekey.open_transcript()?;
+        let mut token = None;
+        let mut retries = 0;
+        loop {
+            let r = telekey.client_session(addrs, &mut token);
+            // the handshake went through if the server is known
+            let connected = telekey.remote.take().is_some();
+            telekey.state = TelekeyState::Idle;
+            if connected {
+                let addr = telekey.peer;
+                telekey.emit(TelekeyEvent::PeerDisconnected { addr });
+            }
+            let e = match r {
+                Ok(()) => return Ok(()),
+                // the input of a sending client closed
+                Err(e) if e.is::<InputClosed>() => return Ok(()),
+                Err(e) => e
+            };
+            if connected {
+                if is_closed(&e) {
+                    info!("Session closed: the peer disconnected");
+                } else {
+                    error!("{}", e);
+                }
+                retries = 0;
+            }
+            let exhausted = telekey.config.max_retries.is_some_and(|max| retries >= max);
+            if !telekey.config.reconnect || exhausted {
+                return if connected { Ok(()) } else { Err(e) };
+            }
+            if !connected {
+                warn!("{}", e);
+            }
+            let delay = std::time::Duration::from_secs(telekey.config.reconnect_delay)
+                .saturating_mul(1 << retries.min(16))
+                .min(MAX_RECONNECT_DELAY);
+            info!("Reconnecting in {:?}...", delay);
+            std::thread::sleep(delay);
+            retries += 1;
+        }
+    }
+
+    /// Opens a session with the server and emulates its keys until it ends.
+    /// The token is asked on the first session, then kept for the next ones
+    /// unless the handshake fails.
+    fn client_session(&mut self, addrs: &[SocketAddr], token: &mut Option<SecretKey>)
+        -> Result<()> {
+        Self::start_session()?;
+        let previous = self.peer;
+        let stream = self.connect_any(addrs)?;
+        // with the token of the previous session, so the same server
+        self.reconnected = token.is_some() && self.peer == previous;
+        let stream: TcpTransport = stream.into();
+        let skey = match token.take() {
+            Some(skey) => skey,
+            None => self.client_token()?
+        };
+        let secret = SecretKey::from_slice(skey.unprotected_as_bytes())?;
+
+        let mut stream = self.open_session(stream, secret)
+            .context("Handshake failed")?;
+        *token = Some(skey);
+
+        if self.sends() {
+            return self.wait_for_input(&mut stream);
+        }
+        if !self.config.quiet {
+            println!("{}{}", self.print_header(stream.peer_addr().ok()),
+                self.config.theme.active.render(&[]));
+        }
+        self.receive(stream)
+    }
+
+    /// Connects to the first server of `addrs` accepting the connection,
+    /// which becomes the peer
+    fn connect_any(&mut self, addrs: &[SocketAddr]) -> Result<TcpStream> {
+        let mut errors = Vec::new();
+        for &addr in addrs {
+            info!("Connecting to {}...", addr);
+            match self.connect_once(addr) {
+                Ok(stream) => {
+                    info!("Successfully connected to {}!", addr);
+                    self.peer = Some(addr);
+                    return Ok(stream);
+                },
+                Err(e) if addrs.len() == 1 => bail!("Couldn't connect to server: {}", e),
+                Err(e) => {
+                    warn!("Couldn't connect to {}: {}", addr, e);
+                    errors.push(format!("{}: {}", addr, e));
+                }
+            }
+        }
+        bail!("Couldn't connect to any of the {} servers:\n  {}", addrs.len(), errors.join("\n  "))
+    }
+
+    /// Opens a connection to `addr`, giving up after `connect_timeout`
+    fn connect_once(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let r = match self.config.connect_timeout {
+            Some(secs) => TcpStream::connect_timeout(&addr, std::time::Duration::from_secs(secs)),
+            None => TcpStream::connect(addr)
+        };
+        match (r, self.config.connect_timeout) {
+            (Ok(stream), _) => Ok(stream),
+            (Err(e), Some(secs)) if e.kind() == io::ErrorKind::TimedOut => {
+                bail!("connection timed out after {}s", secs)
+            },
+            (Err(e), _) => Err(e.into())
+        }
+    }
+
+    /// Emulates the keys of the peer until the session ends, then releases
+    /// the ones left held down
+    fn receive<T: TelekeyTransport>(&mut self, tr: T) -> Result<()> {
+        let r = self.listen_loop(tr);
+        self.release_held(None)?;
+        r
+    }
+
+    /// Serves the first client accepted on `addr` the keys of the macro at
+    /// `path`, waiting before each of them as long as when it was recorded,
+    /// then closes the session. Keys go through the usual sending path, so
+    /// transforms and rate limiting apply.
+    pub fn replay(addr: SocketAddr, path: &Path, config: TelekeyConfig) -> Result<()> {
+        let steps = recording::load(path)?;
+        let listener = TcpListener::bind(addr)?;
+        info!("Replaying `{}` ({} keys) on {}", path.display(), steps.len(), addr);
+
+        let mut telekey = Telekey::new(TelekeyMode::Server, config);
+        telekey.open_transcript()?;
+        for stream in listener.incoming() {
+            let stream: TcpTransport = stream?.into();
+            let peer_addr = stream.peer_addr().ok();
+            if !telekey.check_allowed(peer_addr) {
+                continue;
+            }
+            let skey = telekey.session_secret()?;
+            telekey.token_shown = Some(std::time::Instant::now());
+            let mut tr = match telekey.open_session(stream, skey) {
+                Ok(tr) => tr,
+                Err(e) => {
+                    error!("{:?}", telekey.reject(peer_addr, e));
+                    continue;
+                }
+            };
+            for (delay, e) in &steps {
+                std::thread::sleep(*delay);
+                telekey.send_key(&mut tr, e.clone())?;
+            }
+            tr.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Disconnect, Vec::new())).ok();
+            tr.shutdown().ok();
+            info!("Replayed {} keys", steps.len());
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    /// Relays the keys sent by the server at `target` to a client accepted
+    /// on `addr`. Each leg has its own session keys: keys are decrypted and
+    /// encrypted again by the relay, which thus sees them in plaintext.
+    pub fn relay(addr: SocketAddr, target: SocketAddr, config: TelekeyConfig) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Relay listenning on {} as `{}`", addr, config.hostname);
+
+        let mut downstream = Telekey::new(TelekeyMode::Server, config.clone());
+        let (stream, _) = loop {
+            let (stream, peer_addr) = listener.accept().context("Failed to accept client")?;
+            if downstream.check_allowed(Some(peer_addr)) {
+                break (stream, peer_addr);
+            }
+        };
+        let skey = downstream.session_secret()?;
+        downstream.token_shown = Some(std::time::Instant::now());
+        let mut client = downstream.open_session(TcpTransport::from(stream), skey)
+            .context("Handshake with the client failed")?;
+
+        info!("Connecting to {}...", target);
+        let stream = TcpStream::connect(target)
+            .with_context(|| format!("Couldn't connect to {}", target))?;
+        let mut upstream = Telekey::new(TelekeyMode::Client, config);
+        let skey = upstream.client_token()?;
+        let mut server = upstream.open_session(TcpTransport::from(stream), skey)
+            .context("Handshake with the server failed")?;
+
+        if !upstream.config.quiet {
+            println!("{}{}", upstream.print_header(server.peer_addr().ok()),
+                style(" RELAYING ").on_green().black());
+            println!("{}", downstream.print_header(client.peer_addr().ok()));
+        }
+        loop {
+            let p = server.recv_packet()?;
+            match p.kind() {
+                TelekeyPacketKind::KeyEvent | TelekeyPacketKind::Text
+                    | TelekeyPacketKind::Mouse => client.send_packet(p)?,
+                TelekeyPacketKind::Disconnect => {
+                    client.send_packet(p).ok();
+                    client.shutdown().ok();
+                    info!("Session closed: the peer disconnected");
+                    return Ok(());
+                },
+                _ => upstream.handle_packet(&mut server, p)?
+            }
+        }
+    }
+
+    /// Generates the secret of a new session and shows it as a token
+    fn session_secret(&self) -> Result<SecretKey> {
+        let skey = SecretKey::generate(32)
+            .context("Failed to generate session secret")?;
+        let token = base64::encode(skey.unprotected_as_bytes());
+        if self.config.qr {
+            // encodes the exact string the client asks for
+            let code = QrCode::new(token.as_bytes())
+                .context("Failed to encode the token as a QR code")?;
+            println!("{}", code.render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build());
+        }
+        match self.config.token_ttl {
+            _ if self.config.quiet => println!("{}", token),
+            Some(ttl) if !self.config.multi_client => {
+                println!("Enter this token to confirm (valid for {}s): {}", ttl, token)
+            },
+            _ => println!("Enter this token to confirm: {}", token)
+        }
+        Ok(skey)
+    }
+
+    /// The token of the config or of `TELEKEY_TOKEN`, asked otherwise
+    fn client_token(&self) -> Result<SecretKey> {
+        let token = self.config.token.clone().map(Zeroizing::new)
+            .or_else(|| std::env::var(TOKEN_ENV).ok().map(Zeroizing::new));
+        match token {
+            Some(token) => Self::parse_token(&token).context("Invalid token given"),
+            None => Self::read_token(self.config.quiet)
+        }
+    }
+
+    /// Asks for the token shown by the server, failing right away if nobody
+    /// can type it. A quiet prompt is left blank
+    fn read_token(quiet: bool) -> Result<SecretKey> {
+        if !io::stdin().is_terminal() {
+            bail!("No token given: pass --token or set {} when the input is not a terminal",
+                  TOKEN_ENV);
+        }
+        let mut inp = Zeroizing::new(String::new());
+        if !quiet {
+            print!("Please enter token to continue: ");
+            io::stdout().flush()?;
+        }
+        if io::stdin().read_line(&mut inp)? == 0 {
+            // e.g. Ctrl+D at the prompt
+            if !quiet {
+                println!();
+            }
+            bail!("No token provided: the input was closed");
+        }
+        Self::parse_token(&inp)
+    }
+
+    /// Decodes a token, with or without its base64 padding
+    fn parse_token(inp: &str) -> Result<SecretKey> {
+        let inp = inp.trim().trim_end_matches('=');
+        if inp.is_empty() {
+            bail!("No token provided");
+        }
+        let bytes = Zeroizing::new(base64::decode_config(inp, base64::STANDARD_NO_PAD)
+            .context("Invalid token: not base64")?);
+        if bytes.len() != TOKEN_SIZE {
+            bail!("Invalid token: {} bytes long once decoded, expected {}",
+                  bytes.len(), TOKEN_SIZE);
+        }
+        SecretKey::from_slice(&bytes)
+            .context("Could not create secret key")
+    }
+
+    /// Emulates the key events described in the key spec file at `path`
+    /// (one `KeyEvent` spec per line, `#` starts a comment) without any peer,
+    /// to check that key emulation works on this machine.
+    pub fn test_emulate(path: &Path, config: TelekeyConfig) -> Result<()> {
+        let specs = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read `{}`", path.display()))?;
+        let mut telekey = Telekey::new(TelekeyMode::Client, config);
+        for (i, line) in specs.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let e: KeyEvent = line.parse()
+                .with_context(|| format!("{}:{}: Invalid key spec",
+                                         path.display(), i + 1))?;
+            telekey.emulate(&e)?;
+        }
+        Ok(())
+    }
+
+    /// Tells whether a telekey server listens at `addr`, without any token.
+    /// A secure handshake sealed with a random secret is sent: an unsecure
+    /// server answers it with its hostname and version, while a secure one
+    /// cannot open it and closes the connection.
+    pub fn probe(addr: SocketAddr, config: TelekeyConfig) -> Result<()> {
+        let stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)
+            .with_context(|| format!("Couldn't connect to {}", addr))?;
+        stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+        let mut tr: TcpTransport = stream.into();
+        let telekey = Telekey::new(TelekeyMode::Client, config);
+
+        let session = EphemeralClientSession::new()
+            .context("Failed to generate ephemeral key pair securely")?;
+        let skey = SecretKey::generate(32)
+            .context("Failed to generate probe secret")?;
+        let pkey = orion::aead::seal(&skey, &session.public_key().to_bytes())
+            .context("Failed to seal public key using probe secret")?;
+        tr.send_packet(HandshakeRequest {
+            hostname: Cow::Borrowed(&telekey.config.hostname),
+            version: telekey.version,
+            token: Cow::Borrowed(&[]),
+            pkey: Cow::Owned(pkey),
+            max_rate: telekey.config.max_rate,
+            compress: false,
+            absolute_mouse: false,
+            reversed: false,
+            nonce: Cow::Borrowed(&[])
+        }.into())?;
+
+        match tr.recv_packet() {
+            Ok(p) => {
+                let msg = deserialize_from_slice::<HandshakeResponse>(p.data());
+                match msg {
+                    Ok(msg) if matches!(p.kind(), TelekeyPacketKind::Handshake)
+                        && msg.version != 0 => {
+                        println!("{}: TeleKey v{} server `{}` in unsecure mode",
+                            style(addr).green().bold(), msg.version, msg.hostname);
+                    },
+                    _ => println!("{}: Answered, but not with the TeleKey protocol",
+                                  style(addr).red().bold())
+                }
+            },
+            Err(e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof
+                               | io::ErrorKind::ConnectionReset) => {
+                println!("{}: Closed the connection on the handshake, likely a TeleKey server in secure mode",
+                    style(addr).yellow().bold());
+            },
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock
+                               | io::ErrorKind::TimedOut) => {
+                println!("{}: No answer after {:?}, not a TeleKey server",
+                    style(addr).red().bold(), PROBE_TIMEOUT);
+            },
+            Err(e) => return Err(e).context("Probe failed")
+        }
+        tr.shutdown().ok();
+        Ok(())
+    }
+
+    fn open_transcript(&mut self) -> Result<()> {
+        if let Some(path) = &self.config.transcript {
+            self.transcript = Some(if self.config.plain_transcript {
+                Transcript::plain(path)?
+            } else {
+                Transcript::encrypted(path, &transcript::passphrase()?)?
+            });
+        }
+        Ok(())
+    }
+
+    /// Reports `e` to the callback of the configuration, if any
+    fn emit(&self, e: TelekeyEvent) {
+        if let Some(hook) = &self.config.on_event {
+            hook.call(e);
+        }
+    }
+
+    /// Records a failed handshake in the reject log, if any, and counts it
+    /// towards the lock-out of its address
+    fn reject(&mut self, peer_addr: Option<SocketAddr>, e: anyhow::Error) -> anyhow::Error {
+        let reason = match e.downcast_ref::<Rejection>() {
+            Some(r) => r.to_string(),
+            None => "handshake failed".to_string()
+        };
+        self.log_event(peer_addr, &reason);
+        if let (Some(addr), Some(max)) = (peer_addr, self.config.max_failures) {
+            let window = std::time::Duration::from_secs(self.config.failure_window);
+            if self.failures.lock().unwrap().fail(addr.ip(), max, window) {
+                warn!("Locked {} out for {:?} after {} failed handshakes",
+                      addr.ip(), LOCKOUT, max);
+            }
+        }
+        e.context("Handshake failed")
+    }
+
+    /// Tells whether `peer_addr` is locked out after too many failed
+    /// handshakes, logging the connection dropped then
+    fn is_locked_out(&mut self, peer_addr: Option<SocketAddr>) -> bool {
+        let addr = match peer_addr {
+            Some(addr) => addr,
+            None => return false
+        };
+        let locked = self.failures.lock().unwrap().is_locked(addr.ip());
+        if locked {
+            warn!("Dropped a connection from {}, locked out", addr.ip());
+            self.log_event(peer_addr, "locked out");
+        }
+        locked
+    }
+
+    /// Tells whether a connection from `peer_addr` may go on, logging it
+    /// otherwise. Addresses which cannot be told are refused when an
+    /// allowlist is set
+    fn check_allowed(&mut self, peer_addr: Option<SocketAddr>) -> bool {
+        let allowed = match peer_addr {
+            Some(addr) => self.config.allows(addr.ip()),
+            None => self.config.allowlist.is_empty()
+        };
+        if !allowed {
+            let peer = peer_addr.map_or_else(|| "unknown".to_string(), |a| a.ip().to_string());
+            warn!("Dropped a connection from {}, not in the allowlist", peer);
+            self.log_event(peer_addr, "not in the allowlist");
+        }
+        allowed
+    }
+
+    /// Appends a line about `peer_addr` to the reject log, if any
+    fn log_event(&mut self, peer_addr: Option<SocketAddr>, reason: &str) {
+        if let Some(log) = &self.reject_log {
+            let peer = peer_addr.map_or_else(|| "unknown".to_string(), |a| a.to_string());
+            let mut log = log.lock().unwrap();
+            let r = writeln!(log, "{} {} {}", Utc::now().to_rfc3339(), peer, reason)
+                .and_then(|_| log.flush());
+            if let Err(e) = r {
+                error!("Could not write to the reject log: {}", e);
+            }
+        }
+    }
+
+    /// Waits for the next connection, `None` once a shutdown is requested
+    fn next_connection(&mut self) -> Option<TcpStream> {
+        if let Some(stream) = self.pending.pop_front() {
+            return Some(stream);
+        }
+        let incoming = self.incoming.as_ref()?;
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            match incoming.recv_timeout(ACCEPT_POLL) {
+                Ok(stream) => return Some(stream),
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None
+            }
+        }
+        info!("Shutting down");
+        None
+    }
+
+    /// Queues the connections accepted since the last call. A connection
+    /// from the address of the current peer likely means its previous
+    /// session is stuck: it is logged and, depending on `on_duplicate`,
+    /// ends the current session once it completed its own handshake, with
+    /// the token of the current session, so that it is served right away.
+    fn check_incoming(&mut self) -> Result<()> {
+        let streams: Vec<TcpStream> = match &self.incoming {
+            Some(incoming) => incoming.try_iter().collect(),
+            None => return Ok(())
+        };
+        for stream in streams {
+            let addr = stream.peer_addr().ok();
+            let same_ip = matches!((addr, self.peer),
+                (Some(addr), Some(peer)) if addr.ip() == peer.ip());
+            if !same_ip {
+                self.pending.push_back(stream);
+                continue;
+            }
+            match self.config.on_duplicate {
+                DuplicatePolicy::Warn => {
+                    self.log_event(addr, "duplicate connection");
+                    warn!("{} connected again while its session is running",
+                        addr.unwrap().ip());
+                    self.pending.push_back(stream);
+                },
+                DuplicatePolicy::Replace => {
+                    if let Some(addr) = self.replace_session(stream)? {
+                        self.log_event(Some(addr), "duplicate connection");
+                        bail!("Session replaced by a new connection from {}", addr);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the handshake of a connection meant to replace the current
+    /// session, which it does only if the handshake succeeds. Returns the
+    /// address of its peer then
+    fn replace_session(&mut self, stream: TcpStream) -> Result<Option<SocketAddr>> {
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return Ok(None)
+        };
+        let token = match &self.token {
+            Some(token) => SecretKey::from_slice(token.unprotected_as_bytes())?,
+            None => {
+                self.pending.push_back(stream);
+                return Ok(None);
+            }
+        };
+        if !self.check_allowed(Some(addr)) || self.is_locked_out(Some(addr)) {
+            return Ok(None);
+        }
+        let mut handshaker = Telekey::handshaker(self.config.clone(), self.failures.clone(),
+            self.reject_log.clone());
+        handshaker.token_shown = self.token_shown;
+        let current = Self::session_id();
+        Self::start_session()?;
+        match handshaker.open_session(TcpTransport::from(stream), token) {
+            Ok(tr) => {
+                self.replacement = Some((tr, handshaker.remote.take()));
+                Ok(Some(addr))
+            },
+            Err(e) => {
+                Self::set_session_id(current);
+                warn!("Kept the current session: {:?}", handshaker.reject(Some(addr), e));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs the handshake matching the configured security mode
+    fn open_session<T: FrameTransport + Send + 'static>(&mut self, mut tr: T, skey: SecretKey)
+        -> Result<Box<dyn TelekeyTransport + Send>> {
+        tr.set_max_packet_size(self.config.max_packet_size);
+        tr.set_nodelay(self.config.nodelay)
+            .context("Failed to set TCP_NODELAY")?;
+        tr.set_read_timeout(self.config.read_timeout.map(std::time::Duration::from_secs))
+            .context("Failed to set the read timeout")?;
+        let addr = tr.peer_addr().ok();
+        let r = if self.config.secure {
+            self.sec_handshake(tr, skey).context("Secure handshake failed")
+        } else {
+            self.handshake(tr, skey).map(|tr| Box::new(tr) as Box<dyn TelekeyTransport + Send>)
+        };
+        let tr = match r {
+            Ok(tr) => tr,
+            Err(e) => {
+                self.emit(TelekeyEvent::HandshakeFailed { addr });
+                if is_timed_out(&e) {
+                    return Err(e.context(Rejection::HandshakeTimeout));
+                }
+                return Err(e);
+            }
+        };
+        if let Some(remote) = &self.remote {
+            debug!("Handshake done with `{}` (protocol v{}, max rate {}, compression {})",
+                remote.hostname, remote.version, remote.max_rate, self.compression());
+            let hostname = remote.hostname.clone();
+            self.emit(TelekeyEvent::PeerConnected { addr, hostname });
+        }
+        Ok(tr)
+    }
+
+    /// Secure handshake, which only falls back to the unsecure one if the
+    /// peer runs in unsecure mode and `allow_downgrade` is set. A request
+    /// or response without a sealed public key comes from an unsecure peer.
+    fn sec_handshake<T: FrameTransport + Send + 'static>(&mut self, mut tr: T, skey: SecretKey)
+        -> Result<Box<dyn TelekeyTransport + Send>> {
+        if matches!(self.mode, TelekeyMode::Server) {
+            let session = EphemeralServerSession::new()
+                .context("Failed to generate ephemeral key pair securely")?;
+
+            let p = tr.recv_packet().context("Failed to receive handshake")?;
+            self.check_token_ttl(&mut tr)?;
+            let msg: HandshakeRequest = deserialize_from_slice(p.data())
+                .context("Failed to decode HandshakeRequest message")?;
+            self.check_version(&mut tr, msg.version)?;
+            self.check_direction(&mut tr, msg.reversed)?;
+            if msg.pkey.is_empty() && !msg.token.is_empty() {
+                if !self.config.allow_downgrade {
+                    tr.shutdown().context("Failed to close socket (Security mode mismatch)")?;
+                    return Err(anyhow!(Rejection::SecurityMismatch)
+                        .context("The client runs in unsecure mode, use --allow-downgrade to accept it"));
+                }
+                warn!("The client runs in unsecure mode, downgrading to an unsecure session");
+                self.accept_token(&mut tr, msg, &skey)?;
+                return Ok(Box::new(tr));
+            }
+            let key = orion::aead::open(&skey, &msg.pkey)
+                .map_err(|_| Rejection::InvalidToken)
+                .context("Could not open client public key with session secret")?;
+            let key = peer_public_key(key)?;
+
+            let pkey = orion::aead::seal(&skey, &session.public_key().to_bytes())
+                .context("Failed to seal public key using session secret")?;
+            let mut nonce = [0u8; NONCE_SIZE];
+            orion::util::secure_rand_bytes(&mut nonce)
+                .context("Failed to generate the handshake nonce")?;
+            tr.send_packet(HandshakeResponse {
+                hostname: Cow::Borrowed(&self.config.hostname),
+                version: self.version,
+                pkey: Cow::Owned(pkey),
+                max_rate: self.config.max_rate,
+                compress: self.config.compress && msg.compress,
+                absolute_mouse: self.config.absolute_mouse && msg.absolute_mouse,
+                reversed: self.reversed(),
+                nonce: Cow::Borrowed(&nonce),
+                session: Cow::Owned(Self::session_id().unwrap_or_default())
+            }.into())?;
+            self.remote = Some(msg.into());
+            tr.set_compression(self.compression());
+
+            let server_keys: SessionKeys = session
+                .establish_with_client(&key)
+                .context("Key exchange failed")?;
+            let mut tr = SecureTransport::new(tr, server_keys)
+                .context("Failed to set up the session keys")?;
+            if let Err(e) = Self::check_nonce(&mut tr, &nonce) {
+                self.remote = None;
+                return Err(e);
+            }
+            Ok(Box::new(tr))
+        } else {
+            let session = EphemeralClientSession::new()
+                .context("Failed to generate ephemeral key pair securely")?;
+            let pkey = orion::aead::seal(&skey, &session.public_key().to_bytes())
+                .context("Failed to seal public key using session secret")?;
+            tr.send_packet(HandshakeRequest {
+                hostname: Cow::Borrowed(&self.config.hostname),
+                version: self.version,
+                token: Cow::Borrowed(&[]),
+                pkey: Cow::Owned(pkey),
+                max_rate: self.config.max_rate,
+                compress: self.config.compress,
+                absolute_mouse: self.config.absolute_mouse,
+                reversed: self.reversed(),
+                nonce: Cow::Borrowed(&[])
+            }.into())?;
+
+            let p = tr.recv_packet()?;
+            let msg: HandshakeResponse = deserialize_from_slice(p.data())
+                .context("Failed to decode HandshakeResponse message")?;
+            self.check_version(&mut tr, msg.version)?;
+            self.check_direction(&mut tr, msg.reversed)?;
+            if msg.pkey.is_empty() {
+                if !self.config.allow_downgrade {
+                    tr.shutdown().context("Failed to close socket (Security mode mismatch)")?;
+                    bail!("The server runs in unsecure mode, use --allow-downgrade to connect anyway");
+                }
+                warn!("The server runs in unsecure mode, downgrading to an unsecure session");
+                return Ok(Box::new(self.handshake(tr, skey)?));
+            }
+            self.remote = Some((&msg).into());
+            Self::join_session(&msg);
+            tr.set_compression(self.compression());
+
+            let key = orion::aead::open(&skey, &msg.pkey)
+                .context("Could not open server public key with session secret")?;
+            let key = peer_public_key(key)?;
+            let client_keys: SessionKeys = session
+                .establish_with_server(&key)
+                .context("Key exchange failed")?;
+            let mut tr = SecureTransport::new(tr, client_keys)
+                .context("Failed to set up the session keys")?;
+            // sealed with the session keys, so only the peer of this very
+            // exchange can echo it
+            tr.send_packet(HandshakeRequest {
+                nonce: Cow::Borrowed(&msg.nonce),
+                ..Default::default()
+            }.into()).context("Failed to confirm the handshake")?;
+            Ok(Box::new(tr))
+        }
+    }
+
+    fn handshake<T: FrameTransport>(&mut self, mut tr: T, secret: SecretKey) -> Result<T> {
+        if matches!(self.mode, TelekeyMode::Server) {
+            let mut p = tr.recv_packet()?;
+            self.check_token_ttl(&mut tr)?;
+            let wants_secure = {
+                let msg: HandshakeRequest = deserialize_from_slice(p.data())
+                    .context("Failed to decode HandshakeRequest message")?;
+                msg.token.is_empty() && !msg.pkey.is_empty()
+            };
+            if wants_secure {
+                // Let the client know this server is unsecure, it may then
+                // downgrade and send its token in a new request
+                warn!("The client asked for a secure session but this server runs in unsecure mode");
+                tr.send_packet(HandshakeResponse {
+                    hostname: Cow::Borrowed(&self.config.hostname),
+                    version: self.version,
+                    pkey: Cow::Borrowed(&[]),
+                    max_rate: self.config.max_rate,
+                    compress: false,
+                    absolute_mouse: false,
+                    reversed: self.reversed(),
+                    nonce: Cow::Borrowed(&[]),
+                    session: Cow::Owned(Self::session_id().unwrap_or_default())
+                }.into())?;
+                p = tr.recv_packet()
+                    .context("The client refused to downgrade to an unsecure session")?;
+            }
+            let msg: HandshakeRequest = deserialize_from_slice(p.data())
+                .context("Failed to decode HandshakeRequest message")?;
+            self.check_version(&mut tr, msg.version)?;
+            self.check_direction(&mut tr, msg.reversed)?;
+            self.accept_token(&mut tr, msg, &secret)?;
+
+            Ok(tr)
+        } else {
+            let p = HandshakeRequest {
+                hostname: Cow::Borrowed(&self.config.hostname),
+                version: self.version,
+                token: Cow::Borrowed(secret.unprotected_as_bytes()),
+                pkey: Cow::Borrowed(&[]),
+                max_rate: self.config.max_rate,
+                compress: self.config.compress,
+                absolute_mouse: self.config.absolute_mouse,
+                reversed: self.reversed(),
+                nonce: Cow::Borrowed(&[])
+            };
+            tr.send_packet(p.into())?;
+
+            let p = tr.recv_packet()
+                .context("No handshake response, the server may only accept secure sessions")?;
+            let msg: HandshakeResponse = deserialize_from_slice(p.data())
+                .context("Failed to decode HandshakeResponse message")?;
+            self.check_version(&mut tr, msg.version)?;
+            self.check_direction(&mut tr, msg.reversed)?;
+            self.remote = Some((&msg).into());
+            Self::join_session(&msg);
+            tr.set_compression(self.compression());
+            Ok(tr)
+        }
+    }
+
+    /// Refuses the handshake if it arrived after the token expired
+    fn check_token_ttl<T: TelekeyTransport>(&self, tr: &mut T) -> Result<()> {
+        let expired = match (self.token_shown, self.config.token_ttl) {
+            (Some(shown), Some(ttl)) => shown.elapsed() > std::time::Duration::from_secs(ttl),
+            _ => false
+        };
+        if expired {
+            tr.shutdown().context("Failed to close socket (Expired token)")?;
+            bail!(Rejection::ExpiredToken);
+        }
+        Ok(())
+    }
+
+    /// Refuses a peer whose protocol version this build does not understand
+    fn check_version<T: TelekeyTransport>(&self, tr: &mut T, version: u32) -> Result<()> {
+        if !(MIN_SUPPORTED_VERSION..=self.version).contains(&version) {
+            tr.shutdown().context("Failed to close socket (Incompatible version)")?;
+            bail!(Rejection::IncompatibleVersion(version));
+        }
+        Ok(())
+    }
+
+    /// Refuses a secure session whose first packet does not echo `nonce`,
+    /// such as one opened by replaying an earlier handshake request
+    fn check_nonce<T: TelekeyTransport>(tr: &mut T, nonce: &[u8]) -> Result<()> {
+        let p = tr.recv_packet().context("Failed to receive the handshake confirmation")?;
+        let echoed = matches!(p.kind(), TelekeyPacketKind::Handshake)
+            && deserialize_from_slice::<HandshakeRequest>(p.data())
+                .is_ok_and(|msg| orion::util::secure_cmp(nonce, &msg.nonce).is_ok());
+        if !echoed {
+            tr.shutdown().context("Failed to close socket (Stale handshake)")?;
+            bail!(Rejection::StaleHandshake);
+        }
+        Ok(())
+    }
+
+    /// Refuses a peer which does not agree on the direction: both ends must
+    /// tell whether the client is the one sending the keys
+    fn check_direction<T: TelekeyTransport>(&self, tr: &mut T, reversed: bool) -> Result<()> {
+        if reversed != self.reversed() {
+            tr.shutdown().context("Failed to close socket (Direction mismatch)")?;
+            bail!(Rejection::DirectionMismatch);
+        }
+        Ok(())
+    }
+
+    /// Whether the client sends the keys and the server receives them,
+    /// rather than the other way around
+    fn reversed(&self) -> bool {
+        matches!((self.config.direction, self.mode),
+                 (Some(Direction::Receive), TelekeyMode::Server)
+                 | (Some(Direction::Send), TelekeyMode::Client))
+    }
+
+    /// Whether this end sends the keys, rather than receiving them
+    fn sends(&self) -> bool {
+        self.is_server() != self.reversed()
+    }
+
+    /// Checks the plaintext token of an unsecure handshake request and
+    /// answers it
+    fn accept_token<T: FrameTransport>(&mut self, tr: &mut T, msg: HandshakeRequest,
+                                       secret: &SecretKey) -> Result<()> {
+        // constant-time, so timing tells nothing about the matching bytes
+        let valid = orion::util::secure_cmp(secret.unprotected_as_bytes(), &msg.token);
+        if valid.is_err() {
+            tr.shutdown().context("Failed to close socket (Invalid secret)")?;
+            bail!(Rejection::InvalidToken);
+        }
+        tr.send_packet(HandshakeResponse {
+            hostname: Cow::Borrowed(&self.config.hostname),
+            version: self.version,
+            pkey: Cow::Borrowed(&[]),
+            max_rate: self.config.max_rate,
+            compress: self.config.compress && msg.compress,
+            absolute_mouse: self.config.absolute_mouse && msg.absolute_mouse,
+            reversed: self.reversed(),
+            nonce: Cow::Borrowed(&[]),
+            session: Cow::Owned(Self::session_id().unwrap_or_default())
+        }.into())?;
+        self.remote = Some(msg.into());
+        tr.set_compression(self.compression());
+        Ok(())
+    }
+
+    /// Whether both ends asked for absolute pointer moves, known once the
+    /// handshake is done
+    fn absolute_mouse(&self) -> bool {
+        self.config.absolute_mouse && self.remote.as_ref().is_some_and(|r| r.absolute_mouse)
+    }
+
+    /// Whether both ends asked for compression, known once the handshake
+    /// messages were exchanged
+    fn compression(&self) -> bool {
+        self.config.compress && self.remote.as_ref().is_some_and(|r| r.compress)
+    }
+
+    fn listen_loop<T: TelekeyTransport>(&mut self, mut tr: T) -> Result<()> {
+        loop {
+            let max_hold = self.config.max_hold.map(std::time::Duration::from_secs);
+            let p = match max_hold.and_then(|max| self.held.deadline(max)) {
+                Some(deadline) => match Self::recv_until(&mut tr, deadline)? {
+                    Some(p) => p,
+                    None => {
+                        self.release_held(max_hold)?;
+                        continue;
+                    }
+                },
+                None => tr.recv_packet()?
+            };
+            let leaving = matches!(p.kind(), TelekeyPacketKind::Disconnect);
+            self.handle_packet(&mut tr, p)?;
+            if leaving {
+                info!("Session closed: the peer disconnected");
+                return Ok(());
+            }
+        }
+    }
+
+    /// Polls for a packet until `deadline`
+    fn recv_until<T: TelekeyTransport>(tr: &mut T, deadline: std::time::Instant)
+        -> Result<Option<TelekeyPacket>> {
+        loop {
+            if let Some(p) = tr.try_recv_packet()? {
+                return Ok(Some(p));
+            }
+            if std::time::Instant::now() >= deadline {
+                return Ok(None);
+            }
+            std::thread::sleep(KEEPALIVE_POLL);
+        }
+    }
+
+    /// Releases, in reverse order, the keys and buttons held down for at
+    /// least `min`, or all of them
+    fn release_held(&mut self, min: Option<std::time::Duration>) -> Result<()> {
+        let (keys, buttons) = self.held.take(min);
+        if keys.is_empty() && buttons.is_empty() {
+            return Ok(());
+        }
+        info!("Releasing {} keys and {} buttons left held down", keys.len(), buttons.len());
+        for mut k in keys.into_iter().rev() {
+            k.state = KeyState::UP;
+            self.sink.deliver(&k)?;
+        }
+        for button in buttons.into_iter().rev() {
+            self.sink.deliver_mouse(&MouseEvent {
+                action: MouseAction::RELEASE, button, ..Default::default()
+            })?;
+        }
+        Ok(())
+    }
+
+    fn handle_packet<T: TelekeyTransport>(&mut self, tr: &mut T, p: TelekeyPacket)
+        -> Result<()> {
         match p.kind() {
             TelekeyPacketKind::Handshake => Ok(()), // Handshake should no be sent at this point
             TelekeyPacketKind::KeyEvent => {
@@ -418,187 +3339,1686 @@ impl Telekey {
                     return tr.shutdown()
                         .context("Received KeyEvent but the sender is unknown");
                 }
-                if !self.is_server() {
+                if !self.sends() {
                     let msg: KeyEvent = deserialize_from_slice(p.data())
                         .context("Failed to decode KeyEvent message")?;
-
-                    if self.config.cold_run {
-                        print!("{}", msg);
-                        io::stdout().flush()?;
+                    // a coalesced key stands for several clicks
+                    for _ in 0..msg.repeat.clamp(1, MAX_REPEAT) {
+                        self.emulate(&msg)?;
+                    }
+                }
+                Ok(())
+            },
+            // only senders ping, so every ping received is echoed as is
+            TelekeyPacketKind::Ping if p.data().len() != PING_TOKEN_SIZE => {
+                bail!("Malformed ping packet of {} bytes, expected {}",
+                      p.data().len(), PING_TOKEN_SIZE)
+            },
+            TelekeyPacketKind::Ping => {
+                tr.send_packet(p).context("Could not respond to ping packet")
+            },
+            TelekeyPacketKind::Text => {
+                if self.remote.is_none() {
+                    return tr.shutdown()
+                        .context("Received Text but the sender is unknown");
+                }
+                if !self.sends() {
+                    let text = std::str::from_utf8(p.data())
+                        .context("Received text is not valid UTF-8")?;
+                    self.type_text(text)?;
+                }
+                Ok(())
+            },
+            TelekeyPacketKind::Mouse => {
+                if self.remote.is_none() {
+                    return tr.shutdown()
+                        .context("Received Mouse but the sender is unknown");
+                }
+                if !self.sends() {
+                    let msg: MouseEvent = deserialize_from_slice(p.data())
+                        .context("Failed to decode MouseEvent message")?;
+                    if msg.action == MouseAction::MOVE && msg.absolute && !self.absolute_mouse() {
+                        warn!("Skipped `{}`: absolute pointer moves were not agreed on", msg);
+                        return Ok(());
+                    }
+                    self.held.button(&msg);
+                    self.sink.deliver_mouse(&msg)?;
+                }
+                Ok(())
+            },
+            TelekeyPacketKind::Clipboard => {
+                if self.remote.is_none() {
+                    return tr.shutdown()
+                        .context("Received Clipboard but the sender is unknown");
+                }
+                if self.sends() {
+                    // answers are read by `fetch_clipboard`
+                    warn!("Unexpected clipboard from the peer, skipped");
+                    return Ok(());
+                }
+                if p.data().is_empty() {
+                    let text = if self.config.clipboard {
+                        self.read_clipboard()
                     } else {
-                         // TODO: Support pressing and releasing keys rather
-                         // than just pressing them
-                        let r: Result<enigo::Key, String> = (&msg).into();
-                        match r {
-                            Ok(k) => self.enigo.key_click(k),
-                            Err(e) => {
-                                println!("{} while receiving `{}`: {:?}", 
-                                         style("RUNTIME ERROR").yellow().bold(),
-                                         style(format!("{}", msg)).green(), e);
-                            }
-                        }
+                        warn!("The peer asked for the clipboard, \
+                               which is only shared with --clipboard");
+                        String::new()
+                    };
+                    let answer = TelekeyPacket::raw(TelekeyPacketKind::Clipboard, text.into_bytes());
+                    return tr.send_packet(answer).context("Could not send the clipboard");
+                }
+                if !self.config.clipboard {
+                    warn!("Skipped the clipboard of the peer, \
+                           which is only shared with --clipboard");
+                    return Ok(());
+                }
+                let text = std::str::from_utf8(p.data())
+                    .context("Received clipboard is not valid UTF-8")?;
+                self.write_clipboard(text);
+                Ok(())
+            },
+            TelekeyPacketKind::Disconnect => {
+                tr.shutdown().ok();
+                Ok(())
+            },
+            k => {
+                warn!("Unknown packet {:?}", k);
+                Ok(())
+            }
+        }
+    }
+
+    /// Every received event is delivered on its own: repeated events (e.g.
+    /// a held key) are never coalesced.
+    fn emulate(&mut self, msg: &KeyEvent) -> Result<()> {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.record(msg).context("Could not write to the transcript")?;
+        }
+        self.held.key(msg);
+        self.sink.deliver(msg)
+    }
+
+    /// Types a whole text at once
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.record_text(text)?;
+        self.sink.type_text(text)
+    }
+
+    /// Measures the round-trip time as the fastest of a few ping exchanges.
+    /// The clocks of the two machines are not synchronized, so the one-way
+    /// delay can only be estimated as half of it.
+    fn measure_latency<T: TelekeyTransport>(tr: &mut T) -> Result<Latency> {
+        let mut best = std::time::Duration::MAX;
+        for _ in 0..LATENCY_SAMPLES {
+            best = best.min(Self::ping_once(tr)?);
+        }
+        let rtt = i64::try_from(best.as_nanos()).unwrap_or(i64::MAX);
+        Ok(Latency { rtt, one_way: rtt / 2 })
+    }
+
+    /// Measures the latency and renders it for the menu. `None` means the
+    /// peer left during the ping exchange, which only ends the session.
+    fn refresh_latency<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<Option<String>> {
+        self.render_latency(Self::measure_latency(tr))
+    }
+
+    /// Renders a latency measurement for the menu, see `refresh_latency`
+    fn render_latency(&mut self, measured: Result<Latency>) -> Result<Option<String>> {
+        match measured {
+            Ok(latency) => {
+                // invalid measurements are shown as unknown, but left out of
+                // the statistics
+                if latency.is_valid() {
+                    let rtt = std::time::Duration::from_nanos(latency.rtt as u64);
+                    self.emit(TelekeyEvent::LatencyMeasured(rtt));
+                    if self.latencies.len() == LATENCY_WINDOW {
+                        self.latencies.pop_front();
+                    }
+                    self.latencies.push_back(latency);
+                    if self.rtts.len() == SPARKLINE_SAMPLES {
+                        self.rtts.pop_front();
+                    }
+                    self.rtts.push_back(latency.rtt);
+                }
+                let stats = LatencyStats::of(&self.latencies);
+                let rendered = latency.render(stats, &sparkline(&self.rtts), &self.config.theme);
+                if self.config.nodelay {
+                    Ok(Some(rendered))
+                } else {
+                    // keys may be delayed by Nagle's algorithm on top of this
+                    Ok(Some(format!("{}{}", rendered, style("(Nagle) ").color256(246))))
+                }
+            },
+            Err(e) if is_disconnect(&e) => {
+                info!("Session closed: the peer disconnected");
+                Ok(None)
+            },
+            Err(e) if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut) => {
+                Err(e.context("Latency measurement timed out"))
+            },
+            Err(e) => Err(e.context("Latency measurement failed"))
+        }
+    }
+
+    /// Sends a ping and returns the time until its answer, measured on the
+    /// local monotonic clock only
+    fn ping_once<T: TelekeyTransport>(tr: &mut T) -> Result<std::time::Duration> {
+        let token = PING_TOKEN.fetch_add(1, Ordering::Relaxed);
+        let start = std::time::Instant::now();
+        tr.send_packet(ping_packet(token))?;
+        let p = tr.recv_packet()?;
+        match p.kind() {
+            TelekeyPacketKind::Ping => {
+                let rtt = start.elapsed();
+                if p.data() != token.to_be_bytes() {
+                    bail!("Ping answer does not echo the token sent");
+                }
+                Ok(rtt)
+            },
+            k => {
+                bail!("Expected ping packet received {:?}", k)
+            }
+        }
+    }
+
+    fn print_header(&self, peer_addr: Option<SocketAddr>) -> String
+    {
+        let theme = &self.config.theme;
+        let name = theme.name.render(&[("version", &self.version)]);
+        if peer_addr.is_none() {
+            return format!("{}{}", name, theme.unknown_peer.render(&[]));
+        };
+        let peer_addr = peer_addr.unwrap();
+        let peer = if let Some(remote) = &self.remote {
+            theme.named_peer.render(&[("addr", &peer_addr), ("hostname", &remote.hostname)])
+        } else {
+            theme.peer.render(&[("addr", &peer_addr)])
+        };
+        format!("{}{}", name, peer)
+    }
+
+    fn print_menu(&self, header: &str, latency: &str,
+                  history: Option<&VecDeque<KeyEvent>>) {
+        if self.config.quiet {
+            return;
+        }
+        for line in self.menu_lines(header, latency, history) {
+            println!("{}", line);
+        }
+    }
+
+    /// Prints the simple menu again in place of the previous one
+    fn redraw_menu(&self, term: &Term, header: &str, latency: &str) -> Result<()> {
+        if !self.config.quiet {
+            term.clear_last_lines(2)?;
+            self.print_menu(header, latency, None);
+        }
+        Ok(())
+    }
+
+    /// Lines of the menu: the status line, the last keys sent if any, and
+    /// the prompt
+    fn menu_lines(&self, header: &str, latency: &str,
+                  history: Option<&VecDeque<KeyEvent>>) -> Vec<String> {
+        let theme = &self.config.theme;
+        let state = match self.state {
+            TelekeyState::Idle | TelekeyState::Inactive => theme.idle.render(&[]),
+            TelekeyState::Activating(n) => theme.activating.render(&[("n", &n)]),
+            TelekeyState::Active => theme.active.render(&[]),
+            TelekeyState::Paused => theme.paused.render(&[]),
+        };
+
+        let clients = match &self.clients {
+            Some(clients) => theme.clients.render(&[("n", &clients.len())]),
+            None => String::new()
+        };
+        let keyboard = self.keyboard.to_string();
+        let keyboard = if keyboard.is_empty() {
+            keyboard
+        } else {
+            theme.keyboard.render(&[("keys", &keyboard)])
+        };
+        // the simple menu is not redrawn as the rate changes
+        let rate = if self.config.update_screen {
+            theme.rate.render(&[("rate", &format!("{:.1}", self.rate.per_second()))])
+        } else {
+            String::new()
+        };
+        let mut lines = Vec::new();
+        if self.throttled {
+            lines.push(format!("{}{}{}{}{}{}{}", header, clients, state, keyboard, latency, rate,
+                               theme.throttled.render(&[])));
+        } else {
+            lines.push(format!("{}{}{}{}{}{}", header, clients, state, keyboard, latency, rate));
+        }
+        if let Some(hist) = history {
+            // only the last keys fitting between the status line and the
+            // prompt are shown
+            let rows = Term::stdout().size().0 as usize;
+            for l in hist.iter().skip(hist.len().saturating_sub(rows.saturating_sub(2))) {
+                lines.push(l.to_string());
+            }
+        }
+        lines.push(theme.prompt.render(&[]));
+        lines
+    }
+
+    /// Switches to `Active`, counting down `activation_delay` seconds in the
+    /// menu first so the remote user can focus the right window. Keys typed
+    /// during the countdown are not dropped: they stay in the terminal buffer
+    /// and are sent as soon as the session becomes active.
+    fn activate(&mut self, term: &Term, header: &str, latency: &str,
+                history: Option<&VecDeque<KeyEvent>>) -> Result<()> {
+        for remaining in (1..=self.config.activation_delay).rev() {
+            self.state = TelekeyState::Activating(remaining);
+            if !self.config.quiet {
+                if self.config.update_screen {
+                    term.clear_screen()?;
+                } else {
+                    term.clear_last_lines(2)?;
+                }
+            }
+            self.print_menu(header, latency, history);
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+        self.state = TelekeyState::Active;
+        Ok(())
+    }
+
+    /// Reads a key like `read_key`, pinging the peer each time no key was
+    /// typed for the keepalive interval, so a dead peer ends the session
+    /// even while idle
+    fn next_key<T: TelekeyTransport>(&mut self, tr: &mut T,
+                                     timeout: Option<std::time::Duration>)
+        -> Result<Option<KeyEvent>> {
+        let interval = match self.config.keepalive {
+            Some(secs) => std::time::Duration::from_secs(secs),
+            None => return self.read_key(timeout)
+        };
+        let deadline = timeout.map(|t| std::time::Instant::now() + t);
+        loop {
+            let left = deadline
+                .map(|d| d.saturating_duration_since(std::time::Instant::now()));
+            let wait = left.map_or(interval, |left| left.min(interval));
+            if let Some(key) = self.read_key(Some(wait))? {
+                return Ok(Some(key));
+            }
+            if deadline.is_some_and(|d| std::time::Instant::now() >= d) {
+                return Ok(None);
+            }
+            Self::keepalive(tr)?;
+        }
+    }
+
+    /// Pings the peer and polls for its answer, failing with `TimedOut` if
+    /// none arrived within `KEEPALIVE_TIMEOUT`
+    fn keepalive<T: TelekeyTransport>(tr: &mut T) -> Result<()> {
+        let token = PING_TOKEN.fetch_add(1, Ordering::Relaxed);
+        tr.send_packet(ping_packet(token))?;
+        let deadline = std::time::Instant::now() + KEEPALIVE_TIMEOUT;
+        loop {
+            match tr.try_recv_packet()? {
+                Some(p) if matches!(p.kind(), TelekeyPacketKind::Ping) => {
+                    if p.data() != token.to_be_bytes() {
+                        bail!("Ping answer does not echo the token sent");
+                    }
+                    return Ok(());
+                },
+                Some(p) => bail!("Expected ping packet received {:?}", p.kind()),
+                None if std::time::Instant::now() >= deadline => {
+                    return Err(anyhow!(io::Error::new(io::ErrorKind::TimedOut,
+                        "Timed out waiting for the peer"))
+                        .context("No answer to the keepalive ping"));
+                },
+                None => std::thread::sleep(KEEPALIVE_POLL)
+            }
+        }
+    }
+
+    /// Waits for the next key of the source, the terminal by default, or
+    /// until `timeout` has elapsed. The terminal is read on demand, so that
+    /// it is only in raw mode while waiting here. Other sources are read on
+    /// a dedicated thread, shared by all sessions, so that waiting can time
+    /// out. The connections accepted by a server in the meantime are
+    /// checked regularly, as is a shutdown request, which closes the input.
+    fn read_key(&mut self, timeout: Option<std::time::Duration>)
+        -> Result<Option<KeyEvent>> {
+        let deadline = timeout.map(|t| std::time::Instant::now() + t);
+        loop {
+            if SHUTDOWN.load(Ordering::SeqCst) {
+                bail!(InputClosed);
+            }
+            self.check_incoming()?;
+            let left = deadline
+                .map(|d| d.saturating_duration_since(std::time::Instant::now()));
+            let wait = left.map_or(ACCEPT_POLL, |left| left.min(ACCEPT_POLL));
+            let key = match self.keys() {
+                Keys::Term(term) => match term.poll_key(wait) {
+                    Some(key) => key,
+                    None => bail!(InputClosed)
+                },
+                Keys::Source(keys) => match keys.recv_timeout(wait) {
+                    Ok(key) => Some(key),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => bail!(InputClosed)
+                }
+            };
+            if key.is_some() || deadline.is_some_and(|d| std::time::Instant::now() >= d) {
+                return Ok(key);
+            }
+        }
+    }
+
+    fn keys(&mut self) -> &mut Keys {
+        let source = self.config.source.clone();
+        self.keys.get_or_insert_with(|| {
+            let mut source = match source {
+                Some(source) => source,
+                None => return Keys::Term(TermSource::new())
+            };
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                while let Some(key) = source.next_key() {
+                    if tx.send(key).is_err() {
+                        break;
                     }
                 }
+            });
+            Keys::Source(rx)
+        })
+    }
+
+    fn idle_timeout(&self) -> Option<std::time::Duration> {
+        match self.state {
+            TelekeyState::Active => self.config.idle_timeout
+                .map(std::time::Duration::from_secs),
+            _ => None
+        }
+    }
+
+    /// Waits long enough to stay under the rate the peer advertised
+    fn throttle(&mut self) {
+        let max_rate = match &self.remote {
+            Some(remote) if remote.max_rate > 0 => remote.max_rate,
+            _ => return
+        };
+        let interval = std::time::Duration::from_secs(1) / max_rate;
+        let now = std::time::Instant::now();
+        self.throttled = match self.last_sent {
+            Some(last) if now - last < interval => {
+                std::thread::sleep(interval - (now - last));
+                true
+            },
+            _ => false
+        };
+        self.last_sent = Some(std::time::Instant::now());
+    }
+
+    /// Sends a key once the configured transforms have been applied, and
+    /// returns the key actually sent, if any
+    fn send_key<T: TelekeyTransport>(&mut self, tr: &mut T, e: KeyEvent)
+        -> Result<Option<KeyEvent>> {
+        if Self::is_hotkey(&self.hotkeys.paste, &e) {
+            self.paste(tr)?;
+            return Ok(None);
+        }
+        if self.config.clipboard {
+            if Self::is_hotkey(&self.hotkeys.clipboard, &e) {
+                self.push_clipboard(tr)?;
+                return Ok(None);
+            }
+            if Self::is_hotkey(&self.hotkeys.fetch_clipboard, &e) {
+                self.fetch_clipboard(tr)?;
+                return Ok(None);
+            }
+        }
+        let e = match self.config.transforms.iter().try_fold(e, |e, t| t.apply(e)) {
+            Some(e) => e,
+            None => return Ok(None)
+        };
+        self.throttle();
+        self.send_coalesced(tr, &e)?;
+        self.rate.count();
+        self.held.key(&e);
+        self.keyboard.update(&e);
+        if let Some(transcript) = &mut self.transcript {
+            transcript.record(&e).context("Could not write to the transcript")?;
+        }
+        if let Some(history_log) = &mut self.history_log {
+            history_log.record(&e).context("Could not write to the history log")?;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&e).context("Could not write to the macro")?;
+        }
+        Ok(Some(e))
+    }
+
+    fn coalesce_window(&self) -> Option<std::time::Duration> {
+        self.config.coalesce.map(std::time::Duration::from_millis)
+    }
+
+    /// Sends a key, unless it repeats the click sent last within the
+    /// coalescing window: it is then only counted, until the repeats are
+    /// due or another key is sent
+    fn send_coalesced<T: TelekeyTransport>(&mut self, tr: &mut T, e: &KeyEvent) -> Result<()> {
+        self.flush_due_repeats(tr)?;
+        let window = match self.coalesce_window() {
+            Some(window) if e.state == KeyState::CLICK && e.kind != KeyKind::SCROLL => window,
+            _ => {
+                self.flush_repeats(tr)?;
+                return Ok(tr.send_packet(e.clone().into())?);
+            }
+        };
+        let now = std::time::Instant::now();
+        if let Some(r) = &mut self.repeats {
+            if &r.event == e && now - r.last < window && r.count < MAX_REPEAT {
+                if r.count == 0 {
+                    r.since = now;
+                }
+                r.count += 1;
+                r.last = now;
+                return Ok(());
+            }
+        }
+        self.flush_repeats(tr)?;
+        tr.send_packet(e.clone().into())?;
+        self.repeats = Some(Repeats { event: e.clone(), count: 0, since: now, last: now });
+        Ok(())
+    }
+
+    /// Sends the repeats not sent yet as a single key
+    fn flush_repeats<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
+        match self.repeats.take() {
+            Some(r) if r.count > 0 => {
+                tr.send_packet(KeyEvent { repeat: r.count, ..r.event }.into())?;
                 Ok(())
             },
-            TelekeyPacketKind::Ping => {
-                let tm = Utc::now().timestamp_nanos();
-                let mut buf = tm.to_be_bytes().to_vec();
-                buf.reserve(1);
-                tr.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Ping, buf))
-                    .context("Could not respond to ping packet")
+            _ => Ok(())
+        }
+    }
+
+    /// Sends the repeats not sent yet once the coalescing window is over
+    /// since the first of them, telling whether there were any. The key
+    /// may still be repeated afterwards
+    fn flush_due_repeats<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<bool> {
+        let window = match self.coalesce_window() {
+            Some(window) => window,
+            None => return Ok(false)
+        };
+        match &mut self.repeats {
+            Some(r) if r.count > 0 && r.since.elapsed() >= window => {
+                tr.send_packet(KeyEvent { repeat: r.count, ..r.event.clone() }.into())?;
+                r.count = 0;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    /// Time left before the repeats not sent yet are due, if any
+    fn repeats_due_in(&self) -> Option<std::time::Duration> {
+        let window = self.coalesce_window()?;
+        let r = self.repeats.as_ref().filter(|r| r.count > 0)?;
+        Some(window.saturating_sub(r.since.elapsed()))
+    }
+
+    /// Presses again the keys still held down when the previous session
+    /// ended if this one resumes it, forgets them otherwise
+    fn resume_held<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
+        if std::mem::take(&mut self.reconnected) {
+            return self.press_held(tr);
+        }
+        self.held = HeldKeys::default();
+        Ok(())
+    }
+
+    /// Presses again the keys still held down when the previous session
+    /// ended, which its receiver released then, e.g. a modifier held across
+    /// a reconnection
+    fn press_held<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
+        let keys = self.held.snapshot();
+        if !keys.is_empty() {
+            info!("Pressing again {} keys still held down", keys.len());
+        }
+        for e in keys {
+            tr.send_packet(e.clone().into())?;
+            self.keyboard.update(&e);
+        }
+        Ok(())
+    }
+
+    /// Replaces a key typed with the one it is remapped to, if any
+    fn remap(&self, e: KeyEvent) -> KeyEvent {
+        match self.remaps.iter().find(|(from, _)| from.kind == e.kind && from.key == e.key) {
+            Some((_, to)) => KeyEvent {
+                modifiers: e.modifiers | to.modifiers, state: e.state, ..to.clone()
+            },
+            None => e
+        }
+    }
+
+    /// Tells whether `e` is the hotkey `key`, if set
+    fn is_hotkey(key: &Option<KeyEvent>, e: &KeyEvent) -> bool {
+        key.as_ref() == Some(e)
+    }
+
+    /// Sends the local clipboard as text packets. A clipboard that cannot
+    /// be read is reported without ending the session
+    fn paste<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
+        // the keys typed before come first
+        self.flush_repeats(tr)?;
+        let text = match self.clipboard().and_then(|c| c.get_text()) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("Could not read the clipboard: {}", e);
+                return Ok(());
             }
-            k => {
-                println!("{}: Unknown packet {:?}",
-                     style("RUNTIME ERROR").yellow().bold(), k);
-                Ok(())
+        };
+        for chunk in text_chunks(&text, MAX_TEXT_CHUNK) {
+            self.throttle();
+            tr.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Text, chunk.as_bytes().to_vec()))?;
+        }
+        self.record_text(&text)
+    }
+
+    /// The clipboard of this machine, opened on first use
+    fn clipboard(&mut self) -> Result<&mut arboard::Clipboard, arboard::Error> {
+        let clipboard = match self.clipboard.take() {
+            Some(clipboard) => clipboard,
+            None => arboard::Clipboard::new()?
+        };
+        Ok(self.clipboard.insert(clipboard))
+    }
+
+    /// Largest clipboard sent, which fits in a packet once sealed
+    fn clipboard_limit(&self) -> usize {
+        self.config.max_packet_size.saturating_sub(SEAL_OVERHEAD)
+    }
+
+    /// Text of the local clipboard, empty if it cannot be read or does not
+    /// fit in a packet
+    fn read_clipboard(&mut self) -> String {
+        let max = self.clipboard_limit();
+        match self.clipboard().and_then(|c| c.get_text()) {
+            Ok(text) if text.len() <= max => text,
+            Ok(text) => {
+                warn!("The clipboard ({} bytes) exceeds the limit of {} bytes per packet",
+                      text.len(), max);
+                String::new()
+            },
+            Err(e) => {
+                warn!("Could not read the clipboard: {}", e);
+                String::new()
             }
         }
     }
 
-    fn measure_latency<T: TelekeyTransport>(tr: &mut T) -> Result<i64> {
-        let start = Utc::now().timestamp_nanos();
-        tr.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Ping,
-                Vec::with_capacity(1)))?;
+    /// Sets the local clipboard, a failure being reported without ending
+    /// the session. A cold run leaves it untouched
+    fn write_clipboard(&mut self, text: &str) {
+        let n = text.chars().count();
+        if self.config.cold_run {
+            info!("Received a clipboard of {} characters", n);
+            return;
+        }
+        match self.clipboard().and_then(|c| c.set_text(text)) {
+            Ok(()) => info!("Clipboard set to the one of the peer ({} characters)", n),
+            Err(e) => warn!("Could not set the clipboard: {}", e)
+        }
+    }
+
+    /// Sets the clipboard of the peer to the local one
+    fn push_clipboard<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
+        self.flush_repeats(tr)?;
+        let text = self.read_clipboard();
+        // an empty clipboard would ask for the one of the peer
+        if text.is_empty() {
+            return Ok(());
+        }
+        tr.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Clipboard, text.into_bytes()))?;
+        Ok(())
+    }
+
+    /// Sets the local clipboard to the one of the peer, waiting for it
+    fn fetch_clipboard<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
+        if self.clients.is_some() {
+            warn!("The clipboard cannot be fetched from several clients");
+            return Ok(());
+        }
+        tr.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Clipboard, Vec::new()))?;
         let p = tr.recv_packet()?;
         match p.kind() {
-            TelekeyPacketKind::Ping => {
-                let end = Utc::now().timestamp_nanos();
-                let middle = i64::from_be_bytes(p.data().try_into().unwrap());
-                let d1 = middle - start;
-                let d2 = end - middle;
-                Ok((d1 + d2) / 2)
+            TelekeyPacketKind::Clipboard if p.data().is_empty() => {
+                warn!("The peer did not share its clipboard");
             },
-            k => {
-                bail!("Expected ping packet received {:?}", k)
+            TelekeyPacketKind::Clipboard => {
+                let text = std::str::from_utf8(p.data())
+                    .context("Received clipboard is not valid UTF-8")?;
+                self.write_clipboard(text);
+            },
+            k => bail!("Expected clipboard packet received {:?}", k)
+        }
+        Ok(())
+    }
+
+    /// Records a text to the transcript and the history log as one key per
+    /// character
+    fn record_text(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            let e = KeyEvent { kind: KeyKind::CHAR, key: c as u32, ..Default::default() };
+            if let Some(transcript) = &mut self.transcript {
+                transcript.record(&e).context("Could not write to the transcript")?;
+            }
+            if let Some(history_log) = &mut self.history_log {
+                history_log.record(&e).context("Could not write to the history log")?;
             }
         }
+        Ok(())
     }
 
-    fn print_header(&self, peer_addr: Option<SocketAddr>) -> String
-    {
-        let name = style(format!("TeleKey v{} ", self.version))
-            .color256(173).italic();
-        if peer_addr.is_none() {
-            return format!("{}{}", name, style("!! Unkown peer !!").on_red());
-        };
-        let peer_addr = peer_addr.unwrap();
-        let peer = if let Some(remote) = &self.remote {
-            style(format!(" {} ({}) ", peer_addr, remote.hostname))
-        } else {
-            style(format!(" {} ", peer_addr))
-        }.bg(console::Color::Color256(238)).fg(console::Color::Magenta);
-        format!("{}{}", name, peer)
+    /// Reads a key while paused, dropping it unless it is the pause key,
+    /// which resumes the session
+    fn paused_key<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
+        if let Some(key) = self.next_key(tr, None)? {
+            if Self::is_hotkey(&self.hotkeys.pause, &self.remap(key)) {
+                self.state = TelekeyState::Active;
+            }
+        }
+        Ok(())
     }
 
-    fn print_menu(&self, header: &str, latency: &str,
-                  history: Option<&VecDeque<KeyEvent>>) {
-        let state = match self.state {
-            TelekeyState::Idle => style(" IDLE ").on_blue().black(),
-            TelekeyState::Active => style(" ACTIVE ").on_green().black(),
-        };
+    /// Runs the full menu with the transport on a network thread, which
+    /// sends the keys, measures the latency and keeps the session alive, so
+    /// the keys never wait for a measurement. The first error of that thread
+    /// ends the session
+    fn threaded_loop<T: TelekeyTransport + Send>(&mut self, tr: &mut T, header: &str,
+                                                 latency: String) -> Result<()> {
+        let keepalive = self.config.keepalive.map(std::time::Duration::from_secs);
+        let peer = tr.peer_addr().ok();
+        std::thread::scope(|s| {
+            let (commands, received) = mpsc::channel();
+            let (measured, latencies) = mpsc::channel();
+            let (replies, packets) = mpsc::channel();
+            let net = s.spawn(move || Self::net_loop(tr, received, measured, replies,
+                                                     keepalive));
+            let mut handle = NetHandle { commands, packets, peer };
+            let r = self.render_loop(&mut handle, &latencies, header, latency,
+                                     || net.is_finished());
+            drop(handle);
+            let net_r = net.join()
+                .unwrap_or_else(|_| Err(anyhow!("The network thread panicked")));
+            net_r.and(r)
+        })
+    }
 
-        println!("{}{}{}", header, state, latency);
-        if let Some(hist) = history {
-            for l in hist {
-                println!("{}", l);
+    /// Body of the network thread: sends the packets it is handed, measures
+    /// the latency or receives a packet when asked and pings the peer after
+    /// `keepalive` without any packet. Returns once the handle is dropped or
+    /// a measurement or a read failed
+    fn net_loop<T: TelekeyTransport>(tr: &mut T, commands: mpsc::Receiver<NetCommand>,
+                                     measured: mpsc::Sender<Result<Latency>>,
+                                     replies: mpsc::Sender<io::Result<TelekeyPacket>>,
+                                     keepalive: Option<std::time::Duration>) -> Result<()> {
+        loop {
+            let command = match keepalive {
+                Some(interval) => match commands.recv_timeout(interval) {
+                    Ok(command) => command,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        Self::keepalive(tr)?;
+                        continue;
+                    },
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(())
+                },
+                None => match commands.recv() {
+                    Ok(command) => command,
+                    Err(_) => return Ok(())
+                }
+            };
+            match command {
+                NetCommand::Send(p) => tr.send_packet(p)?,
+                NetCommand::Measure => {
+                    let latency = Self::measure_latency(tr);
+                    let failed = latency.is_err();
+                    if measured.send(latency).is_err() || failed {
+                        return Ok(());
+                    }
+                },
+                NetCommand::Recv => {
+                    let p = tr.recv_packet();
+                    let failed = p.is_err();
+                    if replies.send(p).is_err() || failed {
+                        return Ok(());
+                    }
+                }
             }
         }
-        println!("{}", style("--> Press any key <--").color256(246));
     }
 
-    fn wait_for_input<T: TelekeyTransport>(&mut self, tr: &mut T) -> Result<()> {
-        let header = self.print_header(tr.peer_addr().ok());
+    /// Menu of the threaded loop, only drawn again when something shown
+    /// changed: the state, the keys sent, the latency or the clients
+    fn render_loop(&mut self, tr: &mut NetHandle,
+                   latencies: &mpsc::Receiver<Result<Latency>>, header: &str,
+                   mut latency: String, net_done: impl Fn() -> bool) -> Result<()> {
         let term = Term::stdout();
+        let size = self.config.history_size;
+        let mut history = VecDeque::with_capacity(size);
+        let mut l = 0;
+        let mut last_key = std::time::Instant::now();
+        let mut clients = self.clients.as_ref().map(Broadcast::len);
+        // tenths of keys per second, as shown
+        let mut rate = 0;
+        let mut screen = Screen::default();
+        let mut dirty = true;
+        loop {
+            if dirty && !self.config.quiet {
+                screen.draw(&term, self.menu_lines(header, &latency, Some(&history)))?;
+                dirty = false;
+            }
+            if net_done() {
+                return Ok(());
+            }
+            self.flush_due_repeats(tr)?;
+            if let Ok(measured) = latencies.try_recv() {
+                latency = match self.render_latency(measured)? {
+                    Some(latency) => latency,
+                    None => return Ok(())
+                };
+                dirty = true;
+            }
+            let now_clients = self.clients.as_ref().map(Broadcast::len);
+            dirty |= now_clients != clients;
+            clients = now_clients;
+            let now_rate = (self.rate.per_second() * 10.0).round() as u64;
+            dirty |= now_rate != rate;
+            rate = now_rate;
 
-        let nano = Self::measure_latency(tr)?;
-        let mut latency = if let Ok(d) = Duration::nanoseconds(nano).to_std() {
-            style(format!(" {:?} ", d)).yellow()
-        } else {
-            style(" ??ms ".to_string()).yellow()
-        }.to_string();
-
-        if self.config.update_screen {
-            term.clear_screen()?;
-            self.print_menu(&header, &latency, None);
-
-            let mut l = 0;
-            let mut history = VecDeque::with_capacity(20);
-            loop {
-                match self.state {
-                    TelekeyState::Idle => {
-                        if let Ok(_key) = term.read_key() {
+            let before = self.state;
+            match self.read_key(Some(REDRAW_POLL))? {
+                Some(key) => {
+                    last_key = std::time::Instant::now();
+                    let e = self.remap(key);
+                    let pause = Self::is_hotkey(&self.hotkeys.pause, &e);
+                    match self.state {
+                        TelekeyState::Idle | TelekeyState::Activating(_) => {
+                            self.activate(&term, header, &latency, Some(&history))?;
+                            screen.clear();
+                        },
+                        TelekeyState::Paused if pause => self.state = TelekeyState::Active,
+                        TelekeyState::Paused => (),
+                        TelekeyState::Active | TelekeyState::Inactive if pause => {
+                            self.state = TelekeyState::Paused;
+                        },
+                        TelekeyState::Active | TelekeyState::Inactive => {
                             self.state = TelekeyState::Active;
-                        }
-                    },
-                    TelekeyState::Active => {
-                        if let Ok(key) = term.read_key() {
-                            let e: KeyEvent = key.into();
-                            let p: TelekeyPacket = e.clone().into();
-                            tr.send_packet(p)?;
-                            if history.len() == 20 {
-                                history.pop_front();
+                            if let Some(e) = self.send_key(tr, e)? {
+                                if size > 0 {
+                                    if history.len() == size {
+                                        history.pop_front();
+                                    }
+                                    history.push_back(e);
+                                }
+                                dirty = true;
                             }
-                            history.push_back(e);
                         }
                     }
+                    if self.latency_due(&mut l) {
+                        tr.measure()?;
+                    }
+                },
+                None => {
+                    let idle = self.idle_timeout().is_some_and(|t| last_key.elapsed() >= t);
+                    if idle {
+                        self.state = TelekeyState::Inactive;
+                    }
                 }
+            }
+            dirty |= before != self.state;
+        }
+    }
 
-                if let Some(period) = self.config.refresh_latency {
-                    if l == period { // after x reads, measure latency
-                        let nano = Self::measure_latency(tr)?;
-                        latency = if let Ok(d) = Duration::nanoseconds(nano).to_std() {
-                            style(format!(" {:?} ", d)).yellow()
-                        } else {
-                            style(" ??ms ".to_string()).yellow()
-                        }.to_string();
-                        l = 0;
-                    } else {
-                        l += 1;
-                    }
+    /// Counts a read and tells whether the latency should be measured again,
+    /// which happens every `refresh_latency` reads
+    fn latency_due(&self, reads: &mut usize) -> bool {
+        match self.config.refresh_latency {
+            Some(period) => {
+                *reads += 1;
+                if *reads >= period {
+                    *reads = 0;
+                    true
+                } else {
+                    false
                 }
+            },
+            None => false
+        }
+    }
 
-                term.clear_screen()?;
-                self.print_menu(&header, &latency, Some(&history));
-            }
+    /// Runs the session until the input closes, e.g. on Ctrl+C, in which
+    /// case the peer is told before the connection is closed
+    fn wait_for_input<T: TelekeyTransport + Send>(&mut self, tr: &mut T) -> Result<()> {
+        let r = self.input_loop(tr);
+        if matches!(&r, Err(e) if e.is::<InputClosed>()) {
+            self.flush_repeats(tr).ok();
+            tr.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Disconnect, Vec::new())).ok();
+            tr.shutdown().ok();
+        }
+        r
+    }
+
+    fn input_loop<T: TelekeyTransport + Send>(&mut self, tr: &mut T) -> Result<()> {
+        let header = self.print_header(tr.peer_addr().ok());
+        self.latencies.clear();
+        self.rtts.clear();
+        self.keyboard = KeyboardState::default();
+        self.rate = KeyRate::default();
+        self.resume_held(tr)?;
+        let term = Term::stdout();
+
+        let mut latency = match self.refresh_latency(tr)? {
+            Some(latency) => latency,
+            None => return Ok(())
+        };
+
+        if self.config.update_screen {
+            self.threaded_loop(tr, &header, latency)
         } else {
             self.print_menu(&header, &latency, None);
 
             let mut l = 0;
             loop {
                 match self.state {
-                    TelekeyState::Idle => {
-                        if let Ok(_key) = term.read_key() {
-                            self.state = TelekeyState::Active;
-                            term.clear_last_lines(2)?;
-                            self.print_menu(&header, &latency, None);
+                    TelekeyState::Idle | TelekeyState::Activating(_) => {
+                        if let Some(_key) = self.next_key(tr, None)? {
+                            self.activate(&term, &header, &latency, None)?;
+                            self.redraw_menu(&term, &header, &latency)?;
+                        }
+                    },
+                    TelekeyState::Paused => {
+                        self.paused_key(tr)?;
+                        if self.state != TelekeyState::Paused {
+                            self.redraw_menu(&term, &header, &latency)?;
                         }
                     },
-                    TelekeyState::Active => {
-                        if let Ok(key) = term.read_key() {
-                            let e: KeyEvent = key.into();
-                            let e: TelekeyPacket = e.into();
-                            tr.send_packet(e)?;
+                    TelekeyState::Active | TelekeyState::Inactive => {
+                        // wakes up for the repeats due as well
+                        let timeout = match (self.idle_timeout(), self.repeats_due_in()) {
+                            (Some(idle), Some(due)) => Some(idle.min(due)),
+                            (idle, due) => idle.or(due)
+                        };
+                        let before = self.state;
+                        if let Some(key) = self.next_key(tr, timeout)? {
+                            let e = self.remap(key);
+                            if Self::is_hotkey(&self.hotkeys.pause, &e) {
+                                self.state = TelekeyState::Paused;
+                            } else {
+                                self.state = TelekeyState::Active;
+                                self.send_key(tr, e)?;
+                            }
+                        } else if !self.flush_due_repeats(tr)? {
+                            self.state = TelekeyState::Inactive;
+                        }
+                        if before != self.state {
+                            self.redraw_menu(&term, &header, &latency)?;
                         }
                     }
                 }
 
-                if let Some(period) = self.config.refresh_latency {
-                    if l == period { // after x reads, measure latency
-                        let nano = Self::measure_latency(tr)?;
-                        latency = if let Ok(d) = Duration::nanoseconds(nano).to_std() {
-                            style(format!(" {:?} ", d)).yellow()
-                        } else {
-                            style(" ??ms ".to_string()).yellow()
-                        }.to_string();
-                        term.clear_last_lines(2)?;
-                        self.print_menu(&header, &latency, None);
-                        l = 0;
-                    } else {
-                        l += 1;
-                    }
+                if self.latency_due(&mut l) {
+                    latency = match self.refresh_latency(tr)? {
+                        Some(latency) => latency,
+                        None => return Ok(())
+                    };
+                    self.redraw_menu(&term, &header, &latency)?;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn config() -> TelekeyConfig {
+        TelekeyConfig { cold_run: true, quiet: true, ..Default::default() }
+    }
+
+    type Session = Result<Box<dyn TelekeyTransport + Send>>;
+
+    /// Runs the handshake of a server knowing `server_token` with a client
+    /// giving `client_token`, over memory
+    fn handshake(server_token: [u8; TOKEN_SIZE], client_token: [u8; TOKEN_SIZE])
+        -> (Session, Session) {
+        let (a, b) = MemoryTransport::pair();
+        let server = std::thread::spawn(move || {
+            let skey = SecretKey::from_slice(&server_token).unwrap();
+            Telekey::new(TelekeyMode::Server, config()).open_session(a, skey)
+        });
+        let skey = SecretKey::from_slice(&client_token).unwrap();
+        let client = Telekey::new(TelekeyMode::Client, config()).open_session(b, skey);
+        (server.join().unwrap(), client)
+    }
+
+    fn text(s: &str) -> TelekeyPacket {
+        TelekeyPacket::raw(TelekeyPacketKind::Text, s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn console_keys_display_and_emulate() {
+        use console::Key;
+        let keys = [
+            (Key::Enter, "\\n", Some(enigo::Key::Return)),
+            (Key::ArrowUp, "[A^]", Some(enigo::Key::UpArrow)),
+            (Key::ArrowDown, "[Av]", Some(enigo::Key::DownArrow)),
+            (Key::ArrowLeft, "[A<]", Some(enigo::Key::LeftArrow)),
+            (Key::ArrowRight, "[A>]", Some(enigo::Key::RightArrow)),
+            (Key::Escape, "[ESC]", Some(enigo::Key::Escape)),
+            (Key::Backspace, "[BACKSPACE]", Some(enigo::Key::Backspace)),
+            (Key::Home, "[HOM]", Some(enigo::Key::Home)),
+            (Key::End, "[END]", Some(enigo::Key::End)),
+            (Key::Tab, "\\t", Some(enigo::Key::Tab)),
+            (Key::BackTab, "[SHIFT]+\\t", Some(enigo::Key::Tab)),
+            (Key::Del, "[DEL]", Some(enigo::Key::Delete)),
+            (Key::Insert, "[INSERT]", None),
+            (Key::PageUp, "[P^]", Some(enigo::Key::PageUp)),
+            (Key::PageDown, "[Pv]", Some(enigo::Key::PageDown)),
+            (Key::Shift, "[SHIFT]", Some(enigo::Key::Shift)),
+            (Key::Char('a'), "a", Some(enigo::Key::Layout('a'))),
+            (Key::Char('\x03'), "[CTRL]+c", Some(enigo::Key::Layout('c'))),
+            (Key::UnknownEscSeq(vec!['x']), "[ALT]+x", Some(enigo::Key::Layout('x'))),
+            (Key::UnknownEscSeq(vec!['O', 'P']), "[F1]", Some(enigo::Key::F1)),
+            (Key::UnknownEscSeq(vec!['[', '2', '4', '~']), "[F12]", Some(enigo::Key::F12)),
+            (Key::Unknown, "[?]", None),
+        ];
+        for (key, shown, emulated) in keys {
+            let e = KeyEvent::from(key.clone());
+            assert_eq!(e.to_string(), shown, "{:?}", key);
+            let r: Result<enigo::Key, String> = (&e).into();
+            match emulated {
+                Some(k) => assert_eq!(r, Ok(k), "{:?}", key),
+                None => assert!(r.is_err(), "{:?}", key)
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_scalar_neither_panics_nor_emulates() {
+        let e = KeyEvent { kind: KeyKind::CHAR, key: 0xD800, ..Default::default() };
+        assert_eq!(e.to_string(), char::REPLACEMENT_CHARACTER.to_string());
+        let r: Result<enigo::Key, String> = (&e).into();
+        assert_eq!(r, Err("Invalid character U+D800".to_string()));
+    }
+
+    #[test]
+    fn malformed_ping_is_an_error() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        let peer = std::thread::spawn(move || {
+            b.recv_packet().unwrap();
+            b.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Ping, vec![1, 2, 3])).unwrap();
+        });
+        assert!(Telekey::ping_once(&mut a).is_err());
+        peer.join().unwrap();
+
+        // and is not echoed by the receiver either
+        let mut client = Telekey::new(TelekeyMode::Client, config());
+        let (mut a, mut b) = MemoryTransport::pair();
+        let p = TelekeyPacket::raw(TelekeyPacketKind::Ping, vec![1, 2, 3]);
+        assert!(client.handle_packet(&mut a, p).is_err());
+        assert!(b.try_recv_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn secure_handshake_derives_matching_keys() {
+        let (server, client) = handshake([7; TOKEN_SIZE], [7; TOKEN_SIZE]);
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        // each end opens what the other sealed, in both directions
+        server.send_packet(text("hello")).unwrap();
+        assert_eq!(client.recv_packet().unwrap().data(), b"hello");
+        client.send_packet(text("world")).unwrap();
+        assert_eq!(server.recv_packet().unwrap().data(), b"world");
+    }
+
+    #[test]
+    fn secure_handshake_rejects_wrong_token() {
+        let (server, client) = handshake([7; TOKEN_SIZE], [8; TOKEN_SIZE]);
+        let e = server.err().unwrap();
+        assert!(matches!(e.downcast_ref::<Rejection>(), Some(Rejection::InvalidToken)));
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn unsecure_handshake_rejects_wrong_token() {
+        let config = || TelekeyConfig { secure: false, ..config() };
+        let (a, b) = MemoryTransport::pair();
+        let server = std::thread::spawn(move || {
+            let skey = SecretKey::from_slice(&[7; TOKEN_SIZE]).unwrap();
+            Telekey::new(TelekeyMode::Server, config()).open_session(a, skey)
+        });
+        let skey = SecretKey::from_slice(&[8; TOKEN_SIZE]).unwrap();
+        let client = Telekey::new(TelekeyMode::Client, config()).open_session(b, skey);
+        let e = server.join().unwrap().err().unwrap();
+        assert!(matches!(e.downcast_ref::<Rejection>(), Some(Rejection::InvalidToken)));
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn ping_is_echoed_as_is() {
+        // Regression: the receiver used to answer with its own timestamp from
+        // `timestamp_nanos`, which panics outside 1677-2262. Any token, even
+        // one no clock would produce, is now echoed untouched.
+        let mut client = Telekey::new(TelekeyMode::Client, config());
+        let (mut a, mut b) = MemoryTransport::pair();
+        client.handle_packet(&mut a, ping_packet(u64::MAX)).unwrap();
+        let echoed = b.try_recv_packet().unwrap().unwrap();
+        assert!(matches!(echoed.kind(), TelekeyPacketKind::Ping));
+        assert_eq!(echoed.data(), u64::MAX.to_be_bytes());
+    }
+
+    #[test]
+    fn unshared_clipboard_is_neither_sent_nor_set() {
+        let mut client = Telekey::new(TelekeyMode::Client, config());
+        client.remote = Some(TelekeyRemote {
+            hostname: "server".to_string(), version: 0, mode: TelekeyMode::Server,
+            max_rate: 0, compress: false, absolute_mouse: false
+        });
+        let (mut a, mut b) = MemoryTransport::pair();
+        // a fetch is answered, but with an empty clipboard
+        let fetch = TelekeyPacket::raw(TelekeyPacketKind::Clipboard, Vec::new());
+        client.handle_packet(&mut a, fetch).unwrap();
+        let answer = b.try_recv_packet().unwrap().unwrap();
+        assert!(matches!(answer.kind(), TelekeyPacketKind::Clipboard));
+        assert!(answer.data().is_empty());
+        // and the clipboard sent is skipped
+        let text = TelekeyPacket::raw(TelekeyPacketKind::Clipboard, b"secret".to_vec());
+        client.handle_packet(&mut a, text).unwrap();
+        assert!(client.clipboard.is_none());
+        assert!(b.try_recv_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn key_rate_forgets_the_seconds_gone_by() {
+        let mut rate = KeyRate::default();
+        assert_eq!(rate.per_second(), 0.0);
+        for _ in 0..10 {
+            rate.count();
+        }
+        assert_eq!(rate.per_second(), 10.0);
+
+        // 7 seconds in, only the keys of seconds 3 and 4 are in the window
+        let now = std::time::Instant::now();
+        let mut rate = KeyRate {
+            slots: [(0, 50), (1, 50), (2, 10), (3, 10), (4, 10)],
+            start: Some(now - std::time::Duration::from_secs(7))
+        };
+        assert_eq!(rate.per_second(), 20.0 / RATE_WINDOW as f64);
+        // the slot of second 2 is reused for second 7
+        rate.count();
+        assert_eq!(rate.slots[2], (7, 1));
+        assert_eq!(rate.per_second(), 21.0 / RATE_WINDOW as f64);
+    }
+
+    #[test]
+    fn ping_measures_the_echo() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        let peer = std::thread::spawn(move || {
+            let p = b.recv_packet().unwrap();
+            b.send_packet(p).unwrap();
+        });
+        Telekey::ping(&mut a).unwrap();
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn ping_fails_without_its_echo() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        b.send_packet(ping_packet(u64::MAX)).unwrap();
+        assert!(Telekey::ping(&mut a).is_err());
+        b.send_packet(text("a")).unwrap();
+        assert!(Telekey::ping(&mut a).is_err());
+    }
+
+    /// Records the keys delivered
+    #[derive(Clone, Default)]
+    struct Delivered(Arc<Mutex<Vec<KeyEvent>>>);
+
+    impl KeySink for Delivered {
+        fn deliver(&mut self, event: &KeyEvent) -> Result<()> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn held_keys_are_forgotten_once_released() {
+        let delivered = Delivered::default();
+        let mut client_config = config();
+        client_config.set_sink(delivered.clone());
+        let mut client = Telekey::new(TelekeyMode::Client, client_config);
+        for spec in ["SHIFT", "CHAR a"] {
+            let e = KeyEvent { state: KeyState::DOWN, ..spec.parse::<KeyEvent>().unwrap() };
+            client.emulate(&e).unwrap();
+        }
+        assert_eq!(client.held.keys.len(), 2);
+        client.release_held(None).unwrap();
+        assert!(client.held.keys.is_empty());
+        {
+            let delivered = delivered.0.lock().unwrap();
+            let released: Vec<_> = delivered[2..].iter().map(|e| (e.kind, e.state)).collect();
+            assert_eq!(released, [(KeyKind::CHAR, KeyState::UP), (KeyKind::SHIFT, KeyState::UP)]);
+        }
+        // nothing is released twice
+        client.release_held(None).unwrap();
+        assert_eq!(delivered.0.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn repeats_are_sent_as_one_key() {
+        let mut server_config = config();
+        server_config.set_coalesce(Some(60_000));
+        let mut server = Telekey::new(TelekeyMode::Server, server_config);
+        let (mut a, mut b) = MemoryTransport::pair();
+        let a_key: KeyEvent = "CHAR a".parse().unwrap();
+        let b_key: KeyEvent = "CHAR b".parse().unwrap();
+        for e in [&a_key, &a_key, &a_key, &b_key] {
+            server.send_coalesced(&mut a, e).unwrap();
+        }
+        let mut sent = Vec::new();
+        while let Some(p) = b.try_recv_packet().unwrap() {
+            let e: KeyEvent = deserialize_from_slice(p.data()).unwrap();
+            sent.push((e.key, e.repeat));
+        }
+        // the first click leaves right away, its repeats before the next key
+        assert_eq!(sent, [(a_key.key, 0), (a_key.key, 2), (b_key.key, 0)]);
+    }
+
+    #[test]
+    fn coalesced_key_is_emulated_as_many_times() {
+        let delivered = Delivered::default();
+        let mut client_config = config();
+        client_config.set_sink(delivered.clone());
+        let mut client = Telekey::new(TelekeyMode::Client, client_config);
+        client.remote = Some(TelekeyRemote {
+            hostname: "server".to_string(), version: 0, mode: TelekeyMode::Server,
+            max_rate: 0, compress: false, absolute_mouse: false
+        });
+        let (mut a, _b) = MemoryTransport::pair();
+        for repeat in [3, MAX_REPEAT + 1] {
+            let e = KeyEvent { repeat, .."CHAR a".parse().unwrap() };
+            client.handle_packet(&mut a, e.into()).unwrap();
+        }
+        assert_eq!(delivered.0.lock().unwrap().len(), 3 + MAX_REPEAT as usize);
+    }
+
+    #[test]
+    fn token_is_parsed_with_or_without_padding() {
+        let bytes = [7u8; TOKEN_SIZE];
+        let padded = base64::encode(bytes);
+        assert!(padded.ends_with('='));
+        for inp in [padded.clone(), padded.trim_end_matches('=').to_string(), format!(" {}\n", padded)] {
+            let key = Telekey::parse_token(&inp).unwrap();
+            assert_eq!(key.unprotected_as_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn token_of_the_wrong_size_is_rejected() {
+        for len in [TOKEN_SIZE - 1, TOKEN_SIZE + 1] {
+            let token = base64::encode(vec![7u8; len]);
+            assert!(Telekey::parse_token(&token).is_err());
+            assert!(Telekey::parse_token(token.trim_end_matches('=')).is_err());
+        }
+        assert!(Telekey::parse_token("").is_err());
+        assert!(Telekey::parse_token("not base64!").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn every_key_kind_round_trips_through_json() {
+        for i in (0..=34i32).filter(|&i| i != 12) {
+            let kind = KeyKind::from(i);
+            assert!(i == 0 || kind != KeyKind::UNKNOWN, "no kind {}", i);
+            let name = serde_json::to_string(&kind).unwrap();
+            assert_eq!(name, format!("\"{:?}\"", kind));
+            assert_eq!(serde_json::from_str::<KeyKind>(&name).unwrap(), kind);
+            for state in [KeyState::CLICK, KeyState::DOWN, KeyState::UP] {
+                let e = KeyEvent {
+                    kind, key: 'a' as u32, modifiers: modifiers::CTRL, state,
+                    ..Default::default()
+                };
+                let json = serde_json::to_string(&e).unwrap();
+                assert_eq!(serde_json::from_str::<KeyEvent>(&json).unwrap(), e, "{}", json);
+            }
+        }
+        assert!(serde_json::from_str::<KeyKind>("\"NOPE\"").is_err());
+    }
+
+    #[test]
+    fn cold_run_never_emulates() {
+        assert_eq!(LocalSink::of(&config()), LocalSink::Print(ColdFormat::Text));
+        let hot = TelekeyConfig { cold_run: false, ..config() };
+        assert_eq!(LocalSink::of(&hot), LocalSink::Emulate);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn json_cold_run_prints() {
+        let mut cold = config();
+        cold.set_cold_format(ColdFormat::Json);
+        assert_eq!(LocalSink::of(&cold), LocalSink::Print(ColdFormat::Json));
+    }
+
+    fn echo(nonce: &[u8]) -> TelekeyPacket {
+        HandshakeRequest { nonce: Cow::Borrowed(nonce), ..Default::default() }.into()
+    }
+
+    fn is_stale(r: Result<()>) -> bool {
+        matches!(r, Err(e) if matches!(e.downcast_ref::<Rejection>(),
+                                       Some(Rejection::StaleHandshake)))
+    }
+
+    #[test]
+    fn nonce_echoed_is_accepted() {
+        let (mut server, mut client) = crate::transport::tests::secure_pair();
+        client.send_packet(echo(&[1; NONCE_SIZE])).unwrap();
+        Telekey::check_nonce(&mut server, &[1; NONCE_SIZE]).unwrap();
+    }
+
+    #[test]
+    fn missing_nonce_is_stale() {
+        let (mut server, mut client) = crate::transport::tests::secure_pair();
+        client.send_packet(echo(&[])).unwrap();
+        assert!(is_stale(Telekey::check_nonce(&mut server, &[1; NONCE_SIZE])));
+
+        let (mut server, mut client) = crate::transport::tests::secure_pair();
+        client.send_packet(ping_packet(1)).unwrap();
+        assert!(is_stale(Telekey::check_nonce(&mut server, &[1; NONCE_SIZE])));
+    }
+
+    #[test]
+    fn wrong_nonce_is_stale() {
+        let (mut server, mut client) = crate::transport::tests::secure_pair();
+        client.send_packet(echo(&[2; NONCE_SIZE])).unwrap();
+        assert!(is_stale(Telekey::check_nonce(&mut server, &[1; NONCE_SIZE])));
+    }
+
+    #[test]
+    fn blank_token_is_reported_as_missing() {
+        for inp in ["", "\n", "  \t\n", "=="] {
+            let e = Telekey::parse_token(inp).unwrap_err();
+            assert_eq!(e.to_string(), "No token provided");
+        }
+    }
+
+    #[test]
+    fn client_adopts_the_session_id_of_the_server() {
+        Telekey::start_session().unwrap();
+        let own = Telekey::session_id().unwrap();
+        assert_eq!(own.len(), 2 * SESSION_ID_SIZE);
+        assert!(own.chars().all(|c| c.is_ascii_hexdigit()));
+        // servers predating session IDs send none
+        Telekey::join_session(&HandshakeResponse::default());
+        assert_eq!(Telekey::session_id().as_ref(), Some(&own));
+        let msg = HandshakeResponse { session: Cow::Borrowed("0badcafe"), ..Default::default() };
+        Telekey::join_session(&msg);
+        assert_eq!(Telekey::session_id().as_deref(), Some("0badcafe"));
+        // other threads are in no session
+        assert_eq!(std::thread::spawn(Telekey::session_id).join().unwrap(), None);
+    }
+
+    #[test]
+    fn refused_connection_fails_before_the_token() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        for timeout in [Some(5), None] {
+            let mut client_config = config();
+            client_config.set_connect_timeout(timeout);
+            let mut client = Telekey::new(TelekeyMode::Client, client_config);
+            let e = client.client_session(&[addr], &mut None).unwrap_err();
+            assert!(e.to_string().starts_with("Couldn't connect to server: "), "{}", e);
+        }
+    }
+
+    #[test]
+    fn first_server_accepting_is_the_peer() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let mut client = Telekey::new(TelekeyMode::Client, config());
+        client.connect_any(&[closed, open]).unwrap();
+        assert_eq!(client.peer, Some(open));
+
+        let e = client.connect_any(&[closed, closed]).unwrap_err().to_string();
+        assert!(e.starts_with("Couldn't connect to any of the 2 servers:"), "{}", e);
+        assert_eq!(e.matches(&closed.to_string()).count(), 2);
+    }
+
+    #[test]
+    fn keepalive_checks_echoed_token() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        let peer = std::thread::spawn(move || {
+            let p = b.recv_packet().unwrap();
+            b.send_packet(p).unwrap();
+            b.recv_packet().unwrap();
+            b.send_packet(ping_packet(u64::MAX)).unwrap();
+        });
+        Telekey::keepalive(&mut a).unwrap();
+        assert!(Telekey::keepalive(&mut a).is_err());
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn hotkeys_are_opt_in() {
+        let hotkeys = Hotkeys::of(&TelekeyConfig::default());
+        assert!(hotkeys.paste.is_none() && hotkeys.pause.is_none());
+
+        let mut config = TelekeyConfig::default();
+        config.set_pause_key(Some("ALT+CHAR p".to_string()));
+        let hotkeys = Hotkeys::of(&config);
+        let e: KeyEvent = "ALT+CHAR p".parse().unwrap();
+        assert!(Telekey::is_hotkey(&hotkeys.pause, &e));
+        assert!(!Telekey::is_hotkey(&hotkeys.paste, &e));
+    }
+
+    #[test]
+    fn handshake_threads_share_reject_log() {
+        let path = std::env::temp_dir()
+            .join(format!("telekey-reject-{}.log", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let log = Some(Arc::new(Mutex::new(BufWriter::new(file))));
+        let threads: Vec<_> = (0..2).map(|_| {
+            let log = log.clone();
+            std::thread::spawn(move || {
+                let mut config = config();
+                config.allow("10.0.0.0/8".parse().unwrap());
+                let mut telekey = Telekey::new(TelekeyMode::Server, config);
+                telekey.reject_log = log;
+                assert!(!telekey.check_allowed(Some(([192, 168, 1, 2], 1234).into())));
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(written.lines().filter(|l| l.ends_with("not in the allowlist")).count(), 2);
+    }
+
+    #[test]
+    fn silent_peer_is_a_handshake_timeout() {
+        let (a, _b) = MemoryTransport::pair();
+        let mut config = config();
+        config.set_read_timeout(Some(1));
+        let skey = SecretKey::from_slice(&[7; TOKEN_SIZE]).unwrap();
+        let e = Telekey::new(TelekeyMode::Server, config).open_session(a, skey).err().unwrap();
+        assert!(matches!(e.downcast_ref::<Rejection>(), Some(Rejection::HandshakeTimeout)));
+        assert_eq!(e.downcast_ref::<Rejection>().unwrap().to_string(), "handshake timeout");
+    }
+
+    #[test]
+    fn clipboard_limit_fits_a_sealed_packet() {
+        let mut config = config();
+        config.set_max_packet_size(256);
+        let max = Telekey::new(TelekeyMode::Client, config).clipboard_limit();
+        let clipboard = |n| TelekeyPacket::raw(TelekeyPacketKind::Clipboard, vec![b'a'; n]);
+
+        let (mut a, mut b) = crate::transport::tests::secure_pair();
+        b.set_max_packet_size(256);
+        a.send_packet(clipboard(max)).unwrap();
+        assert_eq!(b.recv_packet().unwrap().data().len(), max);
+        a.send_packet(clipboard(max + 1)).unwrap();
+        assert!(b.recv_packet().is_err());
+    }
+
+    fn key_event(p: TelekeyPacket) -> KeyEvent {
+        assert!(matches!(p.kind(), TelekeyPacketKind::KeyEvent));
+        deserialize_from_slice::<KeyEvent>(p.data()).unwrap()
+    }
+
+    #[test]
+    fn repeats_are_coalesced_and_flushed_before_paste() {
+        let mut server_config = config();
+        server_config.set_coalesce(Some(60_000));
+        let mut server = Telekey::new(TelekeyMode::Server, server_config);
+        let (mut tx, mut rx) = MemoryTransport::pair();
+        let e: KeyEvent = "CHAR a".parse().unwrap();
+        for _ in 0..3 {
+            server.send_key(&mut tx, e.clone()).unwrap();
+        }
+        // the first press goes out right away, the repeats wait
+        let first = key_event(rx.recv_packet().unwrap());
+        assert_eq!(first.repeat, 0);
+        assert!(rx.try_recv_packet().unwrap().is_none());
+        server.paste(&mut tx).unwrap();
+        let repeats = key_event(rx.recv_packet().unwrap());
+        assert_eq!((repeats.key, repeats.repeat), (e.key, 2));
+
+        let delivered = Delivered::default();
+        let mut client_config = config();
+        client_config.set_sink(delivered.clone());
+        let mut client = Telekey::new(TelekeyMode::Client, client_config);
+        client.remote = Some(HandshakeRequest::default().into());
+        client.handle_packet(&mut rx, first.into()).unwrap();
+        client.handle_packet(&mut rx, repeats.into()).unwrap();
+        assert_eq!(delivered.0.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn keyboard_state_tracks_every_modifier() {
+        let mut keyboard = KeyboardState::default();
+        let key = |kind, modifiers, state| KeyEvent {
+            kind, key: 'c' as u32, modifiers, state, ..Default::default()
+        };
+        keyboard.update(&key(KeyKind::CHAR, modifiers::CTRL, KeyState::CLICK));
+        assert_eq!(keyboard.to_string(), "CTRL");
+        keyboard.update(&key(KeyKind::CHAR, modifiers::ALT, KeyState::DOWN));
+        keyboard.update(&key(KeyKind::SHIFT, 0, KeyState::DOWN));
+        assert_eq!(keyboard.to_string(), "SHIFT ALT");
+        keyboard.update(&key(KeyKind::CHAR, modifiers::ALT, KeyState::UP));
+        keyboard.update(&key(KeyKind::CAPS_LOCK, 0, KeyState::CLICK));
+        assert_eq!(keyboard.to_string(), "SHIFT CAPS");
+        keyboard.update(&key(KeyKind::SHIFT, 0, KeyState::UP));
+        assert_eq!(keyboard.to_string(), "CAPS");
+    }
+
+    #[test]
+    fn held_modifier_is_pressed_again_on_reconnect() {
+        let mut server = Telekey::new(TelekeyMode::Server, config());
+        let shift = |state| KeyEvent { kind: KeyKind::SHIFT, state, ..Default::default() };
+        let (mut tx, _rx) = MemoryTransport::pair();
+        server.send_key(&mut tx, shift(KeyState::DOWN)).unwrap();
+        server.send_key(&mut tx, "CHAR a".parse().unwrap()).unwrap();
+
+        // the session drops and the client connects again
+        let (mut tx, mut rx) = MemoryTransport::pair();
+        server.keyboard = KeyboardState::default();
+        server.reconnected = true;
+        server.resume_held(&mut tx).unwrap();
+        assert_eq!(key_event(rx.recv_packet().unwrap()), shift(KeyState::DOWN));
+        assert!(rx.try_recv_packet().unwrap().is_none());
+        assert_eq!(server.keyboard.to_string(), "SHIFT");
+
+        server.send_key(&mut tx, shift(KeyState::UP)).unwrap();
+        assert!(server.held.snapshot().is_empty());
+    }
+
+    #[test]
+    fn held_modifier_is_forgotten_for_a_new_peer() {
+        let mut server = Telekey::new(TelekeyMode::Server, config());
+        let shift = KeyEvent { kind: KeyKind::SHIFT, state: KeyState::DOWN, ..Default::default() };
+        let (mut tx, _rx) = MemoryTransport::pair();
+        server.send_key(&mut tx, shift).unwrap();
+
+        let (mut tx, mut rx) = MemoryTransport::pair();
+        server.resume_held(&mut tx).unwrap();
+        assert!(rx.try_recv_packet().unwrap().is_none());
+        assert!(server.held.snapshot().is_empty());
+    }
+
+    #[test]
+    fn caps_lock_is_remapped_to_escape() {
+        let mut server_config = config();
+        server_config.remap("CAPS_LOCK".to_string(), "ESC".to_string());
+        let server = Telekey::new(TelekeyMode::Server, server_config);
+        let caps = KeyEvent {
+            kind: KeyKind::CAPS_LOCK, modifiers: modifiers::SHIFT, state: KeyState::DOWN,
+            ..Default::default()
+        };
+        let sent = server.remap(caps);
+        assert_eq!(sent.kind, KeyKind::ESC);
+        assert_eq!(sent.modifiers, modifiers::SHIFT);
+        assert_eq!(sent.state, KeyState::DOWN);
+        // other keys are left alone
+        let a: KeyEvent = "CHAR a".parse().unwrap();
+        assert_eq!(server.remap(a.clone()), a);
+    }
+
+    #[test]
+    fn tampered_packet_closes_the_session() {
+        let delivered = Delivered::default();
+        let mut client_config = config();
+        client_config.set_sink(delivered.clone());
+        let mut client = Telekey::new(TelekeyMode::Client, client_config);
+        let (mut tx, mut sent, mut wire, rx) = crate::transport::tests::intercepted();
+        let e: KeyEvent = "CHAR a".parse().unwrap();
+        tx.send_packet(e.into()).unwrap();
+        let mut frame = sent.recv_frame().unwrap().to_vec();
+        let last = frame.len() - 1;
+        frame[last] ^= 1;
+        wire.send_frame(&frame).unwrap();
+        let err = client.listen_loop(rx).unwrap_err();
+        assert_eq!(err.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(delivered.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn latency_is_refreshed_every_period_reads() {
+        let mut server_config = config();
+        server_config.set_refresh_latency(Some(1));
+        let server = Telekey::new(TelekeyMode::Server, server_config);
+        let mut reads = 0;
+        assert!((0..5).all(|_| server.latency_due(&mut reads)));
+
+        let mut server_config = config();
+        server_config.set_refresh_latency(Some(3));
+        let server = Telekey::new(TelekeyMode::Server, server_config);
+        let due: Vec<_> = (0..6).map(|_| server.latency_due(&mut reads)).collect();
+        assert_eq!(due, [false, false, true, false, false, true]);
+
+        let mut server_config = config();
+        server_config.set_refresh_latency(None);
+        let server = Telekey::new(TelekeyMode::Server, server_config);
+        assert!(!(0..5).any(|_| server.latency_due(&mut reads)));
+    }
+
+    /// Handshake between a server and a client which may differ in security
+    /// mode, neither allowing a downgrade
+    fn mixed_handshake(server_secure: bool, client_secure: bool) -> (Session, Session) {
+        let (a, b) = MemoryTransport::pair();
+        let server = std::thread::spawn(move || {
+            let skey = SecretKey::from_slice(&[7; TOKEN_SIZE]).unwrap();
+            let server_config = TelekeyConfig { secure: server_secure, ..config() };
+            Telekey::new(TelekeyMode::Server, server_config).open_session(a, skey)
+        });
+        let skey = SecretKey::from_slice(&[7; TOKEN_SIZE]).unwrap();
+        let client_config = TelekeyConfig { secure: client_secure, ..config() };
+        let client = Telekey::new(TelekeyMode::Client, client_config).open_session(b, skey);
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn secure_client_refuses_unsecure_server() {
+        let (server, client) = mixed_handshake(false, true);
+        let e = client.err().unwrap();
+        assert!(format!("{:#}", e).contains("--allow-downgrade"), "{:#}", e);
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn secure_server_refuses_unsecure_client() {
+        let (server, client) = mixed_handshake(true, false);
+        let e = server.err().unwrap();
+        assert!(matches!(e.downcast_ref::<Rejection>(), Some(Rejection::SecurityMismatch)));
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn unsecure_handshake_accepts_right_token() {
+        let (server, client) = mixed_handshake(false, false);
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        client.send_packet(ping_packet(3)).unwrap();
+        assert_eq!(server.recv_packet().unwrap().data(), 3u64.to_be_bytes());
+    }
+
+    #[test]
+    fn disconnect_ends_the_session_cleanly() {
+        let mut client = Telekey::new(TelekeyMode::Client, config());
+        let (a, mut b) = MemoryTransport::pair();
+        b.send_packet(TelekeyPacket::raw(TelekeyPacketKind::Disconnect, Vec::new())).unwrap();
+        // left unread, the session is over
+        b.send_packet(ping_packet(4)).unwrap();
+        client.listen_loop(a).unwrap();
+        assert!(b.recv_packet().is_err());
+    }
+
+    #[test]
+    fn out_of_range_version_is_rejected() {
+        let server = Telekey::new(TelekeyMode::Server, config());
+        for version in MIN_SUPPORTED_VERSION..=PROTOCOL_VERSION {
+            let (mut a, _b) = MemoryTransport::pair();
+            server.check_version(&mut a, version).unwrap();
+        }
+        for version in [MIN_SUPPORTED_VERSION.wrapping_sub(1), PROTOCOL_VERSION + 1, u32::MAX] {
+            let (mut a, mut b) = MemoryTransport::pair();
+            let e = server.check_version(&mut a, version).unwrap_err();
+            assert!(matches!(e.downcast_ref::<Rejection>(),
+                             Some(Rejection::IncompatibleVersion(v)) if *v == version));
+            // the peer is hung up on
+            assert!(b.recv_packet().is_err());
+        }
+    }
+
+    #[test]
+    fn peer_closing_during_ping_ends_the_session() {
+        let mut server = Telekey::new(TelekeyMode::Server, config());
+        // the peer answers the first ping of the exchange, then leaves
+        let (mut a, mut b) = MemoryTransport::pair();
+        let peer = std::thread::spawn(move || {
+            let p = b.recv_packet().unwrap();
+            b.send_packet(p).unwrap();
+        });
+        assert!(server.refresh_latency(&mut a).unwrap().is_none());
+        peer.join().unwrap();
+        assert!(server.latencies.is_empty());
+
+        // or leaves right away
+        let (mut a, b) = MemoryTransport::pair();
+        drop(b);
+        assert!(server.refresh_latency(&mut a).unwrap().is_none());
+    }
+
+    #[test]
+    fn only_an_authenticated_connection_replaces_the_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server_config = config();
+        server_config.set_on_duplicate(DuplicatePolicy::Replace);
+        let mut server = Telekey::new(TelekeyMode::Server, server_config);
+        let (incoming, accepted) = mpsc::channel();
+        server.incoming = Some(accepted);
+        server.peer = Some(addr);
+        server.token = Some(SecretKey::from_slice(&[1; TOKEN_SIZE]).unwrap());
+        for token in [[2; TOKEN_SIZE], [1; TOKEN_SIZE]] {
+            let client = std::thread::spawn(move || {
+                let stream = TcpTransport::from(TcpStream::connect(addr).unwrap());
+                let skey = SecretKey::from_slice(&token).unwrap();
+                Telekey::new(TelekeyMode::Client, config()).open_session(stream, skey).is_ok()
+            });
+            incoming.send(listener.accept().unwrap().0).unwrap();
+            let replaced = server.check_incoming().is_err();
+            assert_eq!(replaced, client.join().unwrap());
+            assert_eq!(replaced, server.replacement.is_some());
+        }
+        assert!(server.pending.is_empty());
+    }
+}
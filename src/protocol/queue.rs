@@ -0,0 +1,198 @@
+use crate::protocol::bindings::api::{KeyEvent, KeyState};
+use crate::transport::*;
+use std::{io, net::SocketAddr, str::FromStr, thread};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use anyhow::{Result, bail};
+use quick_protobuf::deserialize_from_slice;
+use serde::{Serialize, Deserialize};
+
+/// What happens when a packet is sent while the send queue is full
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DropPolicy {
+    /// Waits for the queue to make room
+    Block,
+    /// Drops the oldest queued click, or waits if there is none. Presses
+    /// and releases are never dropped, lest a key stays held down, nor are
+    /// coalesced keys, which stand for several clicks
+    DropOldest
+}
+
+impl FromStr for DropPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            _ => bail!("Unknown drop policy `{}` (expected block or drop-oldest)", s)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    packets: VecDeque<TelekeyPacket>,
+    sending: bool,
+    closed: bool,
+    /// Kept once sending failed, every later call fails the same way
+    error: Option<(io::ErrorKind, String)>
+}
+
+impl Queue {
+    fn check(&self) -> io::Result<()> {
+        match &self.error {
+            Some((kind, msg)) => Err(io::Error::new(*kind, msg.clone())),
+            None => Ok(())
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar
+}
+
+/// Sends packets from a background thread so that a slow link does not hold
+/// the input loop. Packets are sent in the order they were queued, and only
+/// key events are ever dropped (see [`DropPolicy`]). Receiving first waits
+/// for the queue to be empty, so an answer is only awaited once everything
+/// queued before, including the request, has been sent.
+pub struct QueuedTransport<T> {
+    inner: Arc<Mutex<T>>,
+    shared: Arc<Shared>,
+    depth: usize,
+    policy: DropPolicy,
+    sender: Option<thread::JoinHandle<()>>
+}
+
+impl<T: TelekeyTransport + Send + 'static> QueuedTransport<T> {
+    pub fn new(tr: T, depth: usize, policy: DropPolicy) -> Self {
+        let inner = Arc::new(Mutex::new(tr));
+        let shared = Arc::new(Shared::default());
+        let sender = {
+            let (inner, shared) = (inner.clone(), shared.clone());
+            thread::spawn(move || Self::run(inner, shared))
+        };
+        Self { inner, shared, depth: depth.max(1), policy, sender: Some(sender) }
+    }
+
+    fn run(inner: Arc<Mutex<T>>, shared: Arc<Shared>) {
+        let mut queue = shared.queue.lock().unwrap();
+        loop {
+            while queue.packets.is_empty() && !queue.closed {
+                queue = shared.changed.wait(queue).unwrap();
+            }
+            let p = match queue.packets.pop_front() {
+                Some(p) => p,
+                None => return // closed and drained
+            };
+            queue.sending = true;
+            drop(queue);
+            let r = inner.lock().unwrap().send_packet(p);
+            queue = shared.queue.lock().unwrap();
+            queue.sending = false;
+            shared.changed.notify_all();
+            if let Err(e) = r {
+                queue.error = Some((e.kind(), e.to_string()));
+                queue.packets.clear();
+                return;
+            }
+        }
+    }
+
+    /// Waits for every queued packet to be sent
+    pub fn flush(&self) -> io::Result<()> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        while (!queue.packets.is_empty() || queue.sending) && queue.error.is_none() {
+            queue = self.shared.changed.wait(queue).unwrap();
+        }
+        queue.check()
+    }
+}
+
+impl<T> QueuedTransport<T> {
+    /// Lets the sender thread stop once the queue is drained
+    fn close(&self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl<T: TelekeyTransport + Send + 'static> TelekeyTransport for QueuedTransport<T> {
+    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
+        self.flush()?;
+        self.inner.lock().unwrap().recv_packet()
+    }
+
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        self.inner.lock().unwrap().try_recv_packet()
+    }
+
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        loop {
+            queue.check()?;
+            if queue.packets.len() < self.depth {
+                break;
+            }
+            let oldest_click = queue.packets.iter().position(is_droppable);
+            match (self.policy, oldest_click) {
+                (DropPolicy::DropOldest, Some(i)) => {
+                    queue.packets.remove(i);
+                    break;
+                },
+                _ => queue = self.shared.changed.wait(queue).unwrap()
+            }
+        }
+        queue.packets.push_back(p);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Sends the queued packets before closing the connection
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.close();
+        if let Some(sender) = self.sender.take() {
+            sender.join().ok();
+        }
+        self.inner.lock().unwrap().shutdown()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.lock().unwrap().peer_addr()
+    }
+}
+
+/// Tells whether `p` is a single click, the only packet which may be dropped
+fn is_droppable(p: &TelekeyPacket) -> bool {
+    matches!(p.kind(), TelekeyPacketKind::KeyEvent)
+        && deserialize_from_slice::<KeyEvent>(p.data())
+            .is_ok_and(|e| e.state == KeyState::CLICK && e.repeat == 0)
+}
+
+impl<T> Drop for QueuedTransport<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(state: KeyState, repeat: u32) -> TelekeyPacket {
+        KeyEvent { state, repeat, ..Default::default() }.into()
+    }
+
+    #[test]
+    fn only_single_clicks_are_dropped() {
+        assert!(is_droppable(&key(KeyState::CLICK, 0)));
+        assert!(!is_droppable(&key(KeyState::CLICK, 3)));
+        assert!(!is_droppable(&key(KeyState::DOWN, 0)));
+        assert!(!is_droppable(&key(KeyState::UP, 0)));
+        assert!(!is_droppable(&TelekeyPacket::raw(TelekeyPacketKind::Ping, Vec::new())));
+    }
+}
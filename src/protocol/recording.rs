@@ -0,0 +1,59 @@
+use crate::protocol::bindings::api::KeyEvent;
+use std::{fs::{File, OpenOptions}, path::Path, time::{Duration, Instant}};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+
+/// One line of a macro file
+#[derive(Serialize, Deserialize)]
+struct Step {
+    /// Milliseconds elapsed since the previous key
+    delay: u64,
+    /// Key spec, as parsed by `KeyEvent::from_str`
+    key: String
+}
+
+/// Appends the keys sent during the sessions to a macro file, one JSON
+/// object per line, along with the time elapsed since the previous key.
+pub struct Recorder {
+    out: BufWriter<File>,
+    last: Option<Instant>
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Could not open `{}`", path.display()))?;
+        Ok(Self { out: BufWriter::new(file), last: None })
+    }
+
+    /// Appends a key to the macro, flushing it right away
+    pub fn record(&mut self, e: &KeyEvent) -> io::Result<()> {
+        let now = Instant::now();
+        let delay = self.last.map_or(0, |last| (now - last).as_millis() as u64);
+        self.last = Some(now);
+        let step = Step { delay, key: e.to_spec() };
+        serde_json::to_writer(&mut self.out, &step)?;
+        writeln!(self.out)?;
+        self.out.flush()
+    }
+}
+
+/// Reads the keys of a macro file along with the delay before each of them
+pub fn load(path: &Path) -> Result<Vec<(Duration, KeyEvent)>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open `{}`", path.display()))?;
+    let mut steps = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let step: Step = serde_json::from_str(&line)
+            .with_context(|| format!("{}:{}: Invalid macro step", path.display(), i + 1))?;
+        let e: KeyEvent = step.key.parse()
+            .with_context(|| format!("{}:{}: Invalid key spec", path.display(), i + 1))?;
+        steps.push((Duration::from_millis(step.delay), e));
+    }
+    Ok(steps)
+}
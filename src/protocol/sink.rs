@@ -0,0 +1,320 @@
+use crate::protocol::bindings::api::{KeyEvent, KeyKind, KeyState};
+use crate::protocol::bindings::api::{MouseEvent, MouseAction, MouseButton};
+use crate::protocol::modifiers;
+use enigo::{Enigo, KeyboardControllable, MouseControllable};
+use std::{io::{self, Write}, str::FromStr, sync::{Arc, Mutex}};
+use anyhow::{Result, bail};
+use log::{error, warn};
+use serde::{Serialize, Deserialize};
+
+/// Largest scroll delta emulated at once, in either direction
+const MAX_SCROLL: i32 = 50;
+
+/// Where the client delivers the keys received from the server
+pub trait KeySink {
+    /// Delivers a key event, scrolls included
+    fn deliver(&mut self, event: &KeyEvent) -> Result<()>;
+
+    /// Types a whole text at once. By default, each of its characters is
+    /// delivered as a `CHAR` event
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            self.deliver(&KeyEvent { kind: KeyKind::CHAR, key: c as u32, ..Default::default() })?;
+        }
+        Ok(())
+    }
+
+    /// Delivers a pointer event. By default, they are skipped
+    fn deliver_mouse(&mut self, event: &MouseEvent) -> Result<()> {
+        warn!("Pointer events are not supported, skipped `{}`", event);
+        Ok(())
+    }
+
+    /// Checks that keys can be delivered, before the session starts. By
+    /// default, they always can
+    fn ready(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Emulates the keys on this machine. The emulation only starts with the
+/// first key, so that a sink which never receives any needs no display
+pub struct EnigoSink {
+    enigo: Option<Enigo>
+}
+
+impl EnigoSink {
+    pub fn new() -> Self {
+        Self { enigo: None }
+    }
+
+    /// Starts the emulation if it is not yet. On Linux, it needs an X
+    /// display, without which enigo would fail on the first key
+    fn enigo(&mut self) -> Result<&mut Enigo> {
+        if self.enigo.is_none() && cfg!(target_os = "linux")
+            && std::env::var_os("DISPLAY").is_none_or(|d| d.is_empty()) {
+            bail!("No X display to emulate the keys on: set DISPLAY or --x-display, \
+                or use --cold-run to print them instead");
+        }
+        Ok(self.enigo.get_or_insert_with(Enigo::new))
+    }
+
+    /// Turns the mouse wheel by the signed delta stored in `key`, clamped to
+    /// `MAX_SCROLL`. Shift makes it scroll horizontally
+    fn scroll(&mut self, event: &KeyEvent) -> Result<()> {
+        let delta = (event.key as i32).clamp(-MAX_SCROLL, MAX_SCROLL);
+        let enigo = self.enigo()?;
+        if event.modifiers & modifiers::SHIFT != 0 {
+            enigo.mouse_scroll_x(delta);
+        } else {
+            enigo.mouse_scroll_y(delta);
+        }
+        Ok(())
+    }
+
+    /// Clicks `key` while holding down the modifiers set in `mods`. They are
+    /// released in reverse order once the key is clicked
+    fn click(&mut self, key: enigo::Key, mods: u32) -> Result<()> {
+        let held: Vec<enigo::Key> = modifiers::ALL.iter()
+            .filter(|(bit, _, _)| mods & bit != 0)
+            .map(|&(_, k, _)| k)
+            .collect();
+        let enigo = self.enigo()?;
+        for &k in &held {
+            enigo.key_down(k);
+        }
+        enigo.key_click(key);
+        for &k in held.iter().rev() {
+            enigo.key_up(k);
+        }
+        Ok(())
+    }
+}
+
+impl Default for EnigoSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeySink for EnigoSink {
+    /// A key that cannot be emulated on this machine is logged and skipped
+    fn deliver(&mut self, event: &KeyEvent) -> Result<()> {
+        if event.kind == KeyKind::SCROLL {
+            return self.scroll(event);
+        }
+        // Down and up events hold and release the key. The terminal only
+        // reports presses though, so senders only emit clicks for now, as
+        // do older ones which leave `state` unset.
+        let r: Result<enigo::Key, String> = event.into();
+        match r {
+            Ok(k) => match event.state {
+                KeyState::CLICK => self.click(k, event.modifiers)?,
+                KeyState::DOWN => self.enigo()?.key_down(k),
+                KeyState::UP => self.enigo()?.key_up(k)
+            },
+            Err(e) => error!("Could not emulate `{}`: {}", event, e)
+        }
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.enigo()?.key_sequence(text);
+        Ok(())
+    }
+
+    fn deliver_mouse(&mut self, event: &MouseEvent) -> Result<()> {
+        let enigo = self.enigo()?;
+        let button = match event.button {
+            MouseButton::LEFT => enigo::MouseButton::Left,
+            MouseButton::RIGHT => enigo::MouseButton::Right,
+            MouseButton::MIDDLE => enigo::MouseButton::Middle
+        };
+        match event.action {
+            MouseAction::MOVE if event.absolute => enigo.mouse_move_to(event.x, event.y),
+            MouseAction::MOVE => enigo.mouse_move_relative(event.x, event.y),
+            MouseAction::CLICK => enigo.mouse_click(button),
+            MouseAction::PRESS => enigo.mouse_down(button),
+            MouseAction::RELEASE => enigo.mouse_up(button)
+        }
+        Ok(())
+    }
+
+    fn ready(&mut self) -> Result<()> {
+        self.enigo().map(|_| ())
+    }
+}
+
+/// Prints the keys instead of emulating them. They go through the same
+/// conversion as the emulation, so it is a faithful preview of it: each key
+/// event received is printed once, repeats and held keys included, exactly
+/// when it would be emulated once
+pub struct ColdRunSink<W: Write = io::Stdout> {
+    out: W
+}
+
+impl ColdRunSink {
+    pub fn new() -> Self {
+        Self::to(io::stdout())
+    }
+}
+
+impl Default for ColdRunSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> ColdRunSink<W> {
+    /// Prints to `out` instead of the standard output
+    pub fn to(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> KeySink for ColdRunSink<W> {
+    fn deliver(&mut self, event: &KeyEvent) -> Result<()> {
+        if event.kind != KeyKind::SCROLL {
+            let r: Result<enigo::Key, String> = event.into();
+            if let Err(e) = r {
+                error!("Could not emulate `{}`: {}", event, e);
+                return Ok(());
+            }
+        }
+        write!(self.out, "{}", event)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        write!(self.out, "{}", text)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn deliver_mouse(&mut self, event: &MouseEvent) -> Result<()> {
+        write!(self.out, "{}", event)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// How a cold run prints the keys received
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColdFormat {
+    /// As shown in the menu, e.g. `[A^]`
+    Text,
+    /// One JSON object per line, e.g. `{"kind":"CHAR","key":97,"modifiers":0}`
+    #[cfg(feature = "serde")]
+    Json
+}
+
+impl FromStr for ColdFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            #[cfg(feature = "serde")]
+            "json" => Ok(Self::Json),
+            #[cfg(not(feature = "serde"))]
+            "json" => bail!("The json cold-run format needs the `serde` feature"),
+            _ => bail!("Unknown cold-run format `{}` (expected text or json)", s)
+        }
+    }
+}
+
+/// Prints the keys as JSON objects, one per line, for other tools to read.
+/// Texts are printed as one key per character
+#[cfg(feature = "serde")]
+pub struct JsonSink;
+
+#[cfg(feature = "serde")]
+impl KeySink for JsonSink {
+    fn deliver(&mut self, event: &KeyEvent) -> Result<()> {
+        println!("{}", serde_json::to_string(event)?);
+        Ok(())
+    }
+}
+
+/// Sink set in the configuration, shared by its clones, so every session
+/// delivers to the same one
+#[derive(Clone)]
+pub struct SharedSink(Arc<Mutex<dyn KeySink + Send>>);
+
+impl SharedSink {
+    pub fn new(sink: impl KeySink + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(sink)))
+    }
+}
+
+impl KeySink for SharedSink {
+    fn deliver(&mut self, event: &KeyEvent) -> Result<()> {
+        self.0.lock().unwrap().deliver(event)
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.0.lock().unwrap().type_text(text)
+    }
+
+    fn deliver_mouse(&mut self, event: &MouseEvent) -> Result<()> {
+        self.0.lock().unwrap().deliver_mouse(event)
+    }
+
+    fn ready(&mut self) -> Result<()> {
+        self.0.lock().unwrap().ready()
+    }
+}
+
+impl std::fmt::Debug for SharedSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SharedSink")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the keys as `EnigoSink` emulates them: those it cannot are
+    /// skipped, the others are emulated once per event
+    #[derive(Default)]
+    struct Emulated(Vec<KeyEvent>);
+
+    impl KeySink for Emulated {
+        fn deliver(&mut self, event: &KeyEvent) -> Result<()> {
+            let r: Result<enigo::Key, String> = event.into();
+            if event.kind == KeyKind::SCROLL || r.is_ok() {
+                self.0.push(event.clone());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cold_run_prints_what_is_emulated() {
+        let up: KeyEvent = "UP".parse().unwrap();
+        let shift: KeyEvent = "SHIFT".parse().unwrap();
+        let events = [
+            // an arrow held down in the terminal, reported as repeats
+            up.clone(), up.clone(), up.clone(),
+            KeyEvent { state: KeyState::DOWN, ..shift.clone() },
+            "CHAR a".parse().unwrap(),
+            KeyEvent { state: KeyState::UP, ..shift },
+            // cannot be emulated, so it is not printed either
+            KeyEvent { kind: KeyKind::CHAR, key: 0xD800, ..Default::default() },
+            up
+        ];
+        let mut emulated = Emulated::default();
+        let mut printed = Vec::new();
+        let mut cold = ColdRunSink::to(&mut printed);
+        for e in &events {
+            emulated.deliver(e).unwrap();
+            cold.deliver(e).unwrap();
+        }
+        assert_eq!(emulated.0.len(), events.len() - 1);
+        let expected: String = emulated.0.iter().map(|e| e.to_string()).collect();
+        assert_eq!(String::from_utf8(printed).unwrap(), expected);
+    }
+}
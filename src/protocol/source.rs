@@ -0,0 +1,90 @@
+use crate::protocol::bindings::api::KeyEvent;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where the server reads the keys it sends. Reads of a source set in the
+/// configuration happen on a dedicated thread, so they may block for as
+/// long as needed.
+pub trait KeySource {
+    /// Waits for the next key, `None` once no more keys will come, which
+    /// ends the sessions like closing the terminal does
+    fn next_key(&mut self) -> Option<KeyEvent>;
+}
+
+/// Reads the keys typed in the terminal, in raw mode while waiting for
+/// one. Modifiers and function keys are reported as well, key releases
+/// are skipped. Ctrl+C closes the input
+pub struct TermSource;
+
+impl TermSource {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Waits up to `timeout` for a key, the terminal being in raw mode only
+    /// meanwhile so that what is printed in between is not garbled.
+    /// `Some(None)` if no key came in time, `None` once the input is closed
+    pub fn poll_key(&mut self, timeout: Duration) -> Option<Option<KeyEvent>> {
+        terminal::enable_raw_mode().ok()?;
+        let key = Self::read_key(Some(Instant::now() + timeout));
+        terminal::disable_raw_mode().ok();
+        key
+    }
+
+    fn read_key(deadline: Option<Instant>) -> Option<Option<KeyEvent>> {
+        loop {
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if !event::poll(left).ok()? {
+                    return Some(None);
+                }
+            }
+            match event::read().ok()? {
+                Event::Key(key) if key.kind == KeyEventKind::Release => (),
+                Event::Key(key) if key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL) => return None,
+                Event::Key(key) => return Some(Some(key.into())),
+                _ => ()
+            }
+        }
+    }
+}
+
+impl Default for TermSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeySource for TermSource {
+    fn next_key(&mut self) -> Option<KeyEvent> {
+        terminal::enable_raw_mode().ok()?;
+        let key = Self::read_key(None);
+        terminal::disable_raw_mode().ok();
+        key.flatten()
+    }
+}
+
+/// Source set in the configuration, shared by its clones
+#[derive(Clone)]
+pub struct SharedSource(Arc<Mutex<dyn KeySource + Send>>);
+
+impl SharedSource {
+    pub fn new(source: impl KeySource + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(source)))
+    }
+}
+
+impl KeySource for SharedSource {
+    fn next_key(&mut self) -> Option<KeyEvent> {
+        self.0.lock().unwrap().next_key()
+    }
+}
+
+impl std::fmt::Debug for SharedSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SharedSource")
+    }
+}
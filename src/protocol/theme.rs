@@ -0,0 +1,79 @@
+use console::Style;
+use serde::{Serialize, Deserialize};
+use std::fmt::Display;
+
+/// Text shown in the menu along with its style. The style is a dotted
+/// string as understood by `console`, e.g. `black.on_green` or `173.italic`.
+/// Each `{name}` in the text is replaced by the value of the same name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Badge {
+    pub text: String,
+    pub style: String
+}
+
+impl Badge {
+    pub fn new(text: &str, style: &str) -> Self {
+        Self { text: text.to_string(), style: style.to_string() }
+    }
+
+    pub fn render(&self, values: &[(&str, &dyn Display)]) -> String {
+        let mut text = self.text.clone();
+        for (name, value) in values {
+            text = text.replace(&format!("{{{}}}", name), &value.to_string());
+        }
+        Style::from_dotted_str(&self.style).apply_to(text).to_string()
+    }
+}
+
+/// Badges of the server menu. Purely presentational, the peer never sees it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    /// `{version}`
+    pub name: Badge,
+    pub unknown_peer: Badge,
+    /// `{addr}`
+    pub peer: Badge,
+    /// `{addr}` and `{hostname}`
+    pub named_peer: Badge,
+    /// `{n}`, the clients connected to a multi-client server
+    pub clients: Badge,
+    pub idle: Badge,
+    /// `{n}`, the seconds left before the session is active
+    pub activating: Badge,
+    pub active: Badge,
+    pub paused: Badge,
+    /// `{keys}`, the modifiers held and locks on, shown when there are any
+    pub keyboard: Badge,
+    /// `{rtt}` and `{one_way}`, along with `{min}`, `{max}`, `{avg}` and
+    /// `{jitter}` over the last round-trip times, and `{sparkline}` drawing
+    /// them
+    pub latency: Badge,
+    pub unknown_latency: Badge,
+    /// `{rate}`, the keys sent per second over the last seconds
+    pub rate: Badge,
+    pub throttled: Badge,
+    pub prompt: Badge
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            name: Badge::new("TeleKey v{version} ", "173.italic"),
+            unknown_peer: Badge::new("!! Unkown peer !!", "on_red"),
+            peer: Badge::new(" {addr} ", "magenta.on_238"),
+            named_peer: Badge::new(" {addr} ({hostname}) ", "magenta.on_238"),
+            clients: Badge::new(" {n} clients ", "black.on_cyan"),
+            idle: Badge::new(" IDLE ", "black.on_blue"),
+            activating: Badge::new(" ACTIVE IN {n}s ", "black.on_yellow"),
+            active: Badge::new(" ACTIVE ", "black.on_green"),
+            paused: Badge::new(" PAUSED ", "black.on_magenta"),
+            keyboard: Badge::new(" {keys} ", "black.on_white"),
+            latency: Badge::new(" RTT {rtt} {sparkline} ~{one_way} one-way (avg {avg} jitter {jitter} min {min} max {max}) ", "yellow"),
+            unknown_latency: Badge::new(" ??ms ", "yellow"),
+            rate: Badge::new(" {rate} keys/s ", "cyan"),
+            throttled: Badge::new("(throttled)", "246"),
+            prompt: Badge::new("--> Press any key <--", "246")
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::protocol::bindings::api::KeyEvent;
+use std::{fs::{File, OpenOptions}, path::Path};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use anyhow::{Result, Context, anyhow, bail};
+use chrono::Utc;
+use console::Term;
+use orion::{aead, kdf};
+
+const SALT_LEN: usize = 16;
+// Argon2i cost parameters used to derive the key from the passphrase
+const KDF_ITERATIONS: u32 = 3;
+const KDF_MEMORY: u32 = 1 << 16;
+/// Largest sealed record: a record is a timestamp and a key, far shorter
+/// than this, plus the nonce and tag added by sealing
+const MAX_RECORD: usize = 1024;
+
+/// Records every key of a session along with a timestamp. Encrypted
+/// transcripts start with the salt used to derive the key from the
+/// passphrase, followed by length-prefixed sealed records.
+pub struct Transcript {
+    out: BufWriter<File>,
+    key: Option<aead::SecretKey>
+}
+
+impl Transcript {
+    pub fn plain(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Could not open `{}`", path.display()))?;
+        Ok(Self { out: BufWriter::new(file), key: None })
+    }
+
+    /// Opens an encrypted transcript, appending to it if it already exists.
+    /// The passphrase must then open its first record, so that a transcript
+    /// never mixes records sealed with different ones
+    pub fn encrypted(path: &Path, passphrase: &str) -> Result<Self> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true)
+            .open(path)
+            .with_context(|| format!("Could not open `{}`", path.display()))?;
+        let existing = file.metadata()?.len() > 0;
+        let salt = if existing {
+            let mut salt = [0u8; SALT_LEN];
+            file.read_exact(&mut salt).context("Invalid transcript header")?;
+            kdf::Salt::from_slice(&salt)?
+        } else {
+            let salt = kdf::Salt::default();
+            file.write_all(salt.as_ref())?;
+            salt
+        };
+        let key = derive_key(passphrase, &salt)?;
+        if existing {
+            read_record(&mut file, &key)
+                .with_context(|| format!("Could not append to `{}`", path.display()))?;
+        }
+        Ok(Self { out: BufWriter::new(file), key: Some(key) })
+    }
+
+    /// Appends a key to the transcript, flushing it right away
+    pub fn record(&mut self, e: &KeyEvent) -> io::Result<()> {
+        let line = format!("{} {}", Utc::now().to_rfc3339(), e);
+        if let Some(key) = &self.key {
+            let sealed = aead::seal(key, line.as_bytes())
+                .map_err(|_| io::Error::other("Failed to seal transcript record"))?;
+            self.out.write_all(&(sealed.len() as u32).to_be_bytes())?;
+            self.out.write_all(&sealed)?;
+        } else {
+            writeln!(self.out, "{}", line)?;
+        }
+        self.out.flush()
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &kdf::Salt) -> Result<aead::SecretKey> {
+    let password = kdf::Password::from_slice(passphrase.as_bytes())
+        .context("The passphrase must not be empty")?;
+    kdf::derive_key(&password, salt, KDF_ITERATIONS, KDF_MEMORY, 32)
+        .context("Failed to derive transcript key")
+}
+
+/// Passphrase taken from `TELEKEY_TRANSCRIPT_PASSPHRASE`, or asked on the
+/// terminal
+pub fn passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var("TELEKEY_TRANSCRIPT_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    print!("Transcript passphrase: ");
+    io::stdout().flush()?;
+    Term::stdout().read_secure_line().context("Could not read passphrase")
+}
+
+/// Writes the records of the encrypted transcript at `path` to `out`
+pub fn decrypt(path: &Path, passphrase: &str, out: &mut impl Write) -> Result<()> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open `{}`", path.display()))?;
+    let mut file = BufReader::new(file);
+    let mut salt = [0u8; SALT_LEN];
+    file.read_exact(&mut salt).context("Invalid transcript header")?;
+    let key = derive_key(passphrase, &kdf::Salt::from_slice(&salt)?)?;
+
+    while let Some(record) = read_record(&mut file, &key)? {
+        out.write_all(&record)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Reads and opens the next record of an encrypted transcript, `None` at
+/// its end
+fn read_record(file: &mut impl Read, key: &aead::SecretKey) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    match file.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into())
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_RECORD {
+        bail!("Corrupted transcript: record of {} bytes", len);
+    }
+    let mut buf = vec![0; len];
+    file.read_exact(&mut buf).context("Truncated transcript record")?;
+    let record = aead::open(key, &buf)
+        .map_err(|_| anyhow!("Wrong passphrase or corrupted transcript"))?;
+    Ok(Some(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("telekey-{}-{}", name, std::process::id()))
+    }
+
+    fn key() -> KeyEvent {
+        "CHAR a".parse().unwrap()
+    }
+
+    #[test]
+    fn appends_with_the_same_passphrase() {
+        let path = temp_path("same-passphrase");
+        Transcript::encrypted(&path, "secret").unwrap().record(&key()).unwrap();
+        Transcript::encrypted(&path, "secret").unwrap().record(&key()).unwrap();
+        let mut out = Vec::new();
+        decrypt(&path, "secret", &mut out).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn refuses_to_append_with_another_passphrase() {
+        let path = temp_path("other-passphrase");
+        Transcript::encrypted(&path, "secret").unwrap().record(&key()).unwrap();
+        let r = Transcript::encrypted(&path, "guess");
+        let mut out = Vec::new();
+        decrypt(&path, "secret", &mut out).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(r.is_err());
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn flipped_byte_is_an_error() {
+        let path = temp_path("flipped-byte");
+        Transcript::encrypted(&path, "secret").unwrap().record(&key()).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        let r = decrypt(&path, "secret", &mut Vec::new());
+        std::fs::remove_file(&path).ok();
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn oversized_record_is_rejected_before_reading_it() {
+        let path = temp_path("oversized-record");
+        Transcript::encrypted(&path, "secret").unwrap().record(&key()).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[SALT_LEN..SALT_LEN + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        let r = decrypt(&path, "secret", &mut Vec::new());
+        std::fs::remove_file(&path).ok();
+        assert!(r.unwrap_err().to_string().contains("record of"));
+    }
+}
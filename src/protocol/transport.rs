@@ -1,19 +1,29 @@
-use std::{io::{self, Write, Read}, net::{TcpStream, SocketAddr}};
+use std::{io::{self, Write, Read}, net::{TcpStream, UdpSocket, SocketAddr}};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, mpsc};
 use quick_protobuf::{MessageWrite, Writer};
-use orion::{kex::SessionKeys, aead};
+use orion::kex::SessionKeys;
+use orion::hazardous::aead::xchacha20poly1305::{self, SecretKey, Nonce};
 
-#[derive(Debug, Clone, Copy)]
+/// Kind of a packet, sent as its last byte
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(u8)]
 pub enum TelekeyPacketKind {
-    Unknown,
-    Handshake,
-    KeyEvent,
-    Ping
-}
-
-impl Default for TelekeyPacketKind {
-    fn default() -> Self {
-        Self::Unknown
-    }
+    Handshake = 0,
+    KeyEvent = 1,
+    Ping = 2,
+    /// Sent by a peer about to close the session on purpose
+    Disconnect = 3,
+    /// UTF-8 text typed at once, such as a pasted clipboard
+    Text = 4,
+    Mouse = 5,
+    /// Clipboard text for the peer to set. An empty one sent by the sender
+    /// of the keys asks for the clipboard of the receiver instead
+    Clipboard = 6,
+    /// Any other byte received. Below `COMPRESSED`, so that it can be
+    /// sent compressed as well
+    #[default]
+    Unknown = 0x7f
 }
 
 impl From<u8> for TelekeyPacketKind {
@@ -22,6 +32,10 @@ impl From<u8> for TelekeyPacketKind {
             0 => Self::Handshake,
             1 => Self::KeyEvent,
             2 => Self::Ping,
+            3 => Self::Disconnect,
+            4 => Self::Text,
+            5 => Self::Mouse,
+            6 => Self::Clipboard,
             _ => Self::Unknown
         }
     }
@@ -29,13 +43,7 @@ impl From<u8> for TelekeyPacketKind {
 
 impl From<TelekeyPacketKind> for u8 {
     fn from(kind: TelekeyPacketKind) -> Self {
-        use TelekeyPacketKind::*;
-        match kind {
-            Handshake => 0,
-            KeyEvent => 1,
-            Ping => 2,
-            Unknown => 255
-        }
+        kind as u8
     }
 }
 
@@ -70,35 +78,242 @@ impl TelekeyPacket {
 pub trait TelekeyTransport {
     /// blocking function
     fn recv_packet(&mut self) -> io::Result<TelekeyPacket>;
+    /// non-blocking function, returns `None` until a full packet is received
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>>;
     fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()>;
     fn shutdown(&mut self) -> io::Result<()>;
+    /// Address of the peer, as captured when the connection was established
+    /// when available, so it stays valid after a half-close
     fn peer_addr(&self) -> io::Result<SocketAddr>;
 }
 
-pub struct TcpTransport {
-    stream: TcpStream
+impl<T: TelekeyTransport + ?Sized> TelekeyTransport for Box<T> {
+    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
+        (**self).recv_packet()
+    }
+
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        (**self).try_recv_packet()
+    }
+
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        (**self).send_packet(p)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
 }
 
-impl TelekeyTransport for TcpTransport {
-    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
-        let mut header = [0u8; 4];
-        self.stream.read_exact(&mut header)?;
-        let len = u32::from_be_bytes(header); // deduce remaining bytes to read
+/// Largest packet accepted by default, well above any telekey message
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// Splits the stream into length-prefixed frames, keeping the bytes of a
+/// partially received frame between reads. Frames are borrowed from the
+/// buffer, which is reused for every packet
+struct FrameReader {
+    buf: Vec<u8>,
+    /// Bytes of the frame handed out last, dropped on the next read
+    consumed: usize,
+    max_len: usize
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self { buf: Vec::new(), consumed: 0, max_len: DEFAULT_MAX_PACKET_SIZE }
+    }
+}
+
+impl FrameReader {
+    fn read(&mut self, stream: &mut TcpStream) -> io::Result<&[u8]> {
+        self.consume();
+        loop {
+            if let Some(len) = self.next_frame()? {
+                return Ok(&self.buf[4..4 + len]);
+            }
+            let mut chunk = [0u8; 1024];
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(self.closed()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // the read timeout of the stream elapsed, reported as
+                // `WouldBlock` on some platforms
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock
+                                   | io::ErrorKind::TimedOut) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut,
+                        "Timed out waiting for the peer"));
+                },
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn try_read(&mut self, stream: &mut TcpStream) -> io::Result<Option<&[u8]>> {
+        self.consume();
+        stream.set_nonblocking(true)?;
+        let r = self.read_available(stream);
+        stream.set_nonblocking(false)?;
+        // the frames received before the peer closed the connection come first
+        match self.next_frame()? {
+            Some(len) => Ok(Some(&self.buf[4..4 + len])),
+            None => r.map(|_| None)
+        }
+    }
+
+    /// Drops the frame handed out last, keeping the capacity of the buffer
+    fn consume(&mut self) {
+        self.buf.drain(..self.consumed);
+        self.consumed = 0;
+    }
+
+    fn read_available(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(self.closed()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// The peer closed the connection: `UnexpectedEof` between two packets,
+    /// `InvalidData` in the middle of one
+    fn closed(&self) -> io::Error {
+        if self.buf.is_empty() {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by the peer")
+        } else {
+            io::Error::new(io::ErrorKind::InvalidData,
+                format!("Connection closed in the middle of a packet ({} bytes received)",
+                        self.buf.len()))
+        }
+    }
 
+    /// Length of the next complete frame, which follows its 4-byte header
+    fn next_frame(&mut self) -> io::Result<Option<usize>> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        // deduce remaining bytes to read
+        let len = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
         if len == 0 {
             return Err(io::Error::new(io::ErrorKind::InvalidInput,
                   "Zero length packet received"));
         }
+        // checked as soon as the header is known, before buffering the rest
+        if len > self.max_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                  format!("Packet of {} bytes exceeds the maximum of {} bytes",
+                          len, self.max_len)));
+        }
+        if self.buf.len() < 4 + len {
+            return Ok(None);
+        }
+        self.consumed = 4 + len;
+        Ok(Some(len))
+    }
+}
 
-        let mut buf = vec![0; len as usize];
-        self.stream.read_exact(&mut buf)?;
-        Ok(TelekeyPacket::raw(buf.pop().unwrap().into(), buf))
+/// Writes `buf` after its length in a single write, so that the header and
+/// the payload go out in the same segment despite `TCP_NODELAY`
+fn write_frame(stream: &mut impl Write, buf: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(4 + buf.len());
+    frame.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+    frame.extend_from_slice(buf);
+    stream.write_all(&frame)
+}
+
+/// Set on the kind byte of a frame whose payload is compressed
+const COMPRESSED: u8 = 0x80;
+
+/// Appends the kind byte to the payload, compressed beforehand if enabled
+/// and if that makes it shorter. Single key events never are
+fn encode(p: TelekeyPacket, compress: bool) -> Vec<u8> {
+    let kind: u8 = p.kind().into();
+    if compress {
+        let mut buf = lz4_flex::compress_prepend_size(&p.payload);
+        if buf.len() < p.payload.len() {
+            buf.push(kind | COMPRESSED);
+            return buf;
+        }
     }
+    let mut buf = p.payload;
+    buf.push(kind);
+    buf
+}
 
-    fn send_packet(&mut self, mut p: TelekeyPacket) -> io::Result<()> {
-        p.payload.push(p.kind().into());
-        self.stream.write_all(&(p.payload.len() as u32).to_be_bytes())?;
-        self.stream.write_all(&p.payload)
+/// Reverse of `encode`, copying the payload out of `buf`. The announced
+/// size of a compressed payload is checked against `max_len` before
+/// decompressing it
+fn decode(buf: &[u8], compress: bool, max_len: usize) -> io::Result<TelekeyPacket> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let (&kind, buf) = buf.split_last().ok_or_else(|| invalid("Empty packet received"))?;
+    if !compress || kind & COMPRESSED == 0 {
+        return Ok(TelekeyPacket::raw(kind.into(), buf.to_vec()));
+    }
+    if buf.len() < 4 {
+        return Err(invalid("Truncated compressed packet"));
+    }
+    let size = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+    if size > max_len {
+        return Err(invalid("Compressed packet exceeds the maximum packet size"));
+    }
+    let payload = lz4_flex::decompress(&buf[4..], size)
+        .map_err(|_| invalid("Failed to decompress packet"))?;
+    Ok(TelekeyPacket::raw((kind & !COMPRESSED).into(), payload))
+}
+
+/// Transport of whole frames, which the packets are encoded to. Sessions
+/// run their handshake over any of them, and [`SecureTransport`] seals the
+/// frames of the one it wraps
+pub trait FrameTransport: TelekeyTransport {
+    /// blocking function, the frame is borrowed until the next read
+    fn recv_frame(&mut self) -> io::Result<&[u8]>;
+    /// non-blocking function, returns `None` until a full frame is received
+    fn try_recv_frame(&mut self) -> io::Result<Option<&[u8]>>;
+    fn send_frame(&mut self, buf: &[u8]) -> io::Result<()>;
+    /// Frames announcing a larger length are refused with `InvalidData`
+    fn set_max_packet_size(&mut self, max: usize);
+    fn max_packet_size(&self) -> usize;
+    /// Compresses the packets worth it, once both ends agreed to during
+    /// the handshake
+    fn set_compression(&mut self, compress: bool);
+    fn compression(&self) -> bool;
+    /// Sets `TCP_NODELAY` on a socket, ignored otherwise
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()>;
+    /// Blocking reads fail with `TimedOut` after `timeout`
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+    peer: Option<SocketAddr>,
+    reader: FrameReader,
+    compress: bool
+}
+
+impl TelekeyTransport for TcpTransport {
+    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
+        let max_len = self.reader.max_len;
+        let buf = self.reader.read(&mut self.stream)?;
+        decode(buf, self.compress, max_len)
+    }
+
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        let max_len = self.reader.max_len;
+        let buf = self.reader.try_read(&mut self.stream)?;
+        buf.map(|buf| decode(buf, self.compress, max_len)).transpose()
+    }
+
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        let buf = encode(p, self.compress);
+        write_frame(&mut self.stream, &buf)
     }
 
     fn shutdown(&mut self) -> io::Result<()> {
@@ -106,7 +321,45 @@ impl TelekeyTransport for TcpTransport {
     }
 
     fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.stream.peer_addr()
+        self.peer.map_or_else(|| self.stream.peer_addr(), Ok)
+    }
+}
+
+impl FrameTransport for TcpTransport {
+    fn recv_frame(&mut self) -> io::Result<&[u8]> {
+        self.reader.read(&mut self.stream)
+    }
+
+    fn try_recv_frame(&mut self) -> io::Result<Option<&[u8]>> {
+        self.reader.try_read(&mut self.stream)
+    }
+
+    fn send_frame(&mut self, buf: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.stream, buf)
+    }
+
+    fn set_max_packet_size(&mut self, max: usize) {
+        self.reader.max_len = max;
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.reader.max_len
+    }
+
+    fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    fn compression(&self) -> bool {
+        self.compress
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
     }
 }
 
@@ -118,7 +371,8 @@ impl TcpTransport {
 
 impl From<TcpStream> for TcpTransport {
     fn from(stream: TcpStream) -> Self {
-        Self { stream }
+        let peer = stream.peer_addr().ok();
+        Self { stream, peer, reader: FrameReader::default(), compress: false }
     }
 }
 
@@ -128,46 +382,940 @@ impl From<TcpTransport> for TcpStream {
     }
 }
 
-pub struct SecureTransport {
-    stream: TcpStream,
-    keys: SessionKeys
+/// Size of the random nonce prefixed to each sealed packet
+const NONCE_SIZE: usize = 24;
+
+/// Size of the authentication tag ending each sealed packet
+const TAG_SIZE: usize = 16;
+
+/// Bytes a sealed frame adds to the payload of its packet: the nonce, the
+/// tag and the kind byte
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE + 1;
+
+/// Packets are sealed with XChaCha20-Poly1305, one key per direction. The
+/// number of packets sent before a packet is authenticated along with it,
+/// so a packet that is dropped, replayed or reordered fails to open.
+pub struct SecureTransport<T: FrameTransport = TcpTransport> {
+    inner: T,
+    sending: SecretKey,
+    receiving: SecretKey,
+    sent: u64,
+    received: u64,
+    /// Plaintext of the packet opened last, reused for every packet
+    plain: Vec<u8>
+}
+
+impl<T: FrameTransport> SecureTransport<T> {
+    /// Frames already received by `tr` are kept, so nothing sent by the peer
+    /// right after the handshake is lost. So are its maximum packet size and
+    /// compression, the latter being applied before encryption
+    pub fn new(tr: T, keys: SessionKeys) -> io::Result<Self> {
+        let key = |k: &orion::kex::SecretKey| SecretKey::from_slice(k.unprotected_as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                                        "Session keys are not 32 bytes long"));
+        Ok(Self {
+            sending: key(keys.transport())?, receiving: key(keys.receiving())?,
+            inner: tr, sent: 0, received: 0, plain: Vec::new()
+        })
+    }
+
+    /// Packets announcing a larger length are refused with `InvalidData`
+    pub fn set_max_packet_size(&mut self, max: usize) {
+        self.inner.set_max_packet_size(max);
+    }
+
+    /// Decodes the packet just opened into `plain`
+    fn opened(&mut self) -> io::Result<TelekeyPacket> {
+        self.received += 1;
+        decode(&self.plain, self.inner.compression(), self.inner.max_packet_size())
+    }
+}
+
+/// Seals `buf`, the `seq`th packet sent, appending it to `msg` after a
+/// random nonce
+fn seal(key: &SecretKey, seq: u64, buf: &[u8], msg: &mut Vec<u8>) -> io::Result<()> {
+    let nonce = Nonce::generate();
+    let start = msg.len();
+    msg.resize(start + NONCE_SIZE + buf.len() + TAG_SIZE, 0);
+    msg[start..start + NONCE_SIZE].copy_from_slice(nonce.as_ref());
+    xchacha20poly1305::seal(key, &nonce, buf, Some(&seq.to_be_bytes()),
+                            &mut msg[start + NONCE_SIZE..])
+        .map_err(|_| io::Error::other("Failed to seal packet"))
 }
 
-impl SecureTransport {
-    pub fn new(stream: TcpStream, keys: SessionKeys) -> Self {
-        Self { stream, keys }
+/// Opens the packet `buf`, the `seq`th one received, into `plain`. A
+/// tampered, truncated, replayed or out of order packet fails with
+/// `InvalidData` instead of being trusted
+fn open(key: &SecretKey, seq: u64, buf: &[u8], plain: &mut Vec<u8>) -> io::Result<()> {
+    let failed = || io::Error::new(io::ErrorKind::InvalidData,
+                                    "Failed to authenticate packet");
+    if buf.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(failed());
     }
+    let (nonce, sealed) = buf.split_at(NONCE_SIZE);
+    let nonce = Nonce::from_slice(nonce).map_err(|_| failed())?;
+    plain.resize(sealed.len() - TAG_SIZE, 0);
+    xchacha20poly1305::open(key, &nonce, sealed, Some(&seq.to_be_bytes()), plain)
+        .map_err(|_| failed())
 }
 
-impl TelekeyTransport for SecureTransport {
+impl<T: FrameTransport> TelekeyTransport for SecureTransport<T> {
     fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
-        let mut header = [0u8; 4];
-        self.stream.read_exact(&mut header)?;
-        let len = u32::from_be_bytes(header); // deduce remaining bytes to read
+        let buf = self.inner.recv_frame()?;
+        open(&self.receiving, self.received, buf, &mut self.plain)?;
+        self.opened()
+    }
 
-        if len == 0 {
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        match self.inner.try_recv_frame()? {
+            Some(buf) => open(&self.receiving, self.received, buf, &mut self.plain)?,
+            None => return Ok(None)
+        }
+        self.opened().map(Some)
+    }
+
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        let buf = encode(p, self.inner.compression());
+        let mut msg = Vec::new();
+        seal(&self.sending, self.sent, &buf, &mut msg)?;
+        self.sent += 1;
+        self.inner.send_frame(&msg)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+/// How often a multi-client server checks the oldest client for a packet
+const BROADCAST_POLL: Duration = Duration::from_millis(1);
+
+/// Sends keys, texts, pointer events and disconnections to every client of
+/// a multi-client server. Other packets, such as pings, are exchanged with
+/// the oldest client only. A client whose connection fails is dropped
+/// without affecting the others.
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    peers: Arc<Mutex<Vec<Box<dyn TelekeyTransport + Send>>>>,
+    timeout: Option<Duration>
+}
+
+impl Broadcast {
+    /// Reads give up with `TimedOut` after `timeout`, dropping the client
+    /// which did not answer, as a blocking read of its own would
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self { peers: Arc::default(), timeout }
+    }
+
+    pub fn add(&self, tr: Box<dyn TelekeyTransport + Send>) {
+        self.peers.lock().unwrap().push(tr);
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Runs `f` on the oldest client, which is dropped if it fails
+    fn with_first<R>(&self, f: impl FnOnce(&mut Box<dyn TelekeyTransport + Send>)
+                     -> io::Result<R>) -> io::Result<R> {
+        let mut peers = self.peers.lock().unwrap();
+        let tr = peers.first_mut().ok_or_else(no_client)?;
+        let r = f(tr);
+        if r.is_err() {
+            peers.remove(0);
+        }
+        r
+    }
+}
+
+fn no_client() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "No client connected")
+}
+
+impl TelekeyTransport for Broadcast {
+    /// Polls the oldest client instead of blocking on it with the clients
+    /// locked, so that they can still be added and sent the keys meanwhile
+    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(p) = self.with_first(|tr| tr.try_recv_packet())? {
+                return Ok(p);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return self.with_first(|_| Err(io::Error::new(io::ErrorKind::TimedOut,
+                    "Timed out waiting for the peer")));
+            }
+            std::thread::sleep(BROADCAST_POLL);
+        }
+    }
+
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        self.with_first(|tr| tr.try_recv_packet())
+    }
+
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        if !matches!(p.kind(), TelekeyPacketKind::KeyEvent | TelekeyPacketKind::Text
+                     | TelekeyPacketKind::Mouse | TelekeyPacketKind::Clipboard
+                     | TelekeyPacketKind::Disconnect) {
+            return self.with_first(|tr| tr.send_packet(p));
+        }
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain_mut(|tr| tr.send_packet(p.clone()).is_ok());
+        if peers.is_empty() {
+            return Err(no_client());
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        for mut tr in self.peers.lock().unwrap().drain(..) {
+            tr.shutdown().ok();
+        }
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.peers.lock().unwrap().first() {
+            Some(tr) => tr.peer_addr(),
+            None => Err(no_client())
+        }
+    }
+}
+
+/// Largest payload of a UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Size of the sequence number starting each datagram
+const SEQ_SIZE: usize = 8;
+
+/// Bytes a datagram adds to the encoded packet: its sequence number, then
+/// the nonce and the tag of the seal
+const DATAGRAM_OVERHEAD: usize = SEQ_SIZE + NONCE_SIZE + TAG_SIZE;
+
+/// Datagrams received this many sequence numbers late are dropped
+const REPLAY_WINDOW: u64 = 64;
+
+/// Sequence numbers of the datagrams received lately, to drop those
+/// received again. Datagrams older than the window are dropped as well,
+/// since they cannot be told apart from replays
+#[derive(Default)]
+struct ReplayWindow {
+    /// Highest sequence number received plus one, 0 before any
+    next: u64,
+    /// Bit `i` is set once `next - 1 - i` is received
+    seen: u64
+}
+
+impl ReplayWindow {
+    fn is_new(&self, seq: u64) -> bool {
+        if seq >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - seq;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+    }
+
+    /// Records `seq`, once its datagram is authenticated
+    fn insert(&mut self, seq: u64) {
+        if seq >= self.next {
+            let shift = seq + 1 - self.next;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = seq + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - seq);
+        }
+    }
+}
+
+/// One sealed packet per datagram over a connected UDP socket, for LAN
+/// links where the latency matters more than the odd lost key. The keys
+/// come from a handshake made beforehand, e.g. over TCP.
+///
+/// Replay protection is mandatory here: unlike a TCP stream, a datagram
+/// captured on the way can be sent again on its own, and a replayed key
+/// event would be typed again. Each datagram carries its sequence number,
+/// authenticated along with the packet as with [`SecureTransport`], and
+/// those already received or too old are dropped. Since datagrams may be
+/// lost, duplicated or reordered, the numbers only need to be new rather
+/// than consecutive, and requests expecting an answer, such as pings and
+/// handshakes, should go through [`UdpTransport::request`] to be retried.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    max_len: usize,
+    sending: SecretKey,
+    receiving: SecretKey,
+    sent: u64,
+    replay: ReplayWindow,
+    /// Receives every datagram
+    buf: Vec<u8>,
+    /// Plaintext of the datagram opened last
+    plain: Vec<u8>
+}
+
+impl UdpTransport {
+    /// `socket` must already be connected to the peer
+    pub fn new(socket: UdpSocket, keys: SessionKeys) -> io::Result<Self> {
+        let peer = socket.peer_addr()?;
+        let key = |k: &orion::kex::SecretKey| SecretKey::from_slice(k.unprotected_as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                                        "Session keys are not 32 bytes long"));
+        Ok(Self {
+            sending: key(keys.transport())?, receiving: key(keys.receiving())?,
+            socket, peer, max_len: DEFAULT_MAX_PACKET_SIZE.min(MAX_DATAGRAM_SIZE - DATAGRAM_OVERHEAD),
+            sent: 0, replay: ReplayWindow::default(), buf: Vec::new(), plain: Vec::new()
+        })
+    }
+
+    /// Packets larger than `max` are refused with `InvalidData`
+    pub fn set_max_packet_size(&mut self, max: usize) {
+        self.max_len = max.min(MAX_DATAGRAM_SIZE - DATAGRAM_OVERHEAD);
+    }
+
+    /// Sends `p` and waits for the answer, a packet of the same kind, sending
+    /// `p` again each time `timeout` elapses, up to `retries` times. Each try
+    /// is sealed with a new sequence number, so it is not taken for a replay
+    pub fn request(&mut self, p: TelekeyPacket, timeout: Duration, retries: u32)
+        -> io::Result<TelekeyPacket> {
+        let previous = self.socket.read_timeout()?;
+        self.socket.set_read_timeout(Some(timeout))?;
+        let r = self.retry(p, timeout, retries);
+        self.socket.set_read_timeout(previous)?;
+        r
+    }
+
+    fn retry(&mut self, p: TelekeyPacket, timeout: Duration, retries: u32)
+        -> io::Result<TelekeyPacket> {
+        let kind = u8::from(p.kind());
+        for _ in 0..=retries {
+            self.send_packet(p.clone())?;
+            let deadline = Instant::now() + timeout;
+            while Instant::now() < deadline {
+                match self.recv_packet() {
+                    Ok(answer) if u8::from(answer.kind()) == kind => return Ok(answer),
+                    // anything else arriving meanwhile is dropped
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the peer"))
+    }
+
+    /// Receives a datagram, `None` if it was received before. One that fails
+    /// to open is an error rather than being dropped, as only tampering or a
+    /// peer with other keys explains it
+    fn recv(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        let failed = || io::Error::new(io::ErrorKind::InvalidData,
+                                        "Failed to authenticate packet");
+        // one more byte than allowed, to tell apart oversized datagrams
+        self.buf.resize(self.max_len + DATAGRAM_OVERHEAD + 1, 0);
+        let n = self.socket.recv(&mut self.buf)?;
+        if n > self.max_len + DATAGRAM_OVERHEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                  format!("Datagram exceeds the maximum of {} bytes", self.max_len)));
+        }
+        if n < SEQ_SIZE {
+            return Err(failed());
+        }
+        let (seq, sealed) = self.buf[..n].split_at(SEQ_SIZE);
+        let seq = u64::from_be_bytes(seq.try_into().unwrap());
+        if !self.replay.is_new(seq) {
+            return Ok(None);
+        }
+        open(&self.receiving, seq, sealed, &mut self.plain)?;
+        self.replay.insert(seq);
+        decode(&self.plain, false, self.max_len).map(Some)
+    }
+}
+
+impl TelekeyTransport for UdpTransport {
+    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
+        loop {
+            match self.recv() {
+                Ok(Some(p)) => return Ok(p),
+                Ok(None) => (),
+                // the read timeout of the socket elapsed, reported as
+                // `WouldBlock` on some platforms
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock
+                                   | io::ErrorKind::TimedOut) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut,
+                        "Timed out waiting for the peer"));
+                },
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        self.socket.set_nonblocking(true)?;
+        let r = loop {
+            match self.recv() {
+                Ok(None) => (),
+                Ok(p) => break Ok(p),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(None),
+                Err(e) => break Err(e)
+            }
+        };
+        self.socket.set_nonblocking(false)?;
+        r
+    }
+
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        let buf = encode(p, false);
+        if buf.len() + DATAGRAM_OVERHEAD > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                  "Packet too large for a datagram"));
+        }
+        let mut msg = Vec::with_capacity(buf.len() + DATAGRAM_OVERHEAD);
+        msg.extend_from_slice(&self.sent.to_be_bytes());
+        seal(&self.sending, self.sent, &buf, &mut msg)?;
+        self.sent += 1;
+        self.socket.send(&msg).map(|_| ())
+    }
+
+    /// Datagrams have no connection to close
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+/// In-memory transport, one end of a pair made by [`MemoryTransport::pair`],
+/// to run a peer without any socket. Packets are encoded to frames as over
+/// TCP, so the size limit and compression apply, and it can be wrapped in a
+/// [`SecureTransport`].
+pub struct MemoryTransport {
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+    addr: SocketAddr,
+    /// Frame handed out last
+    frame: Vec<u8>,
+    max_len: usize,
+    compress: bool,
+    timeout: Option<Duration>
+}
+
+impl MemoryTransport {
+    /// Two connected ends, each receiving what the other sends
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        (Self::new(a_tx, a_rx, addr), Self::new(b_tx, b_rx, addr))
+    }
+
+    fn new(tx: mpsc::Sender<Vec<u8>>, rx: mpsc::Receiver<Vec<u8>>, addr: SocketAddr) -> Self {
+        Self {
+            tx: Some(tx), rx, addr, frame: Vec::new(), max_len: DEFAULT_MAX_PACKET_SIZE,
+            compress: false, timeout: None
+        }
+    }
+
+    /// Keeps `frame` as the one handed out last, once its size is checked
+    fn received(&mut self, frame: Vec<u8>) -> io::Result<&[u8]> {
+        if frame.is_empty() {
             return Err(io::Error::new(io::ErrorKind::InvalidInput,
                   "Zero length packet received"));
         }
+        if frame.len() > self.max_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                  format!("Packet of {} bytes exceeds the maximum of {} bytes",
+                          frame.len(), self.max_len)));
+        }
+        self.frame = frame;
+        Ok(&self.frame)
+    }
+}
+
+impl TelekeyTransport for MemoryTransport {
+    /// Fails with `UnexpectedEof` once the other end is shut down or dropped
+    fn recv_packet(&mut self) -> io::Result<TelekeyPacket> {
+        let (compress, max_len) = (self.compress, self.max_len);
+        decode(self.recv_frame()?, compress, max_len)
+    }
 
-        let mut buf = vec![0; len as usize];
-        self.stream.read_exact(&mut buf)?;
-        let mut buf = aead::open(self.keys.receiving(), &buf).unwrap();
-        Ok(TelekeyPacket::raw(buf.pop().unwrap().into(), buf))
+    fn try_recv_packet(&mut self) -> io::Result<Option<TelekeyPacket>> {
+        let (compress, max_len) = (self.compress, self.max_len);
+        self.try_recv_frame()?.map(|buf| decode(buf, compress, max_len)).transpose()
     }
 
-    fn send_packet(&mut self, mut p: TelekeyPacket) -> io::Result<()> {
-        p.payload.push(p.kind().into());
-        let msg = aead::seal(self.keys.transport(), &p.payload).unwrap();
-        self.stream.write_all(&(msg.len() as u32).to_be_bytes())?;
-        self.stream.write_all(&msg)
+    fn send_packet(&mut self, p: TelekeyPacket) -> io::Result<()> {
+        let buf = encode(p, self.compress);
+        self.send_frame(&buf)
     }
 
     fn shutdown(&mut self) -> io::Result<()> {
-        self.stream.shutdown(std::net::Shutdown::Both)
+        self.tx = None;
+        Ok(())
     }
 
     fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.stream.peer_addr()
+        Ok(self.addr)
+    }
+}
+
+impl FrameTransport for MemoryTransport {
+    fn recv_frame(&mut self) -> io::Result<&[u8]> {
+        let frame = match self.timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => io::Error::new(io::ErrorKind::TimedOut,
+                    "Timed out waiting for the peer"),
+                mpsc::RecvTimeoutError::Disconnected => io::ErrorKind::UnexpectedEof.into()
+            })?,
+            None => self.rx.recv().map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof))?
+        };
+        self.received(frame)
+    }
+
+    fn try_recv_frame(&mut self) -> io::Result<Option<&[u8]>> {
+        match self.rx.try_recv() {
+            Ok(frame) => self.received(frame).map(Some),
+            Err(mpsc::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::TryRecvError::Disconnected) => Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    fn send_frame(&mut self, buf: &[u8]) -> io::Result<()> {
+        let tx = self.tx.as_ref().ok_or(io::ErrorKind::NotConnected)?;
+        tx.send(buf.to_vec()).map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn set_max_packet_size(&mut self, max: usize) {
+        self.max_len = max;
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_len
+    }
+
+    fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    fn compression(&self) -> bool {
+        self.compress
+    }
+
+    fn set_nodelay(&mut self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use orion::kex::{EphemeralClientSession, EphemeralServerSession, PublicKey};
+
+    /// Keys of both ends of an exchange, the server ones first
+    pub(crate) fn session_keys() -> (SessionKeys, SessionKeys) {
+        let server = EphemeralServerSession::new().unwrap();
+        let client = EphemeralClientSession::new().unwrap();
+        let server_pk = PublicKey::from_slice(&server.public_key().to_bytes()).unwrap();
+        let client_pk = PublicKey::from_slice(&client.public_key().to_bytes()).unwrap();
+        (server.establish_with_client(&client_pk).unwrap(),
+         client.establish_with_server(&server_pk).unwrap())
+    }
+
+    /// Two secure ends over memory
+    pub(crate) fn secure_pair() -> (SecureTransport<MemoryTransport>,
+                                    SecureTransport<MemoryTransport>) {
+        let (a, b) = MemoryTransport::pair();
+        let (server_keys, client_keys) = session_keys();
+        (SecureTransport::new(a, server_keys).unwrap(),
+         SecureTransport::new(b, client_keys).unwrap())
+    }
+
+    /// A secure sender, the raw frames it sends, and a secure receiver
+    /// which only gets the frames passed to `wire`
+    pub(crate) fn intercepted() -> (SecureTransport<MemoryTransport>, MemoryTransport,
+                                    MemoryTransport, SecureTransport<MemoryTransport>) {
+        let (a, sent) = MemoryTransport::pair();
+        let (wire, b) = MemoryTransport::pair();
+        let (server_keys, client_keys) = session_keys();
+        (SecureTransport::new(a, server_keys).unwrap(), sent, wire,
+         SecureTransport::new(b, client_keys).unwrap())
+    }
+
+    fn text(s: &str) -> TelekeyPacket {
+        TelekeyPacket::raw(TelekeyPacketKind::Text, s.as_bytes().to_vec())
+    }
+
+    fn is_rejected(r: io::Result<TelekeyPacket>) -> bool {
+        matches!(r, Err(e) if e.kind() == io::ErrorKind::InvalidData)
+    }
+
+    const KINDS: [TelekeyPacketKind; 8] = [
+        TelekeyPacketKind::Handshake, TelekeyPacketKind::KeyEvent, TelekeyPacketKind::Ping,
+        TelekeyPacketKind::Disconnect, TelekeyPacketKind::Text, TelekeyPacketKind::Mouse,
+        TelekeyPacketKind::Clipboard, TelekeyPacketKind::Unknown
+    ];
+
+    /// Frames `p` as it is sent, then reads it back with a `FrameReader`
+    fn round_trip(p: TelekeyPacket, compress: bool) -> TelekeyPacket {
+        let buf = encode(p, compress);
+        let mut reader = FrameReader::default();
+        reader.buf.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+        reader.buf.extend_from_slice(&buf);
+        let len = reader.next_frame().unwrap().unwrap();
+        let p = decode(&reader.buf[4..4 + len], compress, reader.max_len).unwrap();
+        reader.consume();
+        assert!(reader.buf.is_empty());
+        p
+    }
+
+    #[test]
+    fn every_kind_round_trips() {
+        for kind in KINDS {
+            let payload = vec![u8::from(kind); 3];
+            let p = round_trip(TelekeyPacket::raw(kind, payload.clone()), false);
+            assert_eq!(u8::from(p.kind()), u8::from(kind));
+            assert_eq!(p.data(), payload);
+        }
+    }
+
+    #[test]
+    fn every_kind_round_trips_compressed() {
+        for kind in KINDS {
+            let payload = vec![u8::from(kind); 1024];
+            let p = round_trip(TelekeyPacket::raw(kind, payload.clone()), true);
+            assert_eq!(u8::from(p.kind()), u8::from(kind));
+            assert_eq!(p.data(), payload);
+        }
+    }
+
+    #[test]
+    fn unassigned_kind_is_unknown() {
+        let p = decode(&[1, 2, 42], false, DEFAULT_MAX_PACKET_SIZE).unwrap();
+        assert!(matches!(p.kind(), TelekeyPacketKind::Unknown));
+        assert_eq!(p.data(), [1, 2]);
+    }
+
+    #[test]
+    fn unknown_kind_is_not_taken_for_compressed() {
+        assert_eq!(u8::from(TelekeyPacketKind::Unknown) & COMPRESSED, 0);
+        let p = round_trip(TelekeyPacket::raw(TelekeyPacketKind::Unknown, vec![1, 2]), true);
+        assert!(matches!(p.kind(), TelekeyPacketKind::Unknown));
+        assert_eq!(p.data(), [1, 2]);
+    }
+
+    #[test]
+    fn secure_round_trip() {
+        let (mut a, mut b) = secure_pair();
+        a.send_packet(text("hello")).unwrap();
+        assert_eq!(b.recv_packet().unwrap().data(), b"hello");
+        b.send_packet(text("world")).unwrap();
+        assert_eq!(a.recv_packet().unwrap().data(), b"world");
+    }
+
+    #[test]
+    fn secure_rejects_tampered_kind() {
+        let (mut tx, mut sent, mut wire, mut rx) = intercepted();
+        tx.send_packet(text("hello")).unwrap();
+        let mut frame = sent.recv_frame().unwrap().to_vec();
+        // the kind byte ends the plaintext, right before the tag
+        let kind = frame.len() - TAG_SIZE - 1;
+        frame[kind] ^= 1;
+        wire.send_frame(&frame).unwrap();
+        assert!(is_rejected(rx.recv_packet()));
+    }
+
+    #[test]
+    fn secure_rejects_truncated_packet() {
+        let (mut tx, mut sent, mut wire, mut rx) = intercepted();
+        tx.send_packet(text("hello")).unwrap();
+        let frame = sent.recv_frame().unwrap().to_vec();
+        wire.send_frame(&frame[..NONCE_SIZE + TAG_SIZE - 1]).unwrap();
+        assert!(is_rejected(rx.recv_packet()));
+    }
+
+    #[test]
+    fn secure_rejects_replayed_packet() {
+        let (mut tx, mut sent, mut wire, mut rx) = intercepted();
+        tx.send_packet(text("hello")).unwrap();
+        let frame = sent.recv_frame().unwrap().to_vec();
+        wire.send_frame(&frame).unwrap();
+        wire.send_frame(&frame).unwrap();
+        assert_eq!(rx.recv_packet().unwrap().data(), b"hello");
+        assert!(is_rejected(rx.recv_packet()));
+    }
+
+    #[test]
+    fn secure_rejects_reordered_packets() {
+        let (mut tx, mut sent, mut wire, mut rx) = intercepted();
+        tx.send_packet(text("first")).unwrap();
+        let first = sent.recv_frame().unwrap().to_vec();
+        tx.send_packet(text("second")).unwrap();
+        let second = sent.recv_frame().unwrap().to_vec();
+        wire.send_frame(&second).unwrap();
+        wire.send_frame(&first).unwrap();
+        assert!(is_rejected(rx.recv_packet()));
+    }
+
+    /// Counts the writes made to it
+    #[derive(Default)]
+    struct Writes(usize, Vec<u8>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += 1;
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frame_is_written_at_once() {
+        let mut out = Writes::default();
+        write_frame(&mut out, b"hello").unwrap();
+        assert_eq!(out.0, 1);
+        assert_eq!(out.1, [&5u32.to_be_bytes()[..], &b"hello"[..]].concat());
+    }
+
+    /// Two TCP ends over loopback, without Nagle's algorithm
+    fn tcp_pair() -> (TcpTransport, TcpTransport) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut a = TcpTransport::from(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let mut b = TcpTransport::from(listener.accept().unwrap().0);
+        a.set_nodelay(true).unwrap();
+        b.set_nodelay(true).unwrap();
+        (a, b)
+    }
+
+    /// Rough key rate over loopback: `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn send_throughput() {
+        const PACKETS: usize = 100_000;
+        let (mut tx, mut rx) = tcp_pair();
+        let reader = std::thread::spawn(move || {
+            for _ in 0..PACKETS {
+                rx.recv_packet().unwrap();
+            }
+        });
+        let start = std::time::Instant::now();
+        for _ in 0..PACKETS {
+            tx.send_packet(text("a")).unwrap();
+        }
+        reader.join().unwrap();
+        let elapsed = start.elapsed();
+        println!("{} packets sent in {:?}, {:.0} packets/s", PACKETS, elapsed,
+                 PACKETS as f64 / elapsed.as_secs_f64());
+    }
+
+    #[test]
+    fn secure_receive_reuses_its_buffer() {
+        let (mut tx, mut rx) = secure_pair();
+        tx.send_packet(text("hello")).unwrap();
+        rx.recv_packet().unwrap();
+        let buf = rx.plain.as_ptr();
+        for _ in 0..100 {
+            tx.send_packet(text("world")).unwrap();
+            assert_eq!(rx.recv_packet().unwrap().data(), b"world");
+        }
+        assert_eq!(rx.plain.as_ptr(), buf);
+    }
+
+    /// Rough receive rate over loopback, through the secure transport as in
+    /// a session: `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn secure_receive_throughput() {
+        const PACKETS: usize = 100_000;
+        let (a, b) = tcp_pair();
+        let (server_keys, client_keys) = session_keys();
+        let mut tx = SecureTransport::new(a, server_keys).unwrap();
+        let mut rx = SecureTransport::new(b, client_keys).unwrap();
+        let writer = std::thread::spawn(move || {
+            for _ in 0..PACKETS {
+                tx.send_packet(text("a")).unwrap();
+            }
+            tx
+        });
+        let start = std::time::Instant::now();
+        for _ in 0..PACKETS {
+            rx.recv_packet().unwrap();
+        }
+        let elapsed = start.elapsed();
+        writer.join().unwrap();
+        println!("{} packets received in {:?}, {:.0} packets/s", PACKETS, elapsed,
+                 PACKETS as f64 / elapsed.as_secs_f64());
+    }
+
+    #[test]
+    fn oversized_header_is_rejected_before_buffering() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut tr = TcpTransport::from(listener.accept().unwrap().0);
+        tr.set_max_packet_size(1024);
+        // only the header is sent, announcing far more than the maximum
+        peer.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let e = tr.recv_packet().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(tr.reader.buf.capacity() < 1024);
+    }
+
+    /// Two UDP sockets connected to each other, giving up reads after a while
+    fn udp_sockets() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        for socket in [&a, &b] {
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        }
+        (a, b)
+    }
+
+    fn udp_pair() -> (UdpTransport, UdpTransport) {
+        let (a, b) = udp_sockets();
+        let (server_keys, client_keys) = session_keys();
+        (UdpTransport::new(a, server_keys).unwrap(), UdpTransport::new(b, client_keys).unwrap())
+    }
+
+    /// A UDP sender, the socket its datagrams are sent to, the socket
+    /// passing them on, and the receiver
+    fn udp_intercepted() -> (UdpTransport, UdpSocket, UdpSocket, UdpTransport) {
+        let (a, sent) = udp_sockets();
+        let (wire, b) = udp_sockets();
+        let (server_keys, client_keys) = session_keys();
+        (UdpTransport::new(a, server_keys).unwrap(), sent, wire,
+         UdpTransport::new(b, client_keys).unwrap())
+    }
+
+    fn datagram(socket: &UdpSocket) -> Vec<u8> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let n = socket.recv(&mut buf).unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let (mut a, mut b) = udp_pair();
+        a.send_packet(text("hello")).unwrap();
+        assert_eq!(b.recv_packet().unwrap().data(), b"hello");
+        b.send_packet(text("world")).unwrap();
+        assert_eq!(a.recv_packet().unwrap().data(), b"world");
+    }
+
+    #[test]
+    fn udp_drops_replayed_datagram() {
+        let (mut tx, sent, wire, mut rx) = udp_intercepted();
+        tx.send_packet(text("hello")).unwrap();
+        let hello = datagram(&sent);
+        tx.send_packet(text("world")).unwrap();
+        let world = datagram(&sent);
+        for d in [&hello, &hello, &world] {
+            wire.send(d).unwrap();
+        }
+        assert_eq!(rx.recv_packet().unwrap().data(), b"hello");
+        assert_eq!(rx.recv_packet().unwrap().data(), b"world");
+    }
+
+    #[test]
+    fn udp_accepts_reordered_datagrams_once() {
+        let (mut tx, sent, wire, mut rx) = udp_intercepted();
+        tx.send_packet(text("first")).unwrap();
+        let first = datagram(&sent);
+        tx.send_packet(text("second")).unwrap();
+        let second = datagram(&sent);
+        for d in [&second, &first, &first] {
+            wire.send(d).unwrap();
+        }
+        assert_eq!(rx.recv_packet().unwrap().data(), b"second");
+        assert_eq!(rx.recv_packet().unwrap().data(), b"first");
+        rx.socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let e = rx.recv_packet().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn udp_rejects_tampered_kind() {
+        let (mut tx, sent, wire, mut rx) = udp_intercepted();
+        tx.send_packet(text("hello")).unwrap();
+        let mut d = datagram(&sent);
+        let kind = d.len() - TAG_SIZE - 1;
+        d[kind] ^= 1;
+        wire.send(&d).unwrap();
+        assert!(is_rejected(rx.recv_packet()));
+    }
+
+    #[test]
+    fn udp_request_is_retried() {
+        let (mut a, mut b) = udp_pair();
+        let peer = std::thread::spawn(move || {
+            // the first try goes unanswered, as if its answer was lost
+            b.recv_packet().unwrap();
+            let p = b.recv_packet().unwrap();
+            b.send_packet(p).unwrap();
+        });
+        let ping = TelekeyPacket::raw(TelekeyPacketKind::Ping, 7u64.to_be_bytes().to_vec());
+        let answer = a.request(ping, Duration::from_millis(200), 2).unwrap();
+        assert_eq!(answer.data(), 7u64.to_be_bytes());
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn replay_window_keeps_the_last_sequence_numbers() {
+        let mut window = ReplayWindow::default();
+        for seq in [0, 3, 1] {
+            assert!(window.is_new(seq));
+            window.insert(seq);
+            assert!(!window.is_new(seq));
+        }
+        assert!(window.is_new(2));
+        window.insert(REPLAY_WINDOW + 2);
+        // 2 is now too old to tell apart from a replay
+        assert!(!window.is_new(2));
+        assert!(window.is_new(REPLAY_WINDOW + 1));
+        assert!(!window.is_new(3));
+    }
+
+    #[test]
+    fn broadcast_is_not_locked_while_waiting() {
+        let (server, mut client) = tcp_pair();
+        let clients = Broadcast::new(None);
+        clients.add(Box::new(server));
+        let mut waiting = clients.clone();
+        let reader = std::thread::spawn(move || waiting.recv_packet().unwrap());
+        std::thread::sleep(Duration::from_millis(50));
+        let (other, _other) = tcp_pair();
+        clients.add(Box::new(other));
+        assert_eq!(clients.len(), 2);
+        client.send_packet(text("hello")).unwrap();
+        assert_eq!(reader.join().unwrap().data(), b"hello");
+    }
+
+    #[test]
+    fn broadcast_drops_the_client_timing_out() {
+        let (server, _client) = tcp_pair();
+        let mut clients = Broadcast::new(Some(Duration::from_millis(20)));
+        clients.add(Box::new(server));
+        let err = clients.recv_packet().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(clients.is_empty());
     }
 }